@@ -0,0 +1,374 @@
+//! Threshold alarms on the measurements of the CTs.
+//!
+//! An alarm rule watches one quantity of one CT, and triggers once every measurement for `delay`
+//! seconds was below its `min` or above its `max`. It clears with the first measurement back in
+//! range. The rules are kept in the keystore, in `MAX_ALARM_RULES` slots, and the main loop
+//! evaluates them after every measurement. The alarms that triggered or cleared are logged by
+//! `CTStorage`, since a site without a connection can't alert anyone when they happen.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+use crate::ct::CTReading;
+use crate::keystore::SharedKeyStore;
+use crate::record::AlarmEvent;
+use crate::rtc::now;
+use crate::utils::{add_f32_to_buf, add_u16_to_buf, add_u32_to_buf, json_number};
+use crate::MAX_ALARM_RULES;
+
+/// What an alarm rule watches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantity {
+    RealPower = 1,
+    ApparentPower = 2,
+    ReactivePower = 3,
+    PowerFactor = 4,
+    IRms = 5,
+    VRms = 6,
+    Frequency = 7,
+}
+
+const QUANTITIES: [Quantity; 7] = [
+    Quantity::RealPower,
+    Quantity::ApparentPower,
+    Quantity::ReactivePower,
+    Quantity::PowerFactor,
+    Quantity::IRms,
+    Quantity::VRms,
+    Quantity::Frequency,
+];
+
+/// An alarm rule, kept in the keystore under `alarm<id>`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AlarmRule {
+    pub ct_id: u16,
+    pub quantity: Quantity,
+    /// The range the quantity should stay in, either end may be open.
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+    /// How long the quantity has to be out of range before the alarm triggers, in seconds.
+    pub delay: u32,
+}
+
+/// An alarm rule and whether it triggered.
+#[derive(Debug, Clone, Copy)]
+struct Alarm {
+    rule: AlarmRule,
+    /// When the quantity went out of range, as time since boot.
+    out_of_range_since: Option<Duration>,
+    active: bool,
+}
+
+/// The alarm rules, shared between the web server and the main loop.
+pub struct AlarmTable {
+    keystore: SharedKeyStore,
+    ct_count: usize,
+    /// The rule with id `i + 1` is in slot `i`.
+    alarms: [Option<Alarm>; MAX_ALARM_RULES],
+}
+
+pub type SharedAlarms = Arc<Mutex<AlarmTable>>;
+
+impl Quantity {
+    pub fn from_u16(quantity: u16) -> Option<Quantity> {
+        QUANTITIES.iter().copied().find(|q| *q as u16 == quantity)
+    }
+
+    pub fn from_name(name: &str) -> Option<Quantity> {
+        QUANTITIES.iter().copied().find(|q| q.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Quantity::RealPower => "real_power",
+            Quantity::ApparentPower => "apparent_power",
+            Quantity::ReactivePower => "reactive_power",
+            Quantity::PowerFactor => "power_factor",
+            Quantity::IRms => "i_rms",
+            Quantity::VRms => "v_rms",
+            Quantity::Frequency => "frequency",
+        }
+    }
+
+    fn value(&self, measurement: &CTReading) -> f32 {
+        match self {
+            Quantity::RealPower => measurement.real_power,
+            Quantity::ApparentPower => measurement.apparent_power,
+            Quantity::ReactivePower => measurement.reactive_power,
+            Quantity::PowerFactor => measurement.power_factor,
+            Quantity::IRms => measurement.i_rms,
+            Quantity::VRms => measurement.v_rms,
+            Quantity::Frequency => measurement.frequency,
+        }
+    }
+}
+
+impl AlarmRule {
+    const SIZE: usize = 2 * std::mem::size_of::<u16>() + 3 * std::mem::size_of::<f32>();
+
+    // open ends are stored as NaN, an empty slot as quantity 0.
+    fn to_le_bytes(self) -> anyhow::Result<[u8; AlarmRule::SIZE]> {
+        let mut buf = [0_u8; AlarmRule::SIZE];
+        let mut pos = 0;
+        pos += add_u16_to_buf(&self.ct_id, &mut buf, &pos)?;
+        pos += add_u16_to_buf(&(self.quantity as u16), &mut buf, &pos)?;
+        pos += add_f32_to_buf(&self.min.unwrap_or(f32::NAN), &mut buf, &pos)?;
+        pos += add_f32_to_buf(&self.max.unwrap_or(f32::NAN), &mut buf, &pos)?;
+        add_u32_to_buf(&self.delay, &mut buf, &pos)?;
+        Ok(buf)
+    }
+
+    fn from_le_bytes(buf: &[u8; AlarmRule::SIZE]) -> Option<Self> {
+        let f32_at = |pos: usize| {
+            let value = f32::from_le_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]]);
+            Some(value).filter(|value| !value.is_nan())
+        };
+        Some(AlarmRule {
+            ct_id: u16::from_le_bytes([buf[0], buf[1]]),
+            quantity: Quantity::from_u16(u16::from_le_bytes([buf[2], buf[3]]))?,
+            min: f32_at(4),
+            max: f32_at(8),
+            delay: u32::from_le_bytes([buf[12], buf[13], buf[14], buf[15]]),
+        })
+    }
+
+    /// Checks the rule for a device with `ct_count` CTs.
+    pub fn validate(&self, ct_count: usize) -> anyhow::Result<()> {
+        if !(1..=ct_count as u16).contains(&self.ct_id) {
+            anyhow::bail!("There is no CT {}.", self.ct_id);
+        }
+        if [self.min, self.max]
+            .iter()
+            .flatten()
+            .any(|v| !v.is_finite())
+        {
+            anyhow::bail!("min and max must be numbers.");
+        }
+        match (self.min, self.max) {
+            (None, None) => anyhow::bail!("min, max or both must be given."),
+            (Some(min), Some(max)) if min >= max => anyhow::bail!("min must be below max."),
+            _ => Ok(()),
+        }
+    }
+
+    fn is_out_of_range(&self, value: f32) -> bool {
+        self.min.map(|min| value < min).unwrap_or(false)
+            || self.max.map(|max| value > max).unwrap_or(false)
+    }
+
+    fn to_json(self, id: u16, active: bool) -> String {
+        format!(
+            r#"{{"id":{},"ct":{},"quantity":"{}","min":{},"max":{},"delay":{},"active":{}}}"#,
+            id,
+            self.ct_id,
+            self.quantity.name(),
+            self.min
+                .map(json_number)
+                .unwrap_or_else(|| "null".to_string()),
+            self.max
+                .map(json_number)
+                .unwrap_or_else(|| "null".to_string()),
+            self.delay,
+            active
+        )
+    }
+}
+
+impl AlarmTable {
+    /// Loads the alarm rules of a device with `ct_count` CTs from the keystore.
+    pub fn load(keystore: SharedKeyStore, ct_count: usize) -> anyhow::Result<SharedAlarms> {
+        let mut alarms = [None; MAX_ALARM_RULES];
+        {
+            let keystore = match keystore.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            for (i, alarm) in alarms.iter_mut().enumerate() {
+                let mut buf = [0_u8; AlarmRule::SIZE];
+                if keystore
+                    .get_raw(&rule_key(i as u16 + 1), &mut buf)?
+                    .is_none()
+                {
+                    continue;
+                }
+                if let Some(rule) = AlarmRule::from_le_bytes(&buf) {
+                    match rule.validate(ct_count) {
+                        Ok(()) => {
+                            info!("Alarm rule {}: {:?}", i + 1, rule);
+                            *alarm = Some(Alarm::new(rule));
+                        }
+                        Err(e) => warn!("Ignored stored alarm rule {}: {:?}", i + 1, e),
+                    }
+                }
+            }
+        }
+        Ok(Arc::new(Mutex::new(AlarmTable {
+            keystore,
+            ct_count,
+            alarms,
+        })))
+    }
+
+    /// Stores a rule under `id`, or under the first free id if it is `None`. Returns the id, and
+    /// the event of the alarm it replaced clearing if that one was active.
+    pub(crate) fn set(
+        &mut self,
+        id: Option<u16>,
+        rule: AlarmRule,
+    ) -> anyhow::Result<(u16, Option<AlarmEvent>)> {
+        rule.validate(self.ct_count)?;
+        let index = match id {
+            Some(id) if (1..=MAX_ALARM_RULES as u16).contains(&id) => id as usize - 1,
+            Some(id) => anyhow::bail!("There is no alarm rule {}.", id),
+            None => match self.alarms.iter().position(|alarm| alarm.is_none()) {
+                Some(index) => index,
+                None => anyhow::bail!("There can be at most {} alarm rules.", MAX_ALARM_RULES),
+            },
+        };
+        let id = index as u16 + 1;
+        {
+            let mut keystore = match self.keystore.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            keystore.put_raw(&rule_key(id), &rule.to_le_bytes()?)?;
+        }
+        let cleared = self.clear(index);
+        self.alarms[index] = Some(Alarm::new(rule));
+        info!("Alarm rule {} set to {:?}", id, rule);
+        Ok((id, cleared))
+    }
+
+    /// Removes the rule with `id`. Returns the event of its alarm clearing if it was active.
+    pub(crate) fn remove(&mut self, id: u16) -> anyhow::Result<Option<AlarmEvent>> {
+        let index = match (id as usize).checked_sub(1) {
+            Some(index) if index < MAX_ALARM_RULES && self.alarms[index].is_some() => index,
+            _ => anyhow::bail!("There is no alarm rule {}.", id),
+        };
+        {
+            let mut keystore = match self.keystore.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            keystore.put_raw(&rule_key(id), &[0_u8; AlarmRule::SIZE])?;
+        }
+        let cleared = self.clear(index);
+        self.alarms[index] = None;
+        info!("Removed alarm rule {}.", id);
+        Ok(cleared)
+    }
+
+    // The event of the alarm in slot `index` clearing now, if it is active. There is no
+    // measurement it cleared with, so its value is NaN.
+    fn clear(&self, index: usize) -> Option<AlarmEvent> {
+        let alarm = self.alarms[index].filter(|alarm| alarm.active)?;
+        let event = AlarmEvent {
+            rule_id: index as u16 + 1,
+            ct_id: alarm.rule.ct_id,
+            quantity: alarm.rule.quantity as u16,
+            active: false,
+            boot_count: 0,
+            time: now().as_millis() as u64,
+            value: f32::NAN,
+            min: alarm.rule.min.unwrap_or(f32::NAN),
+            max: alarm.rule.max.unwrap_or(f32::NAN),
+        };
+        warn!("Alarm: {:?}", event);
+        Some(event)
+    }
+
+    /// Checks the rules of a CT against one of its measurements, taken `since_boot`. Returns the
+    /// alarms that triggered or cleared.
+    pub(crate) fn evaluate(
+        &mut self,
+        ct_id: u16,
+        measurement: &CTReading,
+        since_boot: Duration,
+    ) -> Vec<AlarmEvent> {
+        let mut events = Vec::new();
+        for (i, alarm) in self.alarms.iter_mut().enumerate() {
+            let alarm = match alarm {
+                Some(alarm) if alarm.rule.ct_id == ct_id => alarm,
+                _ => continue,
+            };
+            let rule = alarm.rule;
+            let value = rule.quantity.value(measurement);
+            let changed = if rule.is_out_of_range(value) {
+                let since = *alarm.out_of_range_since.get_or_insert(since_boot);
+                !alarm.active
+                    && since_boot.saturating_sub(since) >= Duration::from_secs(rule.delay as u64)
+            } else {
+                alarm.out_of_range_since = None;
+                alarm.active
+            };
+            if changed {
+                alarm.active = !alarm.active;
+                let event = AlarmEvent {
+                    rule_id: i as u16 + 1,
+                    ct_id,
+                    quantity: rule.quantity as u16,
+                    active: alarm.active,
+                    boot_count: 0,
+                    time: measurement.timestamp,
+                    value,
+                    min: rule.min.unwrap_or(f32::NAN),
+                    max: rule.max.unwrap_or(f32::NAN),
+                };
+                warn!("Alarm: {:?}", event);
+                events.push(event);
+            }
+        }
+        events
+    }
+
+    /// The rules as a JSON array, with whether they are active.
+    pub fn to_json(&self) -> String {
+        let rules = self
+            .alarms
+            .iter()
+            .enumerate()
+            .filter_map(|(i, alarm)| {
+                let alarm = alarm.as_ref()?;
+                Some(alarm.rule.to_json(i as u16 + 1, alarm.active))
+            })
+            .collect::<Vec<String>>();
+        format!("[{}]", rules.join(","))
+    }
+
+    /// Whether each alarm is active, as ThingsBoard client attributes, e.g.
+    /// `{"ct1_alarm1":false,"ct2_alarm3":true}`.
+    pub fn to_thingsboard_attributes(&self) -> String {
+        let attributes = self
+            .alarms
+            .iter()
+            .enumerate()
+            .filter_map(|(i, alarm)| {
+                let alarm = alarm.as_ref()?;
+                Some(format!(
+                    r#""ct{}_alarm{}":{}"#,
+                    alarm.rule.ct_id,
+                    i + 1,
+                    alarm.active
+                ))
+            })
+            .collect::<Vec<String>>();
+        format!("{{{}}}", attributes.join(","))
+    }
+}
+
+impl Alarm {
+    fn new(rule: AlarmRule) -> Self {
+        Alarm {
+            rule,
+            out_of_range_since: None,
+            active: false,
+        }
+    }
+}
+
+fn rule_key(id: u16) -> String {
+    format!("alarm{}", id)
+}
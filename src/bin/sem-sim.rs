@@ -0,0 +1,225 @@
+//! Runs the firmware on the host against a directory instead of LittleFS and a scripted waveform
+//! instead of the ADC.
+//!
+//! ```shell
+//! $ cargo +stable run --target x86_64-unknown-linux-gnu --no-default-features \
+//!     --features host --bin sem-sim -- --port 8080 --save-period 10
+//! ```
+
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+use sem::alarms::AlarmTable;
+use sem::calibration::CalibrationTable;
+use sem::channels::Channels;
+use sem::ct::CT;
+use sem::demand::DemandRegisters;
+use sem::keystore::{self, FileKeyStore};
+use sem::sample::{Harmonic, ScriptedSource, WaveformStep};
+use sem::settings::Settings;
+use sem::web::{self, Body, Method};
+use sem::{MAX_REQUEST_BODY_SIZE, VERSION};
+
+/// About 230V and 5A with the default calibration of a single-phase device.
+const DEFAULT_STEP: WaveformStep = WaveformStep {
+    duration: Duration::from_secs(0),
+    v_amplitude: 1038.0,
+    i_amplitude: 51.0,
+    phase_shift: 0.0,
+};
+
+/// A locally administered address, so it can't be mistaken for a real device.
+const SIMULATED_MAC: [u8; 6] = [0x02, 0x00, 0x00, 0x00, 0x00, 0x01];
+
+struct Options {
+    root: PathBuf,
+    port: u16,
+    script: Option<PathBuf>,
+    frequency: f32,
+    /// Overrides the save period of the settings, which can't be shorter than a minute.
+    save_period: Option<Duration>,
+    v_harmonics: Vec<Harmonic>,
+    i_harmonics: Vec<Harmonic>,
+}
+
+impl Options {
+    fn from_args() -> anyhow::Result<Self> {
+        let mut options = Options {
+            root: std::env::temp_dir().join(format!("sem-sim-{}", std::process::id())),
+            port: 8080,
+            script: None,
+            frequency: 50.0,
+            save_period: None,
+            v_harmonics: Vec::new(),
+            i_harmonics: Vec::new(),
+        };
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| anyhow!("Missing value for {}", arg))?;
+            match arg.as_str() {
+                "--root" => options.root = PathBuf::from(value),
+                "--port" => options.port = value.parse()?,
+                "--script" => options.script = Some(PathBuf::from(value)),
+                "--frequency" => options.frequency = value.parse()?,
+                "--save-period" => options.save_period = Some(Duration::from_secs(value.parse()?)),
+                "--v-harmonics" => options.v_harmonics = Harmonic::parse_list(&value)?,
+                "--i-harmonics" => options.i_harmonics = Harmonic::parse_list(&value)?,
+                _ => bail!(
+                    "Unknown option {}. Valid options are --root, --port, --script, --frequency, --save-period, --v-harmonics and --i-harmonics.",
+                    arg
+                ),
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let options = Options::from_args()?;
+    println!("SEM simulator running version: {}", VERSION);
+
+    // The directory plays the role of the LittleFS partition.
+    std::fs::create_dir_all(&options.root)?;
+    let keystore = keystore::shared(FileKeyStore::new(options.root.join("nvs"))?);
+    // only the number of CTs matters, every one of them gets the scripted waveform.
+    let channels = Channels::load(keystore.clone(), &options.root)?;
+    let channel_map = match channels.lock() {
+        Ok(gaurd) => gaurd.active().clone(),
+        Err(poisoned) => poisoned.into_inner().active().clone(),
+    };
+    let ct_count = channel_map.len();
+    let storage_lock = sem::init_ct_storage(
+        options.root.clone(),
+        SIMULATED_MAC,
+        keystore.clone(),
+        ct_count,
+    )?;
+    let calibrations = CalibrationTable::load(keystore.clone(), &channel_map)?;
+    let settings = Settings::load(keystore.clone())?;
+    let alarms = AlarmTable::load(keystore.clone(), ct_count)?;
+    let demand = DemandRegisters::load(keystore, ct_count)?;
+    info!("Using {:?} as storage.", options.root);
+
+    let context = Arc::new(web::Context {
+        storage: storage_lock.clone(),
+        calibrations: calibrations.clone(),
+        channels,
+        settings: settings.clone(),
+        alarms: alarms.clone(),
+        demand: demand.clone(),
+    });
+    let port = options.port;
+    thread::spawn(move || {
+        if let Err(e) = serve(context, port) {
+            error!("Web server stopped: {:?}", e);
+        }
+    });
+    info!("Listening on port {}.", port);
+
+    let steps = match &options.script {
+        Some(path) => ScriptedSource::parse_script(&std::fs::read_to_string(path)?)?,
+        None => vec![DEFAULT_STEP],
+    };
+    let initial_calibrations = match calibrations.lock() {
+        Ok(gaurd) => gaurd.all(),
+        Err(poisoned) => poisoned.into_inner().all(),
+    };
+    let mut cts = initial_calibrations
+        .iter()
+        .enumerate()
+        .map(|(i, calibration)| {
+            CT::new(
+                i as u16 + 1,
+                ScriptedSource::new(options.frequency, steps.clone())
+                    .with_harmonics(options.v_harmonics.clone(), options.i_harmonics.clone()),
+                *calibration,
+            )
+        })
+        .collect::<Vec<CT<ScriptedSource>>>();
+
+    sem::run_measurement_loop(
+        &mut cts,
+        &storage_lock,
+        &calibrations,
+        &settings,
+        &alarms,
+        &demand,
+        options.save_period,
+    )
+}
+
+/// Serves the shared handlers over plain http.
+fn serve(context: Arc<web::Context>, port: u16) -> anyhow::Result<()> {
+    let server = tiny_http::Server::http(("0.0.0.0", port)).map_err(|e| anyhow!("{}", e))?;
+    for mut request in server.incoming_requests() {
+        let method = match request.method() {
+            tiny_http::Method::Get => Method::Get,
+            tiny_http::Method::Post => Method::Post,
+            _ => {
+                request.respond(tiny_http::Response::empty(405))?;
+                continue;
+            }
+        };
+        let url = request.url().to_string();
+        let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+        let too_large = matches!(request.body_length(), Some(len) if len > MAX_REQUEST_BODY_SIZE);
+        // one byte more than the limit, to tell a body that fits from one that is cut off.
+        let mut body = Vec::new();
+        if !too_large {
+            request
+                .as_reader()
+                .take(MAX_REQUEST_BODY_SIZE as u64 + 1)
+                .read_to_end(&mut body)?;
+        }
+
+        let response = if too_large || body.len() > MAX_REQUEST_BODY_SIZE {
+            warn!(
+                "Refused a request to {} with a body that is too long.",
+                path
+            );
+            Ok(web::Response::payload_too_large())
+        } else {
+            web::handle(&context, method, path, query, &body)
+        };
+        let (status, content_type, data) = match response.and_then(into_parts) {
+            Ok(response) => response,
+            Err(e) => {
+                error!("Handling {} failed: {:?}", url, e);
+                (500, "text/plain", format!("{:?}", e).into_bytes())
+            }
+        };
+        let content_type = tiny_http::Header::from_bytes("Content-Type", content_type)
+            .map_err(|_| anyhow!("Invalid content type"))?;
+        request.respond(
+            tiny_http::Response::from_data(data)
+                .with_status_code(status)
+                .with_header(content_type),
+        )?;
+    }
+    Ok(())
+}
+
+/// The status, content type and the whole body of a response.
+fn into_parts(response: web::Response) -> anyhow::Result<(u16, &'static str, Vec<u8>)> {
+    let data = match response.body {
+        Body::Empty => Vec::new(),
+        Body::Bytes(bytes) => bytes,
+        Body::Stream(stream) => {
+            let mut data = Vec::new();
+            stream(&mut data)?;
+            data
+        }
+    };
+    Ok((response.status, response.content_type, data))
+}
@@ -0,0 +1,423 @@
+//! Calibration of the CTs.
+//!
+//! Every CT has its own calibration in the keystore, which can be set directly over http, or found
+//! by a guided calibration against the readings of a reference meter.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+use crate::channels::ChannelMap;
+use crate::ct::{Accumulators, CT};
+use crate::keystore::SharedKeyStore;
+use crate::sample::SampleSource;
+use crate::utils::{add_f32_to_buf, json_number, json_string};
+use crate::MAX_MV_ATTEN_11;
+
+/// Calibration of a CT, kept in the keystore under `cal<id>`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibration {
+    pub ical: f32,
+    pub vcal: f32,
+    pub phase_cal: f32,
+    /// Where the dc offset filters of the current and voltage channels start, in mV.
+    pub offset_i: f32,
+    pub offset_v: f32,
+}
+
+/// The calibration of the single-phase board, the channel map sets `ical` and `vcal` of others.
+pub const DEFAULT_CALIBRATION: Calibration = Calibration {
+    ical: 102.0,
+    vcal: 232.5,
+    phase_cal: 1.7,
+    offset_i: 1066.0,
+    offset_v: 1288.0,
+};
+
+/// The calibration of every CT, shared between the web server and the main loop.
+pub struct CalibrationTable {
+    keystore: SharedKeyStore,
+    calibrations: Vec<Calibration>,
+    /// Calibrations that were set but not yet applied to their CT.
+    changed: Vec<bool>,
+    /// The last guided calibration.
+    status: CalibrationStatus,
+}
+
+/// A guided calibration against a reference meter, requested over http and run by the main loop.
+#[derive(Debug, Clone, Copy)]
+pub struct CalibrationJob {
+    pub ct_id: u16,
+    /// What the reference meter shows while the CT is measuring, in V and A.
+    pub voltage: f32,
+    pub current: f32,
+    /// The real power of the load in W, only needed to find `phase_cal`. Best measured with a
+    /// resistive load such as a heater.
+    pub real_power: Option<f32>,
+}
+
+/// How far the readings of a calibration are off from the reference meter, in percent.
+#[derive(Debug, Clone, Copy)]
+pub struct CalibrationError {
+    pub voltage: f32,
+    pub current: f32,
+    pub real_power: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CalibrationReport {
+    pub ct_id: u16,
+    pub before: Calibration,
+    pub after: Calibration,
+    pub error_before: CalibrationError,
+    pub error_after: CalibrationError,
+}
+
+#[derive(Debug, Clone)]
+pub enum CalibrationStatus {
+    Idle,
+    Pending(CalibrationJob),
+    Running(CalibrationJob),
+    Done(CalibrationReport),
+    Failed(String),
+}
+
+pub type SharedCalibrations = Arc<Mutex<CalibrationTable>>;
+
+impl Calibration {
+    const SIZE: usize = 5 * std::mem::size_of::<f32>();
+
+    fn to_le_bytes(self) -> anyhow::Result<[u8; Calibration::SIZE]> {
+        let mut buf = [0_u8; Calibration::SIZE];
+        let mut pos = 0;
+        for value in [
+            self.ical,
+            self.vcal,
+            self.phase_cal,
+            self.offset_i,
+            self.offset_v,
+        ] {
+            pos += add_f32_to_buf(&value, &mut buf, &pos)?;
+        }
+        Ok(buf)
+    }
+
+    fn from_le_bytes(buf: &[u8; Calibration::SIZE]) -> Self {
+        let mut values = buf
+            .chunks_exact(std::mem::size_of::<f32>())
+            .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+        Calibration {
+            ical: values.next().unwrap_or_default(),
+            vcal: values.next().unwrap_or_default(),
+            phase_cal: values.next().unwrap_or_default(),
+            offset_i: values.next().unwrap_or_default(),
+            offset_v: values.next().unwrap_or_default(),
+        }
+    }
+
+    /// Makes sure the calibration can't make the readings meaningless.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(self.ical.is_finite() && self.ical > 0.0) {
+            anyhow::bail!("ical must be a positive number.");
+        }
+        if !(self.vcal.is_finite() && self.vcal > 0.0) {
+            anyhow::bail!("vcal must be a positive number.");
+        }
+        if !self.phase_cal.is_finite() {
+            anyhow::bail!("phase_cal must be a number.");
+        }
+        for offset in [self.offset_i, self.offset_v] {
+            if !(0.0..=MAX_MV_ATTEN_11 as f32).contains(&offset) {
+                anyhow::bail!("Offsets must be between 0 and {} mV.", MAX_MV_ATTEN_11);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self, ct_id: u16) -> String {
+        format!(
+            r#"{{"ct":{},"ical":{},"vcal":{},"phase_cal":{},"offset_i":{},"offset_v":{}}}"#,
+            ct_id, self.ical, self.vcal, self.phase_cal, self.offset_i, self.offset_v
+        )
+    }
+}
+
+impl CalibrationTable {
+    /// Loads the calibrations of the CTs of the channel map from the keystore, CTs without one get
+    /// the default calibration of the map.
+    pub fn load(keystore: SharedKeyStore, map: &ChannelMap) -> anyhow::Result<SharedCalibrations> {
+        let mut calibrations = vec![map.default_calibration(); map.len()];
+        {
+            let keystore = match keystore.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            for (i, calibration) in calibrations.iter_mut().enumerate() {
+                let mut buf = [0_u8; Calibration::SIZE];
+                if keystore
+                    .get_raw(&calibration_key(i as u16 + 1), &mut buf)?
+                    .is_some()
+                {
+                    let stored = Calibration::from_le_bytes(&buf);
+                    match stored.validate() {
+                        Ok(()) => *calibration = stored,
+                        Err(e) => warn!("Ignored stored calibration of CT {}: {:?}", i + 1, e),
+                    }
+                }
+                info!("Calibration of CT {}: {:?}", i + 1, calibration);
+            }
+        }
+        Ok(Arc::new(Mutex::new(CalibrationTable {
+            keystore,
+            changed: vec![false; calibrations.len()],
+            calibrations,
+            status: CalibrationStatus::Idle,
+        })))
+    }
+
+    /// The calibration of the CT with this id, if there is such a CT.
+    pub fn get(&self, ct_id: u16) -> Option<Calibration> {
+        let index = (ct_id as usize).checked_sub(1)?;
+        self.calibrations.get(index).copied()
+    }
+
+    pub fn all(&self) -> Vec<Calibration> {
+        self.calibrations.clone()
+    }
+
+    /// Stores the calibration of a CT. The main loop applies it before the next measurement.
+    pub(crate) fn set(&mut self, ct_id: u16, calibration: Calibration) -> anyhow::Result<()> {
+        calibration.validate()?;
+        if self.get(ct_id).is_none() {
+            anyhow::bail!("There is no CT {}.", ct_id);
+        }
+        {
+            let mut keystore = match self.keystore.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            keystore.put_raw(&calibration_key(ct_id), &calibration.to_le_bytes()?)?;
+        }
+        self.calibrations[ct_id as usize - 1] = calibration;
+        self.changed[ct_id as usize - 1] = true;
+        info!("Calibration of CT {} set to {:?}", ct_id, calibration);
+        Ok(())
+    }
+
+    pub fn status(&self) -> &CalibrationStatus {
+        &self.status
+    }
+
+    /// Whether a guided calibration is waiting or running.
+    pub fn is_busy(&self) -> bool {
+        matches!(
+            self.status,
+            CalibrationStatus::Pending(_) | CalibrationStatus::Running(_)
+        )
+    }
+
+    /// Queue a guided calibration for the main loop.
+    pub(crate) fn queue(&mut self, job: CalibrationJob) -> anyhow::Result<()> {
+        job.validate()?;
+        if self.get(job.ct_id).is_none() {
+            anyhow::bail!("There is no CT {}.", job.ct_id);
+        }
+        if self.is_busy() {
+            anyhow::bail!("A calibration is already running.");
+        }
+        self.status = CalibrationStatus::Pending(job);
+        info!("Queued calibration {:?}", job);
+        Ok(())
+    }
+
+    // The queued guided calibration, which is marked as running.
+    pub(crate) fn take_job(&mut self) -> Option<CalibrationJob> {
+        match self.status {
+            CalibrationStatus::Pending(job) => {
+                self.status = CalibrationStatus::Running(job);
+                Some(job)
+            }
+            _ => None,
+        }
+    }
+
+    // Store the result of the running guided calibration.
+    pub(crate) fn finish(&mut self, result: anyhow::Result<CalibrationReport>) {
+        let result = result.and_then(|report| {
+            self.set(report.ct_id, report.after)?;
+            Ok(report)
+        });
+        self.status = match result {
+            Ok(report) => {
+                info!("Calibration done: {:?}", report);
+                CalibrationStatus::Done(report)
+            }
+            Err(e) => {
+                warn!("Calibration failed: {:?}", e);
+                CalibrationStatus::Failed(e.to_string())
+            }
+        };
+    }
+
+    // The calibration of a CT if it was set since the last call.
+    pub(crate) fn take_changed(&mut self, ct_id: u16) -> Option<Calibration> {
+        let index = ct_id as usize - 1;
+        if std::mem::replace(&mut self.changed[index], false) {
+            Some(self.calibrations[index])
+        } else {
+            None
+        }
+    }
+}
+
+fn calibration_key(ct_id: u16) -> String {
+    format!("cal{}", ct_id)
+}
+
+impl CalibrationJob {
+    fn validate(&self) -> anyhow::Result<()> {
+        if !(self.voltage.is_finite() && self.voltage > 0.0) {
+            anyhow::bail!("voltage must be a positive number.");
+        }
+        if !(self.current.is_finite() && self.current > 0.0) {
+            anyhow::bail!("current must be a positive number.");
+        }
+        if let Some(real_power) = self.real_power {
+            // a little more than the apparent power is allowed, for the tolerance of the meter.
+            if !(real_power > 0.0 && real_power <= self.voltage * self.current * 1.05) {
+                anyhow::bail!("power must be positive and at most voltage * current.");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CalibrationError {
+    fn to_json(self) -> String {
+        format!(
+            r#"{{"voltage":{},"current":{},"real_power":{}}}"#,
+            json_number(self.voltage),
+            json_number(self.current),
+            self.real_power.map_or("null".to_string(), json_number)
+        )
+    }
+}
+
+impl CalibrationStatus {
+    pub fn to_json(&self) -> String {
+        match self {
+            CalibrationStatus::Idle => r#"{"state":"idle"}"#.to_string(),
+            CalibrationStatus::Pending(job) => {
+                format!(r#"{{"state":"pending","ct":{}}}"#, job.ct_id)
+            }
+            CalibrationStatus::Running(job) => {
+                format!(r#"{{"state":"running","ct":{}}}"#, job.ct_id)
+            }
+            CalibrationStatus::Done(report) => format!(
+                r#"{{"state":"done","ct":{},"before":{},"after":{},"error_before":{},"error_after":{}}}"#,
+                report.ct_id,
+                report.before.to_json(report.ct_id),
+                report.after.to_json(report.ct_id),
+                report.error_before.to_json(),
+                report.error_after.to_json()
+            ),
+            CalibrationStatus::Failed(e) => {
+                format!(r#"{{"state":"failed","error":{}}}"#, json_string(e))
+            }
+        }
+    }
+}
+
+/// Measure `runs` times with the current calibration of `ct` and find the calibration that makes
+/// its readings match the reference meter of `job`, then measure `runs` times more with the new
+/// calibration to check it.
+///
+/// `vcal` and `ical` are scaled by how far off the rms values are. `phase_cal` is only changed if
+/// the real power is known, and is solved for the value where the power factor matches the one of
+/// the reference. The dc offsets the CT has settled on are kept. The CT is left with its old
+/// calibration, the new one is applied once it is stored.
+pub(crate) fn run<S: SampleSource>(
+    ct: &mut CT<S>,
+    job: &CalibrationJob,
+    runs: u32,
+    crossing: u32,
+    timeout: Duration,
+) -> anyhow::Result<CalibrationReport> {
+    info!("Calibrating CT {} with {:?}", ct.id(), job);
+    let before = ct.calibration();
+    let mut sums = ct.accumulate(crossing, timeout);
+    for _ in 1..runs {
+        sums += ct.accumulate(crossing, timeout);
+    }
+
+    let v_rms = sums.v_rms(before.vcal);
+    let i_rms = sums.i_rms(before.ical);
+    if !(v_rms.is_finite() && v_rms > 0.0) {
+        anyhow::bail!("No voltage was measured.");
+    }
+    if !(i_rms.is_finite() && i_rms > 0.0) {
+        anyhow::bail!("No current was measured, is the load on?");
+    }
+    let mut after = Calibration {
+        vcal: before.vcal * job.voltage / v_rms,
+        ical: before.ical * job.current / i_rms,
+        ..before
+    };
+    if let Some(real_power) = job.real_power {
+        let power_factor = f32::min(real_power / (job.voltage * job.current), 1.0);
+        after.phase_cal = match sums.phase_cal_for(power_factor, before.phase_cal) {
+            Some(phase_cal) if (-1.0..=3.0).contains(&phase_cal) => phase_cal,
+            _ => anyhow::bail!(
+                "Could not find a phase_cal for this power, check the reference values."
+            ),
+        };
+    }
+    after.validate()?;
+    let error_before = calibration_error(&sums, &before, job);
+
+    // the new gains fit the first measurements by construction, so they are checked on new ones.
+    ct.set_calibration(Calibration {
+        offset_i: ct.calibration().offset_i,
+        offset_v: ct.calibration().offset_v,
+        ..after
+    });
+    let mut check_sums = ct.accumulate(crossing, timeout);
+    for _ in 1..runs {
+        check_sums += ct.accumulate(crossing, timeout);
+    }
+    let error_after = calibration_error(&check_sums, &ct.calibration(), job);
+    ct.set_calibration(Calibration {
+        offset_i: ct.calibration().offset_i,
+        offset_v: ct.calibration().offset_v,
+        ..before
+    });
+
+    Ok(CalibrationReport {
+        ct_id: ct.id(),
+        before,
+        after,
+        error_before,
+        error_after,
+    })
+}
+
+// How far the readings of `sums` with this calibration are off from the reference meter.
+fn calibration_error(
+    sums: &Accumulators,
+    calibration: &Calibration,
+    job: &CalibrationJob,
+) -> CalibrationError {
+    CalibrationError {
+        voltage: percent_error(sums.v_rms(calibration.vcal), job.voltage),
+        current: percent_error(sums.i_rms(calibration.ical), job.current),
+        real_power: job
+            .real_power
+            .map(|real_power| percent_error(sums.real_power(calibration).abs(), real_power)),
+    }
+}
+
+fn percent_error(measured: f32, reference: f32) -> f32 {
+    (measured - reference) / reference * 100.0
+}
@@ -0,0 +1,281 @@
+//! How the CTs are wired to the board.
+//!
+//! The channel map lists the CTs, each with the ADC1 GPIO of its current sensor and the one of the
+//! voltage sensor it is paired with, so CTs on the same phase can share a voltage sensor. It also
+//! has the default calibration of the sensors, for CTs that were never calibrated. The map is kept
+//! in the keystore, so one firmware serves every board variant, but the pins are only set up at
+//! boot, so a new map is used from the next boot on.
+//!
+//! Devices that ran a firmware from before the map was kept don't have one. The board they were
+//! built for is told from what that firmware left behind: records of more than one CT, or
+//! calibrations, energy or demand registers of CT 2 or 3, were made by the three-phase build.
+//! The map found this way is stored, so it is only looked for once.
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+use crate::calibration::{Calibration, DEFAULT_CALIBRATION};
+use crate::ct::newest_ct_id;
+use crate::keystore::{KeyStore, SharedKeyStore};
+use crate::utils::add_f32_to_buf;
+use crate::MAX_CTS;
+
+/// The GPIOs of ADC1 that can be used, the others are not broken out on the modules.
+pub const ADC1_GPIOS: [u8; 6] = [32, 33, 34, 35, 36, 39];
+
+const CHANNELS_KEY: &str = "channels";
+
+/// The sensors of a CT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Channel {
+    pub current_gpio: u8,
+    pub voltage_gpio: u8,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelMap {
+    /// CT `i + 1` is at `i`.
+    pub channels: Vec<Channel>,
+    pub ical: f32,
+    pub vcal: f32,
+}
+
+/// The channel map in use and the one for the next boot, shared with the web server.
+pub struct Channels {
+    keystore: SharedKeyStore,
+    active: ChannelMap,
+    next: ChannelMap,
+}
+
+pub type SharedChannels = Arc<Mutex<Channels>>;
+
+impl ChannelMap {
+    /// The board the `single-phase` build was made for.
+    pub fn single_phase() -> Self {
+        ChannelMap {
+            channels: vec![Channel {
+                current_gpio: 35,
+                voltage_gpio: 34,
+            }],
+            ical: DEFAULT_CALIBRATION.ical,
+            vcal: DEFAULT_CALIBRATION.vcal,
+        }
+    }
+
+    /// The board the `three-phase` build was made for.
+    pub fn three_phase() -> Self {
+        let channel = |current_gpio, voltage_gpio| Channel {
+            current_gpio,
+            voltage_gpio,
+        };
+        ChannelMap {
+            channels: vec![channel(32, 39), channel(35, 36), channel(34, 33)],
+            ical: 30.0,
+            vcal: 219.25,
+        }
+    }
+
+    /// The board a firmware from before the channel map was built for, three-phase if it left
+    /// data of a second CT behind.
+    fn previous_build(keystore: &dyn KeyStore, root: &Path) -> anyhow::Result<Self> {
+        let mut three_phase = matches!(newest_ct_id(root)?, Some(ct_id) if ct_id > 1);
+        let mut buf = [0_u8; 64];
+        for ct_id in 2..=3 {
+            for key in ["cal", "imp", "exp", "kwh", "demand"] {
+                three_phase |= keystore
+                    .get_raw(&format!("{}{}", key, ct_id), &mut buf)?
+                    .is_some();
+            }
+        }
+        Ok(if three_phase {
+            ChannelMap::three_phase()
+        } else {
+            ChannelMap::single_phase()
+        })
+    }
+
+    pub fn preset(name: &str) -> Option<Self> {
+        match name {
+            "single-phase" => Some(ChannelMap::single_phase()),
+            "three-phase" => Some(ChannelMap::three_phase()),
+            _ => None,
+        }
+    }
+
+    /// Makes sure every CT can be set up, and no GPIO is used for two things.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(1..=MAX_CTS).contains(&self.channels.len()) {
+            anyhow::bail!("There must be between 1 and {} CTs.", MAX_CTS);
+        }
+        for (i, channel) in self.channels.iter().enumerate() {
+            for gpio in [channel.current_gpio, channel.voltage_gpio] {
+                if !ADC1_GPIOS.contains(&gpio) {
+                    anyhow::bail!("GPIO {} of CT {} is not on ADC1.", gpio, i + 1);
+                }
+            }
+            if self.channels.iter().enumerate().any(|(j, other)| {
+                other.voltage_gpio == channel.current_gpio
+                    || (j != i && other.current_gpio == channel.current_gpio)
+            }) {
+                anyhow::bail!(
+                    "GPIO {} of CT {} is used more than once.",
+                    channel.current_gpio,
+                    i + 1
+                );
+            }
+        }
+        self.default_calibration().validate()
+    }
+
+    pub fn len(&self) -> usize {
+        self.channels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.channels.is_empty()
+    }
+
+    /// The calibration of CTs that were never calibrated.
+    pub fn default_calibration(&self) -> Calibration {
+        Calibration {
+            ical: self.ical,
+            vcal: self.vcal,
+            ..DEFAULT_CALIBRATION
+        }
+    }
+
+    // the number of CTs, a GPIO pair for every CT, then ical and vcal.
+    fn to_le_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let mut buf = vec![0_u8; 1 + 2 * self.channels.len() + 2 * std::mem::size_of::<f32>()];
+        buf[0] = self.channels.len() as u8;
+        let mut pos = 1;
+        for channel in self.channels.iter() {
+            buf[pos] = channel.current_gpio;
+            buf[pos + 1] = channel.voltage_gpio;
+            pos += 2;
+        }
+        pos += add_f32_to_buf(&self.ical, &mut buf, &pos)?;
+        add_f32_to_buf(&self.vcal, &mut buf, &pos)?;
+        Ok(buf)
+    }
+
+    fn from_le_bytes(buf: &[u8]) -> Option<Self> {
+        let count = *buf.first()? as usize;
+        let f32_at = |pos: usize| {
+            let bytes = buf.get(pos..pos + 4)?;
+            Some(f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        };
+        let channels = buf
+            .get(1..1 + 2 * count)?
+            .chunks_exact(2)
+            .map(|gpios| Channel {
+                current_gpio: gpios[0],
+                voltage_gpio: gpios[1],
+            })
+            .collect();
+        Some(ChannelMap {
+            channels,
+            ical: f32_at(1 + 2 * count)?,
+            vcal: f32_at(5 + 2 * count)?,
+        })
+    }
+
+    pub fn to_json(&self) -> String {
+        let channels = self
+            .channels
+            .iter()
+            .enumerate()
+            .map(|(i, channel)| {
+                format!(
+                    r#"{{"ct":{},"current_gpio":{},"voltage_gpio":{}}}"#,
+                    i + 1,
+                    channel.current_gpio,
+                    channel.voltage_gpio
+                )
+            })
+            .collect::<Vec<String>>();
+        format!(
+            r#"{{"cts":[{}],"ical":{},"vcal":{}}}"#,
+            channels.join(","),
+            self.ical,
+            self.vcal
+        )
+    }
+}
+
+impl Channels {
+    /// Loads the channel map from the keystore, it is used until the next boot. A device without
+    /// a map gets the one of the board it was built for, found from the keystore and the records
+    /// under `root`.
+    pub fn load(keystore: SharedKeyStore, root: &Path) -> anyhow::Result<SharedChannels> {
+        let mut map = ChannelMap::single_phase();
+        {
+            let mut keystore = match keystore.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            let mut buf = [0_u8; 1 + 2 * MAX_CTS + 2 * std::mem::size_of::<f32>()];
+            match keystore.get_raw(CHANNELS_KEY, &mut buf)? {
+                Some(len) => match ChannelMap::from_le_bytes(&buf[..len]) {
+                    Some(stored) => match stored.validate() {
+                        Ok(()) => map = stored,
+                        Err(e) => warn!("Ignored stored channel map {:?}: {:?}", stored, e),
+                    },
+                    None => warn!("Ignored stored channel map of {} bytes.", len),
+                },
+                None => {
+                    map = ChannelMap::previous_build(&**keystore, root)?;
+                    keystore.put_raw(CHANNELS_KEY, &map.to_le_bytes()?)?;
+                    warn!(
+                        "There was no channel map, stored {:?} from what the previous firmware left behind. Change it with /channels if it is wrong.",
+                        map
+                    );
+                }
+            }
+        }
+        info!("Channel map: {:?}", map);
+        Ok(Arc::new(Mutex::new(Channels {
+            keystore,
+            active: map.clone(),
+            next: map,
+        })))
+    }
+
+    /// The channel map in use since boot.
+    pub fn active(&self) -> &ChannelMap {
+        &self.active
+    }
+
+    /// The channel map that will be used from the next boot on.
+    pub fn next(&self) -> &ChannelMap {
+        &self.next
+    }
+
+    /// Stores a channel map for the next boot.
+    pub(crate) fn set(&mut self, map: ChannelMap) -> anyhow::Result<()> {
+        map.validate()?;
+        {
+            let mut keystore = match self.keystore.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            keystore.put_raw(CHANNELS_KEY, &map.to_le_bytes()?)?;
+        }
+        info!("Channel map for the next boot set to {:?}", map);
+        self.next = map;
+        Ok(())
+    }
+
+    /// The map in use and the one for the next boot, e.g.
+    /// `{"active":{"cts":[{"ct":1,"current_gpio":35,"voltage_gpio":34}],"ical":102,"vcal":232.5},"next":{...}}`.
+    pub fn to_json(&self) -> String {
+        format!(
+            r#"{{"active":{},"next":{}}}"#,
+            self.active.to_json(),
+            self.next.to_json()
+        )
+    }
+}
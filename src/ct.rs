@@ -1,120 +1,482 @@
-use crate::{now, set_system_time, ACCESS_TOKEN_SIZE, MAX_TIME_STORAGE_SIZE};
+use crate::{
+    now, set_system_time, uptime, ACCESS_TOKEN_SIZE, BOOT_EVENT_SIZE, MAX_TIME_STORAGE_SIZE,
+    VERSION,
+};
 use std::collections::HashSet;
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use std::{fs, ops};
 
-use embedded_hal_0_2_7::adc::OneShot;
+#[cfg(feature = "esp")]
+use std::cell::RefCell;
+#[cfg(feature = "esp")]
+use std::rc::Rc;
 
-use embedded_svc::io::Write as SvcWrite;
-use esp_idf_hal::adc::{Atten11dB, PoweredAdc, ADC1};
-use esp_idf_hal::gpio::{Gpio34, Gpio35, Pins};
-use esp_idf_svc::http::server::EspHttpResponseWrite;
+#[cfg(feature = "esp")]
+use esp_idf_hal::adc::{PoweredAdc, ADC1};
+#[cfg(feature = "esp")]
+use esp_idf_hal::gpio::Pins;
 
+use crate::calibration::{Calibration, DEFAULT_CALIBRATION};
+#[cfg(feature = "esp")]
+use crate::channels::ChannelMap;
+use crate::demand::Peak;
+use crate::energy::EnergyRegisters;
+use crate::events::{EventDetector, EventThresholds};
+#[cfg(feature = "harmonics")]
+use crate::harmonics::SampleBuffer;
+use crate::keystore::SharedKeyStore;
+use crate::record::{AlarmEvent, BootEvent, Record, ShardHeader, VoltageEvent};
+use crate::sample::SampleSource;
+#[cfg(feature = "esp")]
+use crate::sample::{AdcChannel, AdcSampleSource};
 use crate::{
-    utils::*, AC_PHASE, CT_READING_SIZE, MAX_MV_ATTEN_11, MAX_SHARD_SIZE, NOISE_THRESHOLD,
-    SAVE_PERIOD_TIMEOUT, SUPPLY_VOLTAGE,
+    CT_READING_SIZE, LEGACY_POWERLOSS_ENTRY_SIZE, MAX_CYCLE_TIME, MAX_EVENT_LOG_SIZE,
+    MAX_MV_ATTEN_11, MAX_SHARD_SIZE, MAX_TARIFFS, NOISE_THRESHOLD, POWERLOSS_ENTRY_SIZE,
+    SECONDS_PER_HOUR, SEQ_RESERVE, SHARD_HEADER_SIZE, SHARD_MAGIC, SUPPLY_VOLTAGE,
 };
 
 #[allow(unused_imports)]
 use log::{debug, error, info, warn};
-struct VoltagePin {
-    pin: Gpio34<Atten11dB<ADC1>>,
+struct VoltageChannel {
     vcal: f32,
     phase_cal: f32,
     offset_v: f32,
 }
 
-struct CurrentPin {
-    pin: Gpio35<Atten11dB<ADC1>>,
+struct CurrentChannel {
     ical: f32,
     offset_i: f32,
 }
 
-pub struct CT {
+/// What `CT::accumulate` adds up over the samples of one measurement.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Accumulators {
+    n_samples: u32,
+    sum_v: f32,
+    sum_i: f32,
+    /// The phase shifted voltage is `last_v + phase_cal * delta_v`, where `last_v` is the previous
+    /// voltage sample and `delta_v` the difference to the current one. Its products with the
+    /// current add up to `sum_p_last + phase_cal * sum_p_delta`, and its squares to
+    /// `sum_v_last + 2 * phase_cal * sum_v_cross + phase_cal^2 * sum_v_delta`.
+    sum_p_last: f32,
+    sum_p_delta: f32,
+    sum_v_last: f32,
+    sum_v_cross: f32,
+    sum_v_delta: f32,
+    /// Number of whole voltage periods that were timed, and how long they took together.
+    periods: u32,
+    periods_time: Duration,
+    elapsed: Duration,
+}
+
+pub struct CT<S: SampleSource> {
     id: u16,
-    current_pin: CurrentPin,
-    voltage_pin: VoltagePin,
+    source: S,
+    current_channel: CurrentChannel,
+    voltage_channel: VoltageChannel,
+    #[cfg(feature = "harmonics")]
+    samples: SampleBuffer,
+    /// When the last measurement ended, as time since boot.
+    last_measured: Option<Duration>,
+    /// The measurements since the last save.
+    aggregate: ReadingAggregate,
+    /// Finds voltage events in the cycles that are measured.
+    events: EventDetector,
+    /// The tariff the energy of the next measurement goes to, if there is one.
+    tariff: Option<u8>,
     pub reading: CTReading,
 }
 
-#[derive(Debug)]
+/// The readings of a CT over a save period. Powers, Irms, Vrms, frequency and THD are
+/// time-weighted means over the measurements, the energies are totals.
+#[derive(Debug, Default, Clone)]
 pub struct CTReading {
-    real_power: f32,
-    apparent_power: f32,
-    i_rms: f32,
-    v_rms: f32,
-    kwh: f32,
-    timestamp: u64,
+    /// Positive when power is imported, negative when it is exported.
+    pub(crate) real_power: f32,
+    pub(crate) apparent_power: f32,
+    /// Positive for inductive loads, negative for capacitive ones.
+    pub(crate) reactive_power: f32,
+    /// Has the sign of the real power.
+    pub(crate) power_factor: f32,
+    pub(crate) i_rms: f32,
+    pub(crate) v_rms: f32,
+    /// The net energy, `import_kwh - export_kwh`.
+    pub(crate) kwh: f32,
+    pub(crate) import_kwh: f32,
+    pub(crate) export_kwh: f32,
+    /// Mains frequency in Hz, 0 if it could not be measured.
+    pub(crate) frequency: f32,
+    /// Total harmonic distortion of the voltage and the current in percent. Only measured with
+    /// the `harmonics` feature, 0 otherwise.
+    pub(crate) v_thd: f32,
+    pub(crate) i_thd: f32,
+    pub(crate) real_power_stats: MinMaxLast,
+    pub(crate) i_rms_stats: MinMaxLast,
+    pub(crate) v_rms_stats: MinMaxLast,
+    /// Number of measurements the reading is made of.
+    pub(crate) samples: u32,
+    pub(crate) timestamp: u64,
+    /// Milliseconds since boot when `timestamp` was taken.
+    pub(crate) uptime: u64,
+    /// The demand of the last whole demand window in kW, and the peak demand of the month with the
+    /// end of its window, see `demand`.
+    pub(crate) demand: f32,
+    pub(crate) peak_demand: f32,
+    pub(crate) peak_demand_time: u64,
+    /// The imported and exported kWh by tariff, see `tariffs`.
+    pub(crate) tariff_import_kwh: [f32; MAX_TARIFFS],
+    pub(crate) tariff_export_kwh: [f32; MAX_TARIFFS],
 }
 
 pub struct CTStorage {
+    /// Where the filesystem is mounted, "/littlefs" on the device.
+    root: PathBuf,
+    /// MAC address of the device, written in the shard headers.
+    mac: [u8; 6],
     pub readings_shard_counter: i32,
     pub readings_shards: HashSet<i32>,
+    /// Number of torn records cut off the newest shard since boot.
+    pub dropped_records: usize,
+    /// Sequence number of the next stored record.
+    next_seq: u64,
+    /// Sequence numbers below this one may have been used before, it is kept in the keystore.
+    reserved_seq: u64,
+    /// Number of times the device has booted, counted from 1.
+    boot_count: u32,
+    /// The last time stored before this boot, 0 if there was none.
+    last_stored_time: u64,
+    /// The number of CTs, which are saved together.
+    ct_count: usize,
+    /// The lifetime energy of every CT.
+    energy: EnergyRegisters,
+    keystore: SharedKeyStore,
 }
 
 impl CTStorage {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(
+        root: PathBuf,
+        mac: [u8; 6],
+        keystore: SharedKeyStore,
+        ct_count: usize,
+    ) -> Self {
         CTStorage {
+            root,
+            mac,
             readings_shard_counter: 1,
             readings_shards: HashSet::new(),
+            dropped_records: 0,
+            next_seq: 1,
+            reserved_seq: 1,
+            boot_count: 0,
+            last_stored_time: 0,
+            ct_count,
+            energy: EnergyRegisters::new(keystore.clone(), ct_count),
+            keystore,
+        }
+    }
+
+    /// Restores the lifetime energy registers from the keystore and the newest records.
+    pub(crate) fn load_energy_registers(&mut self) -> anyhow::Result<()> {
+        self.energy.load()?;
+        // the CTs are saved together, so the newest record of every CT is among the last ones.
+        let mut last_seq = 0;
+        for shard_id in self.sorted_shard_ids().into_iter().rev() {
+            if let Some(seq) = self.last_seq(shard_id)? {
+                last_seq = seq;
+                break;
+            }
         }
+        if last_seq > 0 {
+            let from_seq = (last_seq + 1).saturating_sub(self.ct_count as u64).max(1);
+            let mut restored = Vec::new();
+            self.for_each_record(from_seq, |record| {
+                restored.push(record);
+                Ok(true)
+            })?;
+            for record in restored {
+                self.energy.restore(
+                    record.ct_id,
+                    record.lifetime_import_kwh,
+                    record.lifetime_export_kwh,
+                );
+                self.energy.restore_tariffs(
+                    record.ct_id,
+                    &record.lifetime_tariff_import_kwh,
+                    &record.lifetime_tariff_export_kwh,
+                );
+            }
+        }
+        for ct_id in 1..=self.ct_count as u16 {
+            if let Some((import_kwh, export_kwh)) = self.energy.get(ct_id) {
+                info!(
+                    "Lifetime energy of CT {}: {} kWh imported, {} kWh exported",
+                    ct_id, import_kwh, export_kwh
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn energy(&self) -> &EnergyRegisters {
+        &self.energy
+    }
+
+    fn shard_path(&self, shard_id: i32) -> PathBuf {
+        self.root.join("ct_readings").join(shard_id.to_string())
     }
 
     //Reset everything and clear all files
     pub(crate) fn reset_storage(&mut self) -> anyhow::Result<()> {
-        std::fs::remove_file("/littlefs/powerloss_log")?;
-        std::fs::remove_dir_all("/littlefs/ct_readings")?;
+        // the records won't be there to restore the lifetime energy from.
+        self.energy.store()?;
+        std::fs::remove_file(self.root.join("boot_log"))?;
+        self.remove_log("event_log")?;
+        self.remove_log("alarm_log")?;
+        std::fs::remove_dir_all(self.root.join("ct_readings"))?;
         info!("Deleted Everything.");
         fs::OpenOptions::new()
             .write(true)
             .create(true)
-            .open("/littlefs/powerloss_log")?;
+            .truncate(true)
+            .open(self.root.join("boot_log"))?;
         self.readings_shard_counter = 1;
         self.readings_shards = HashSet::new();
         self.find_newest_readings_shard_num()?;
         Ok(())
     }
 
-    // Whenever the esp boots, it restores the previously set RTC and stores that RTC in a log.
-    pub(crate) fn log_powerloss(&mut self) -> anyhow::Result<()> {
+    // Increment the boot counter in the keystore.
+    // the powerloss log of older firmware is moved to the boot log on the first boot with this one.
+    pub(crate) fn count_boot(&mut self) -> anyhow::Result<()> {
+        let mut keystore = match self.keystore.lock() {
+            Ok(gaurd) => gaurd,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let mut buf = [0_u8; std::mem::size_of::<u32>()];
+        let boot_count = match keystore.get_raw("boot_count", &mut buf)? {
+            Some(_) => {
+                self.convert_powerloss_log(POWERLOSS_ENTRY_SIZE)?;
+                u32::from_le_bytes(buf)
+            }
+            None => {
+                self.convert_powerloss_log(LEGACY_POWERLOSS_ENTRY_SIZE)?;
+                0
+            }
+        };
+        self.boot_count = boot_count + 1;
+        keystore.put_raw("boot_count", &self.boot_count.to_le_bytes())?;
+        info!("Boot number {}.", self.boot_count);
+        Ok(())
+    }
+
+    // Move the entries of the powerloss log of older firmware to the boot log.
+    // entries of `LEGACY_POWERLOSS_ENTRY_SIZE` only have the time, the longer ones also have the
+    // boot counter and uptime. Everything else is unknown and left 0.
+    fn convert_powerloss_log(&self, entry_size: usize) -> anyhow::Result<()> {
+        let powerloss_log = match fs::read(self.root.join("powerloss_log")) {
+            Ok(powerloss_log) => powerloss_log,
+            Err(_) => return Ok(()),
+        };
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.root.join("boot_log"))?;
+        for entry in powerloss_log.chunks_exact(entry_size) {
+            let time_size = std::mem::size_of::<u128>();
+            let mut time_buf = [0_u8; std::mem::size_of::<u128>()];
+            time_buf.copy_from_slice(&entry[..time_size]);
+            let mut event = BootEvent {
+                restored_time: u128::from_le_bytes(time_buf) as u64,
+                ..Default::default()
+            };
+            if entry_size == POWERLOSS_ENTRY_SIZE {
+                let mut boot_count_buf = [0_u8; std::mem::size_of::<u32>()];
+                boot_count_buf.copy_from_slice(&entry[time_size..time_size + 4]);
+                event.boot_count = u32::from_le_bytes(boot_count_buf);
+                let mut uptime_buf = [0_u8; std::mem::size_of::<u64>()];
+                uptime_buf.copy_from_slice(&entry[time_size + 4..POWERLOSS_ENTRY_SIZE]);
+                event.uptime = u64::from_le_bytes(uptime_buf);
+            }
+            file.write_all(&event.to_le_bytes()?)?;
+        }
+        file.flush()?;
+        fs::remove_file(self.root.join("powerloss_log"))?;
+        info!("Moved the powerloss log to the boot log.");
+        Ok(())
+    }
+
+    // Whenever the esp boots, it restores the previously set RTC and logs why it was reset, the
+    // restored RTC and the boot counter in the boot log.
+    pub(crate) fn log_boot(&mut self, reset_reason: u16) -> anyhow::Result<()> {
         if let Ok(mut file) = fs::OpenOptions::new()
-            .write(true)
             .create(true)
             .append(true)
-            .open("/littlefs/powerloss_log")
+            .open(self.root.join("boot_log"))
         {
+            let event = BootEvent {
+                reset_reason,
+                firmware_version: VERSION,
+                boot_count: self.boot_count,
+                restored_time: now().as_millis() as u64,
+                last_stored_time: self.last_stored_time,
+                uptime: uptime().as_millis() as u64,
+            };
             file.seek(SeekFrom::End(0))?;
-            file.write_all(&now().as_millis().to_le_bytes())?;
-            info!("logged powerloss at {}", now().as_millis());
+            file.write_all(&event.to_le_bytes()?)?;
+            info!("logged boot: {:?}", event);
         }
         Ok(())
     }
 
-    // Dump the powerloss log into the given writer and after that delete the log.
-    pub(crate) fn send_powerloss_log(
-        &mut self,
-        writer: &mut EspHttpResponseWrite,
-    ) -> anyhow::Result<()> {
+    // Send the boot log into the given writer.
+    pub(crate) fn send_boot_log(&mut self, writer: &mut dyn Write) -> anyhow::Result<()> {
         // open the log file and send data. If no log is available an empty response is sent.
         if let Ok(mut file) = fs::OpenOptions::new()
-            .write(true)
             .read(true)
-            .open("/littlefs/powerloss_log")
+            .open(self.root.join("boot_log"))
         {
-            let mut buf = [0_u8; std::mem::size_of::<u128>() * 5];
+            let mut buf = [0_u8; BOOT_EVENT_SIZE * 4];
             loop {
                 let n = file.read(&mut buf)?;
                 if n == 0 {
                     break;
                 }
-                writer.write_all(&buf)?;
+                writer.write_all(&buf[..n])?;
             }
             writer.flush()?;
         }
         Ok(())
     }
 
+    /// Append voltage events to the event log.
+    pub(crate) fn log_events(&mut self, events: &[VoltageEvent]) -> anyhow::Result<()> {
+        let mut entries = Vec::new();
+        for event in events {
+            let event = VoltageEvent {
+                boot_count: self.boot_count,
+                ..*event
+            };
+            info!("logged event: {:?}", event);
+            entries.extend_from_slice(&event.to_le_bytes()?);
+        }
+        self.append_to_log("event_log", &entries)
+    }
+
+    /// Send the voltage events, oldest first, as a JSON array into the given writer.
+    pub(crate) fn send_events_json(&mut self, writer: &mut dyn Write) -> anyhow::Result<()> {
+        writer.write_all(b"[")?;
+        let mut first = true;
+        self.for_each_log_entry("event_log", |entry| {
+            if !std::mem::replace(&mut first, false) {
+                writer.write_all(b",")?;
+            }
+            writer.write_all(VoltageEvent::from_le_bytes(entry).to_json().as_bytes())?;
+            Ok(())
+        })?;
+        writer.write_all(b"]")?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Append alarms that triggered or cleared to the alarm log.
+    pub(crate) fn log_alarms(&mut self, alarms: &[AlarmEvent]) -> anyhow::Result<()> {
+        let mut entries = Vec::new();
+        for alarm in alarms {
+            let alarm = AlarmEvent {
+                boot_count: self.boot_count,
+                ..*alarm
+            };
+            info!("logged alarm: {:?}", alarm);
+            entries.extend_from_slice(&alarm.to_le_bytes()?);
+        }
+        self.append_to_log("alarm_log", &entries)
+    }
+
+    /// Send the alarm log, oldest first, as a JSON array into the given writer. With
+    /// `thingsboard`, the entries are ThingsBoard telemetry objects.
+    pub(crate) fn send_alarms_json(
+        &mut self,
+        writer: &mut dyn Write,
+        thingsboard: bool,
+    ) -> anyhow::Result<()> {
+        writer.write_all(b"[")?;
+        let mut first = true;
+        self.for_each_log_entry("alarm_log", |entry| {
+            if !std::mem::replace(&mut first, false) {
+                writer.write_all(b",")?;
+            }
+            let alarm = AlarmEvent::from_le_bytes(entry);
+            let json = if thingsboard {
+                alarm.to_thingsboard_json()
+            } else {
+                alarm.to_json()
+            };
+            writer.write_all(json.as_bytes())?;
+            Ok(())
+        })?;
+        writer.write_all(b"]")?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    // Append entries to one of the logs. Once a log is full, it is kept as the previous one and a
+    // new log is started, so at most two of them are kept.
+    fn append_to_log(&self, log: &str, entries: &[u8]) -> anyhow::Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let path = self.root.join(log);
+        if fs::metadata(&path).map(|m| m.len()).unwrap_or(0) >= MAX_EVENT_LOG_SIZE {
+            fs::rename(&path, self.root.join(format!("{}.old", log)))?;
+            info!("Started a new {}.", log);
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        file.write_all(entries)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    // Call `f` with every entry of one of the logs, oldest first. Every entry starts with its size.
+    fn for_each_log_entry(
+        &self,
+        log: &str,
+        mut f: impl FnMut(&[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        for path in [self.root.join(format!("{}.old", log)), self.root.join(log)] {
+            let buf = match fs::read(&path) {
+                Ok(buf) => buf,
+                Err(_) => continue,
+            };
+            let mut pos = 0;
+            while pos + 2 <= buf.len() {
+                let size = u16::from_le_bytes([buf[pos], buf[pos + 1]]) as usize;
+                if size < 2 || pos + size > buf.len() {
+                    warn!("Skipped the rest of {:?} at a torn entry.", path);
+                    break;
+                }
+                f(&buf[pos..pos + size])?;
+                pos += size;
+            }
+        }
+        Ok(())
+    }
+
+    // Delete one of the logs and its previous one.
+    fn remove_log(&self, log: &str) -> anyhow::Result<()> {
+        for path in [self.root.join(format!("{}.old", log)), self.root.join(log)] {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
     /// Find the newest readings shard id
     ///
     /// under "/littlefs/ct_readings" files are saved with a number as their filename.
@@ -122,7 +484,7 @@ impl CTStorage {
     /// its filename). This is the file that we will be appending new data to.
     pub(crate) fn find_newest_readings_shard_num(&mut self) -> anyhow::Result<()> {
         let mut max_num = 1;
-        if let Ok(paths) = fs::read_dir("/littlefs/ct_readings") {
+        if let Ok(paths) = fs::read_dir(self.root.join("ct_readings")) {
             for path in paths {
                 info!("Shard: {:?}", path);
                 let num = path?.file_name().to_str().unwrap().parse()?;
@@ -130,7 +492,7 @@ impl CTStorage {
                 self.readings_shards.insert(num);
             }
         } else {
-            fs::create_dir("/littlefs/ct_readings")?;
+            fs::create_dir_all(self.root.join("ct_readings"))?;
         }
         self.readings_shard_counter = max_num;
 
@@ -139,62 +501,173 @@ impl CTStorage {
             fs::OpenOptions::new()
                 .write(true)
                 .create(true)
-                .open(format!(
-                    "/littlefs/ct_readings/{}",
-                    self.readings_shard_counter
-                ))?;
+                .truncate(false)
+                .open(self.shard_path(self.readings_shard_counter))?;
             self.readings_shards.insert(1);
             info!("Made sure the first shard is created.");
         }
+
+        // only the shard that was being appended to can end with a torn write.
+        let dropped = self.truncate_torn_records(self.readings_shard_counter)?;
+        if dropped > 0 {
+            warn!(
+                "Dropped {} torn records at the end of shard {}.",
+                dropped, self.readings_shard_counter
+            );
+        }
+        self.dropped_records += dropped;
         info!("Next shard will be: {:?}", self.readings_shard_counter);
+
+        // continue after the newest stored record, or after the numbers that were reserved before
+        // the reboot, whichever is higher. Either one can be missing after a reset.
+        for shard_id in self.readings_shards.iter() {
+            if let Some(seq) = self.last_seq(*shard_id)? {
+                self.next_seq = u64::max(self.next_seq, seq + 1);
+            }
+        }
+        {
+            let keystore = match self.keystore.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            if let Some(seq) = keystore.get_u64("next_seq")? {
+                self.next_seq = u64::max(self.next_seq, seq);
+            }
+        }
+        self.reserved_seq = self.next_seq;
+        info!("Next sequence number will be: {}", self.next_seq);
         Ok(())
     }
 
+    /// Cut off the records at the end of a shard that were not completely written, e.g. because
+    /// the power went out during `save_to_storage`. Returns how many records were dropped.
+    ///
+    /// A partial record counts as one, and so does every record at the end with a bad checksum.
+    fn truncate_torn_records(&self, shard_id: i32) -> anyhow::Result<usize> {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(self.shard_path(shard_id))?;
+        let len = file.metadata()?.len();
+
+        // A header that was cut off is written again by the next save.
+        let mut magic = [0_u8; SHARD_MAGIC.len()];
+        let n = file.read(&mut magic)?;
+        if n > 0 && len < SHARD_HEADER_SIZE as u64 && magic[..n] == SHARD_MAGIC[..n] {
+            file.set_len(0)?;
+            return Ok(0);
+        }
+
+        let header = ShardHeader::read_from(&mut file)?;
+        let records_start = file.stream_position()?;
+        let record_size = header.record_size as u64;
+        let records_len = len.saturating_sub(records_start);
+        let partial = if records_len % record_size != 0 { 1 } else { 0 };
+
+        // Legacy records have no checksum, so for them only a partial record can be found.
+        let (records, _) = last_valid_record(&mut file, &header)?;
+        let dropped = (records_len / record_size - records) as usize + partial;
+
+        let valid_len = records_start + records * record_size;
+        if valid_len < len {
+            file.set_len(valid_len)?;
+            info!(
+                "Truncated shard {} from {} to {} bytes.",
+                shard_id, len, valid_len
+            );
+        }
+        Ok(dropped)
+    }
+
+    // Sequence number of the last valid record of a shard, None if it has no records.
+    fn last_seq(&self, shard_id: i32) -> anyhow::Result<Option<u64>> {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .open(self.shard_path(shard_id))?;
+        let header = ShardHeader::read_from(&mut file)?;
+        let (_, record) = last_valid_record(&mut file, &header)?;
+        Ok(record.map(|record| record.seq))
+    }
+
     /// Save sensor readings to storage.
     ///
     /// this function does not do any synchronization. If something like mutex is needed, you must deal
     /// with it before calling this function.
     /// under "/littlefs/ct_readings" files are saved with a number as their filename.
     /// newer files have a higher number as their filename.
-    pub(crate) fn save_to_storage(&mut self, cts: &[CT; AC_PHASE]) -> anyhow::Result<()> {
-        // check whether the selected shard has enough size. if it doesn't create a new shard
-        println!(
-            "shard size {}",
-            fs::metadata(format!(
-                "/littlefs/ct_readings/{}",
-                self.readings_shard_counter
-            ))?
-            .len()
-        );
-        if (MAX_SHARD_SIZE as i64
-            - fs::metadata(format!(
-                "/littlefs/ct_readings/{}",
-                self.readings_shard_counter
-            ))?
-            .len() as i64)
-            < CT_READING_SIZE as i64
+    /// `flags` are the `Record` flags of the save period.
+    pub(crate) fn save_to_storage<S: SampleSource>(
+        &mut self,
+        cts: &[CT<S>],
+        flags: u16,
+    ) -> anyhow::Result<()> {
+        // check whether the selected shard has enough size and was written in the current format.
+        // if it doesn't create a new shard
+        let shard_size = fs::metadata(self.shard_path(self.readings_shard_counter))?.len();
+        println!("shard size {}", shard_size);
+        if (MAX_SHARD_SIZE as i64 - shard_size as i64) < CT_READING_SIZE as i64
+            || !self.shard_has_current_format(self.readings_shard_counter)?
         {
             self.readings_shard_counter += 1;
             self.readings_shards.insert(self.readings_shard_counter);
         }
         let mut file = fs::OpenOptions::new()
-            .write(true)
             .create(true)
             .append(true)
-            .open(format!(
-                "/littlefs/ct_readings/{}",
-                self.readings_shard_counter
-            ))?;
+            .open(self.shard_path(self.readings_shard_counter))?;
         info!(
-            "Opened {} for writing.",
-            format!("/littlefs/ct_readings/{}", self.readings_shard_counter)
+            "Opened {:?} for writing.",
+            self.shard_path(self.readings_shard_counter)
         );
 
-        // Append the readings for each CT at the end of the file
+        // Every shard starts with a header
+        if file.metadata()?.len() == 0 {
+            file.write_all(&ShardHeader::current(self.mac).to_le_bytes()?)?;
+        }
+
+        // Reserve sequence numbers before they are used, so they are never handed out twice.
+        if self.next_seq + cts.len() as u64 > self.reserved_seq {
+            let reserved_seq = self.next_seq + cts.len() as u64 + SEQ_RESERVE;
+            let mut keystore = match self.keystore.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            keystore.put_u64("next_seq", reserved_seq)?;
+            self.reserved_seq = reserved_seq;
+            info!("Reserved sequence numbers up to {}.", reserved_seq);
+        }
+
+        // Append the readings for each CT at the end of the file, with the lifetime energy including
+        // them.
         for ct in cts {
-            let buf = CTStorage::ct_reading_to_le_bytes(ct)?;
+            self.energy.add(
+                ct.id,
+                ct.reading.import_kwh as f64,
+                ct.reading.export_kwh as f64,
+            );
+            self.energy.add_tariffs(
+                ct.id,
+                &ct.reading.tariff_import_kwh,
+                &ct.reading.tariff_export_kwh,
+            );
+            let (lifetime_import_kwh, lifetime_export_kwh) =
+                self.energy.get(ct.id).unwrap_or_default();
+            let (lifetime_tariff_import_kwh, lifetime_tariff_export_kwh) =
+                self.energy.get_tariffs(ct.id).unwrap_or_default();
+            let record = Record {
+                ct_id: ct.id,
+                seq: self.next_seq,
+                boot_count: self.boot_count,
+                reading: ct.reading.clone(),
+                lifetime_import_kwh,
+                lifetime_export_kwh,
+                lifetime_tariff_import_kwh,
+                lifetime_tariff_export_kwh,
+                flags,
+            };
+            self.next_seq += 1;
             file.seek(SeekFrom::End(0))?;
-            file.write_all(&buf)?;
+            file.write_all(&record.to_le_bytes()?)?;
             info!("Wrote reading: {:?}", ct.reading);
         }
         file.flush()?;
@@ -202,16 +675,30 @@ impl CTStorage {
             "Flushed readings to storage and shard size is {}",
             file.metadata()?.len()
         );
+        self.energy.store_if_due()?;
         Ok(())
     }
 
+    // Whether new records can be appended to the given shard without mixing formats.
+    fn shard_has_current_format(&self, shard_id: i32) -> anyhow::Result<bool> {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .open(self.shard_path(shard_id))?;
+        if file.metadata()?.len() == 0 {
+            return Ok(true);
+        }
+        let header = ShardHeader::read_from(&mut file)?;
+        Ok(header.is_compatible(&ShardHeader::current(self.mac)))
+    }
+
     // Retrieve the latest time from storage and update RTC
     pub(crate) fn update_system_time(&mut self) -> anyhow::Result<()> {
         let mut file = fs::OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open("/littlefs/time")?;
+            .truncate(false)
+            .open(self.root.join("time"))?;
         if file
             .seek(std::io::SeekFrom::End(-(std::mem::size_of::<u64>() as i64)))
             .is_ok()
@@ -220,6 +707,7 @@ impl CTStorage {
             if file.read_exact(&mut time_buf).is_ok() {
                 let time = u64::from_le_bytes(time_buf);
                 println!("Found time from storage: {}", time);
+                self.last_stored_time = time;
                 set_system_time(time)?;
             }
         }
@@ -229,7 +717,7 @@ impl CTStorage {
     // Store the given time to storage
     pub(crate) fn store_time(&mut self, time: u64) -> anyhow::Result<()> {
         let mut file = if (MAX_TIME_STORAGE_SIZE as i64
-            - fs::metadata("/littlefs/time")?.len() as i64)
+            - fs::metadata(self.root.join("time"))?.len() as i64)
             < std::mem::size_of::<u64>() as i64
         {
             // If the file is full, create a new one overwriting the previous file.
@@ -237,13 +725,12 @@ impl CTStorage {
                 .write(true)
                 .create(true)
                 .truncate(true)
-                .open("/littlefs/time")?
+                .open(self.root.join("time"))?
         } else {
             fs::OpenOptions::new()
-                .write(true)
                 .create(true)
                 .append(true)
-                .open("/littlefs/time")?
+                .open(self.root.join("time"))?
         };
 
         file.seek(SeekFrom::End(0))?;
@@ -259,7 +746,7 @@ impl CTStorage {
         let mut file = fs::OpenOptions::new()
             .read(true)
             .write(true)
-            .open("/littlefs/token")?;
+            .open(self.root.join("token"))?;
         let mut token = [0_u8; ACCESS_TOKEN_SIZE];
         file.read_exact(&mut token)?;
         Ok(token)
@@ -271,7 +758,7 @@ impl CTStorage {
             .write(true)
             .create(true)
             .truncate(true)
-            .open("/littlefs/token")?;
+            .open(self.root.join("token"))?;
         file.write_all(token)?;
         log::info!(
             "Stored toke: {} to storage.",
@@ -280,57 +767,351 @@ impl CTStorage {
         Ok(())
     }
 
-    // Send reading shards one by one into this writer.
-    // before deleting a shard, we make sure that he have flushed thr writer.
-    pub(crate) fn send_readings_shards(
-        &mut self,
-        writer: &mut EspHttpResponseWrite,
+    /// Decode every record with a sequence number of at least `from_seq` in the shards, oldest
+    /// first, and pass it to `f` until it returns false.
+    ///
+    /// Legacy shards without a header are decoded too. Records with a bad checksum are skipped.
+    pub(crate) fn for_each_record(
+        &self,
+        from_seq: u64,
+        mut f: impl FnMut(Record) -> anyhow::Result<bool>,
     ) -> anyhow::Result<()> {
-        let mut sorted_shard_ids = self.readings_shards.iter().copied().collect::<Vec<i32>>();
-        sorted_shard_ids.sort();
-        // a fixed size buffer to avoid stack overflow
-        let mut buf = [0_u8; CT_READING_SIZE];
-        for shard_id in sorted_shard_ids {
+        for shard_id in self.sorted_shard_ids() {
+            // shards that only have older records don't have to be read.
+            if from_seq > 0 && self.last_seq(shard_id)?.unwrap_or(0) < from_seq {
+                continue;
+            }
             if let Ok(mut file) = fs::OpenOptions::new()
                 .read(true)
-                .write(true)
-                .create(true)
-                .open(format!("/littlefs/ct_readings/{}", shard_id))
+                .open(self.shard_path(shard_id))
             {
+                let header = ShardHeader::read_from(&mut file)?;
+                let mut buf = vec![0_u8; header.record_size as usize];
                 while file.read_exact(&mut buf).is_ok() {
-                    writer.write(&buf)?;
+                    match Record::from_le_bytes(&header, &buf) {
+                        Ok(record) if record.seq < from_seq => {}
+                        Ok(record) => {
+                            if !f(record)? {
+                                return Ok(());
+                            }
+                        }
+                        Err(e) => warn!("Skipped a record of shard {}: {:?}", shard_id, e),
+                    }
                 }
-                writer.flush()?;
-                info!(
-                    "Sent shard {}",
-                    format!("/littlefs/ct_readings/{}", shard_id)
-                );
             }
         }
         Ok(())
     }
 
-    fn ct_reading_to_le_bytes(ct: &CT) -> anyhow::Result<[u8; CT_READING_SIZE]> {
-        let mut buf = [0_u8; CT_READING_SIZE];
-        let mut pos = 0;
-        pos += add_u16_to_buf(&ct.id, &mut buf, &pos)?;
-        pos += add_f32_to_buf(&ct.reading.real_power, &mut buf, &pos)?;
-        pos += add_f32_to_buf(&ct.reading.apparent_power, &mut buf, &pos)?;
-        pos += add_f32_to_buf(&ct.reading.i_rms, &mut buf, &pos)?;
-        pos += add_f32_to_buf(&ct.reading.v_rms, &mut buf, &pos)?;
-        pos += add_f32_to_buf(&ct.reading.kwh, &mut buf, &pos)?;
-        add_u64_to_buf(&ct.reading.timestamp, &mut buf, &pos)?;
-        Ok(buf)
+    fn sorted_shard_ids(&self) -> Vec<i32> {
+        let mut sorted_shard_ids = self.readings_shards.iter().copied().collect::<Vec<i32>>();
+        sorted_shard_ids.sort();
+        sorted_shard_ids
+    }
+
+    // Send the readings of all shards into this writer.
+    // they are sent in the layout of the first firmware, without a header, so existing collectors
+    // keep working whatever format they were stored in. `send_records` sends all the fields.
+    pub(crate) fn send_readings_shards(&mut self, writer: &mut dyn Write) -> anyhow::Result<()> {
+        let mut sent = 0;
+        self.for_each_record(0, |record| {
+            writer.write_all(&record.to_legacy_le_bytes()?)?;
+            sent += 1;
+            Ok(true)
+        })?;
+        writer.flush()?;
+        info!("Sent {} records of all shards.", sent);
+        Ok(())
+    }
+
+    /// Send at most `limit` records with a sequence number of at least `from_seq` into this
+    /// writer, after a single shard header and in the current format.
+    pub(crate) fn send_records(
+        &mut self,
+        writer: &mut dyn Write,
+        from_seq: u64,
+        limit: usize,
+    ) -> anyhow::Result<()> {
+        writer.write_all(&ShardHeader::current(self.mac).to_le_bytes()?)?;
+        let mut sent = 0;
+        if limit > 0 {
+            self.for_each_record(from_seq, |record| {
+                writer.write_all(&record.to_le_bytes()?)?;
+                sent += 1;
+                Ok(sent < limit)
+            })?;
+        }
+        writer.flush()?;
+        info!("Sent {} records from sequence number {}.", sent, from_seq);
+        Ok(())
+    }
+
+    /// Like `send_records`, but as a ThingsBoard batch telemetry JSON array, which can be posted
+    /// to `/api/v1/<token>/telemetry` as it is.
+    pub(crate) fn send_records_json(
+        &mut self,
+        writer: &mut dyn Write,
+        from_seq: u64,
+        limit: usize,
+    ) -> anyhow::Result<()> {
+        writer.write_all(b"[")?;
+        let mut sent = 0;
+        if limit > 0 {
+            self.for_each_record(from_seq, |record| {
+                if sent > 0 {
+                    writer.write_all(b",")?;
+                }
+                writer.write_all(record.to_thingsboard_json().as_bytes())?;
+                sent += 1;
+                Ok(sent < limit)
+            })?;
+        }
+        writer.write_all(b"]")?;
+        writer.flush()?;
+        info!("Sent {} records as json.", sent);
+        Ok(())
     }
+
+    /// Send the records with a timestamp from `from_time` up to, but not including, `to_time` as
+    /// CSV, for opening in a spreadsheet.
+    pub(crate) fn send_records_csv(
+        &mut self,
+        writer: &mut dyn Write,
+        from_time: Option<u64>,
+        to_time: Option<u64>,
+    ) -> anyhow::Result<()> {
+        writer.write_all(Record::CSV_HEADER.as_bytes())?;
+        let mut sent = 0;
+        self.for_each_record(0, |record| {
+            let timestamp = record.reading.timestamp;
+            if timestamp >= from_time.unwrap_or(0) && timestamp < to_time.unwrap_or(u64::MAX) {
+                writer.write_all(record.to_csv().as_bytes())?;
+                sent += 1;
+            }
+            Ok(true)
+        })?;
+        writer.flush()?;
+        info!("Sent {} records as csv.", sent);
+        Ok(())
+    }
+
+    /// The collector has stored every record up to and including `seq`.
+    ///
+    /// Deletes the shards whose records are all acknowledged, except the shard that is appended
+    /// to and shards without a readable record. Returns the number of deleted shards.
+    pub(crate) fn acknowledge(&mut self, seq: u64) -> anyhow::Result<usize> {
+        let mut deleted = 0;
+        for shard_id in self.sorted_shard_ids() {
+            if shard_id == self.readings_shard_counter {
+                continue;
+            }
+            // shards without a readable record are kept, nothing in them was downloaded.
+            match self.last_seq(shard_id) {
+                Ok(Some(last_seq)) if last_seq <= seq => {
+                    fs::remove_file(self.shard_path(shard_id))?;
+                    self.readings_shards.remove(&shard_id);
+                    deleted += 1;
+                    info!("Deleted acknowledged shard {}.", shard_id);
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Kept unreadable shard {}: {:?}", shard_id, e),
+            }
+        }
+        if deleted > 0 {
+            self.energy.store()?;
+        }
+        Ok(deleted)
+    }
+}
+
+/// The CT id of the newest record in the shards under `root`, None if there is none.
+///
+/// The CTs are saved together in the order of their ids, so this is the number of CTs the records
+/// were stored by.
+pub(crate) fn newest_ct_id(root: &Path) -> anyhow::Result<Option<u16>> {
+    let mut shard_ids = Vec::new();
+    if let Ok(paths) = fs::read_dir(root.join("ct_readings")) {
+        for path in paths {
+            if let Ok(shard_id) = path?.file_name().to_string_lossy().parse::<i32>() {
+                shard_ids.push(shard_id);
+            }
+        }
+    }
+    shard_ids.sort();
+    for shard_id in shard_ids.into_iter().rev() {
+        let mut file = fs::File::open(root.join("ct_readings").join(shard_id.to_string()))?;
+        let header = ShardHeader::read_from(&mut file)?;
+        if let (_, Some(record)) = last_valid_record(&mut file, &header)? {
+            return Ok(Some(record.ct_id));
+        }
+    }
+    Ok(None)
 }
 
-impl CT {
+/// Walk back from the end of a shard until a record checks out.
+///
+/// `file` must be at the first record. Returns the number of records up to and including the
+/// valid one, and the record itself.
+fn last_valid_record(
+    file: &mut fs::File,
+    header: &ShardHeader,
+) -> anyhow::Result<(u64, Option<Record>)> {
+    let records_start = file.stream_position()?;
+    let record_size = header.record_size as u64;
+    let mut records = file.metadata()?.len().saturating_sub(records_start) / record_size;
+    let mut buf = vec![0_u8; record_size as usize];
+    while records > 0 {
+        file.seek(SeekFrom::Start(records_start + (records - 1) * record_size))?;
+        file.read_exact(&mut buf)?;
+        if let Ok(record) = Record::from_le_bytes(header, &buf) {
+            return Ok((records, Some(record)));
+        }
+        records -= 1;
+    }
+    Ok((0, None))
+}
+
+impl<S: SampleSource> CT<S> {
+    pub fn new(id: u16, source: S, calibration: Calibration) -> Self {
+        CT {
+            id,
+            source,
+            current_channel: CurrentChannel {
+                ical: calibration.ical,
+                offset_i: calibration.offset_i,
+            },
+            voltage_channel: VoltageChannel {
+                vcal: calibration.vcal,
+                phase_cal: calibration.phase_cal,
+                offset_v: calibration.offset_v,
+            },
+            #[cfg(feature = "harmonics")]
+            samples: SampleBuffer::new(),
+            last_measured: None,
+            aggregate: ReadingAggregate::default(),
+            events: EventDetector::new(),
+            tariff: None,
+            reading: CTReading::default(),
+        }
+    }
+
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Use a new calibration from the next measurement on.
+    pub(crate) fn set_calibration(&mut self, calibration: Calibration) {
+        self.current_channel = CurrentChannel {
+            ical: calibration.ical,
+            offset_i: calibration.offset_i,
+        };
+        self.voltage_channel = VoltageChannel {
+            vcal: calibration.vcal,
+            phase_cal: calibration.phase_cal,
+            offset_v: calibration.offset_v,
+        };
+    }
+
+    /// Detect voltage events with these thresholds from the next measurement on.
+    pub(crate) fn set_event_thresholds(&mut self, thresholds: EventThresholds) {
+        self.events.set_thresholds(thresholds);
+    }
+
+    /// Add the energy of the next measurements to this tariff.
+    pub(crate) fn set_tariff(&mut self, tariff: Option<u8>) {
+        self.tariff = tariff;
+    }
+
+    /// The voltage events that ended since the last call.
+    pub(crate) fn take_events(&mut self) -> Vec<VoltageEvent> {
+        self.events.take_events()
+    }
+
+    /// Forget the voltage events found so far, which are not to be trusted while the CT is
+    /// calibrated.
+    pub(crate) fn clear_events(&mut self) {
+        self.events.clear();
+    }
+
+    /// Measure for `crossing` voltage zero crossings, or until `timeout`, and add the measurement
+    /// to the current reading. Returns the measurement.
     pub(crate) fn calculate_energy(
         &mut self,
-        powered_adc1: &mut PoweredAdc<ADC1>,
         crossing: u32,
         timeout: std::time::Duration,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<CTReading> {
+        let sums = self.accumulate(crossing, timeout);
+        let calibration = self.calibration();
+
+        let v_rms = sums.v_rms(calibration.vcal);
+        let i_rms = sums.i_rms(calibration.ical);
+
+        // Calculate power values
+        let real_power = sums.real_power(&calibration);
+        let apparent_power = v_rms * i_rms;
+        let reactive_power = sums.reactive_sign()
+            * f32::sqrt(f32::max(
+                apparent_power * apparent_power - real_power * real_power,
+                0.0,
+            ));
+        let power_factor = if apparent_power > 0.0 {
+            f32::clamp(real_power / apparent_power, -1.0, 1.0)
+        } else {
+            0.0
+        };
+        // The power is taken to hold since the previous measurement, which includes the time the
+        // loop slept and the other CTs were measured. The first measurement only counts itself.
+        let measured_at = uptime();
+        let interval = match self.last_measured {
+            Some(last_measured) => measured_at.saturating_sub(last_measured),
+            None => sums.elapsed,
+        };
+        self.last_measured = Some(measured_at);
+        let kwh = (real_power / 1000.0) * interval.as_secs_f32() / SECONDS_PER_HOUR;
+        #[cfg(feature = "harmonics")]
+        let (v_thd, i_thd) = self.samples.thd();
+        #[cfg(not(feature = "harmonics"))]
+        let (v_thd, i_thd) = (0.0, 0.0);
+        let mut measurement = CTReading {
+            real_power,
+            apparent_power,
+            reactive_power,
+            power_factor,
+            kwh,
+            import_kwh: f32::max(kwh, 0.0),
+            export_kwh: f32::max(-kwh, 0.0),
+            frequency: sums.frequency(),
+            v_thd,
+            i_thd,
+            i_rms,
+            v_rms,
+            samples: 1,
+            timestamp: now().as_millis() as u64,
+            uptime: measured_at.as_millis() as u64,
+            ..Default::default()
+        };
+        if let Some(index) = self
+            .tariff
+            .and_then(|tariff| (tariff as usize).checked_sub(1))
+            .filter(|&index| index < MAX_TARIFFS)
+        {
+            measurement.tariff_import_kwh[index] = measurement.import_kwh;
+            measurement.tariff_export_kwh[index] = measurement.export_kwh;
+        }
+        self.aggregate.add(&measurement, interval);
+        self.reading = self.aggregate.reading();
+        self.reading
+            .set_time(measurement.timestamp, measurement.uptime);
+        Ok(measurement)
+    }
+
+    /// Sample the voltage and current for `crossing` voltage zero crossings, or until `timeout`,
+    /// and add up what the readings are calculated from.
+    pub(crate) fn accumulate(
+        &mut self,
+        crossing: u32,
+        timeout: std::time::Duration,
+    ) -> Accumulators {
         // Variables
         let mut cross_count = 0;
         let mut n_samples: u32 = 0;
@@ -343,46 +1124,54 @@ impl CT {
 
         let mut sample_v: u16 = 0;
         let mut sample_i: u16 = 0;
-        let mut offset_v: f32 = self.voltage_pin.offset_v as f32;
-        let mut offset_i: f32 = self.current_pin.offset_i as f32;
+        let mut offset_v: f32 = self.voltage_channel.offset_v;
+        let mut offset_i: f32 = self.current_channel.offset_i;
 
         let mut min_sample_i: u16 = MAX_MV_ATTEN_11;
         let mut min_sample_v: u16 = MAX_MV_ATTEN_11;
         let mut max_sample_i: u16 = 0;
         let mut max_sample_v: u16 = 0;
 
-        let (mut sum_v, mut sum_i, mut sum_p) = (0.0, 0.0, 0.0);
+        let (mut sum_v, mut sum_i, mut sum_p_last, mut sum_p_delta) = (0.0, 0.0, 0.0, 0.0);
+        let (mut sum_v_last, mut sum_v_cross, mut sum_v_delta) = (0.0, 0.0, 0.0);
         let mut check_v_cross = false;
         let mut last_v_cross;
+        let mut rising_crossings = 0;
+        let mut first_rising_crossing = Duration::ZERO;
+        let mut last_rising_crossing = Duration::ZERO;
+        let max_cycle_time = Duration::from_millis(MAX_CYCLE_TIME);
+        let v_ratio = Accumulators::ratio(self.voltage_channel.vcal);
+        let (mut cycle_sum_v, mut cycle_samples) = (0.0, 0);
+        // whether the cycle started at a rising crossing, the one before the first crossing didn't.
+        let mut whole_cycle = false;
 
-        let mut start = std::time::Instant::now(); // start.elapsed() makes sure it doesnt get stuck in the loop if there is an error.
+        #[cfg(feature = "harmonics")]
+        self.samples.clear();
+
+        let mut start = self.source.clock(); // checking the elapsed time makes sure it doesnt get stuck in the loop if there is an error.
         let mut start_v = 0;
 
         // 1) Waits for the waveform to be close to 'zero' (mid-scale adc) part in sin curve.
         loop {
-            start_v = powered_adc1
-                .read(&mut self.voltage_pin.pin)
-                .unwrap_or(start_v);
+            start_v = self.source.read_voltage().unwrap_or(start_v);
 
             if ((start_v as f32) < MAX_MV_ATTEN_11 as f32 * 0.55)
                 && ((start_v as f32) > MAX_MV_ATTEN_11 as f32 * 0.45)
             {
                 break;
             }
-            if start.elapsed() > timeout {
+            if self.source.clock() - start > timeout {
                 break;
             }
         }
         // 2) Main measurement loop
-        start = std::time::Instant::now();
-        while (cross_count < crossing) && (start.elapsed() < timeout) {
+        start = self.source.clock();
+        let mut cycle_start = start;
+        while (cross_count < crossing) && (self.source.clock() - start < timeout) {
             // A) Read in raw voltage and current samples
-            sample_i = powered_adc1
-                .read(&mut self.current_pin.pin)
-                .unwrap_or(sample_i);
-            sample_v = powered_adc1
-                .read(&mut self.voltage_pin.pin)
-                .unwrap_or(sample_v);
+            let (new_sample_i, new_sample_v) = self.source.read_pair();
+            sample_i = new_sample_i.unwrap_or(sample_i);
+            sample_v = new_sample_v.unwrap_or(sample_v);
 
             // B) Apply digital low pass filters to extract the 2.5 V or 1.65 V dc offset,
             //     then subtract this - signal is now centred on 0 counts.
@@ -407,27 +1196,59 @@ impl CT {
             sum_i += filtered_i * filtered_i;
 
             // E) Phase calibration
-            let phase_shift_v =
-                last_filtered_v + self.voltage_pin.phase_cal * (filtered_v - last_filtered_v);
+            //    the phase shifted voltage is last_filtered_v + phase_cal * (filtered_v - last_filtered_v),
+            //    its two parts are summed up separately so the power can be found for any phase_cal.
 
             // F) Instantaneous power calc
-            sum_p += phase_shift_v * filtered_i;
+            let delta_v = filtered_v - last_filtered_v;
+            sum_p_last += last_filtered_v * filtered_i;
+            sum_p_delta += delta_v * filtered_i;
+            sum_v_last += last_filtered_v * last_filtered_v;
+            sum_v_cross += last_filtered_v * delta_v;
+            sum_v_delta += delta_v * delta_v;
+            #[cfg(feature = "harmonics")]
+            self.samples.push(filtered_v, filtered_i);
 
             // G) Find the number of times the voltage has crossed the initial voltage
             //    - every 2 crosses we will have sampled 1 wavelength
             //    - so this method allows us to sample an integer number of half wavelengths which increases accuracy
             last_v_cross = check_v_cross;
-            if sample_v > start_v {
-                check_v_cross = true;
-            } else {
-                check_v_cross = false;
-            }
+            check_v_cross = sample_v > start_v;
             if n_samples == 0 {
                 last_v_cross = check_v_cross;
             }
 
             if last_v_cross != check_v_cross {
                 cross_count += 1;
+                // H) Time whole periods between the rising crossings for the frequency
+                if check_v_cross {
+                    last_rising_crossing = self.source.clock();
+                    if rising_crossings == 0 {
+                        first_rising_crossing = last_rising_crossing;
+                    }
+                    rising_crossings += 1;
+                    #[cfg(feature = "harmonics")]
+                    self.samples.start_period();
+                }
+            }
+
+            // I) RMS of every cycle for the voltage events, a cycle without a rising crossing
+            //    means the voltage is gone.
+            cycle_sum_v += filtered_v * filtered_v;
+            cycle_samples += 1;
+            let rising = last_v_cross != check_v_cross && check_v_cross;
+            let clock = self.source.clock();
+            let too_long = clock - cycle_start > max_cycle_time;
+            if (rising && whole_cycle) || too_long {
+                let cycle_v_rms = v_ratio * f32::sqrt(cycle_sum_v / cycle_samples as f32);
+                self.events
+                    .add_cycle(self.id, cycle_v_rms, cycle_start, clock);
+            }
+            if rising || too_long {
+                cycle_sum_v = 0.0;
+                cycle_samples = 0;
+                cycle_start = clock;
+                whole_cycle = rising;
             }
 
             n_samples += 1;
@@ -439,156 +1260,592 @@ impl CT {
         offset_i = (offset_i + ((max_sample_i + min_sample_i) as f32 / 2.0)) / 2.0;
         offset_v = (offset_v + ((max_sample_v + min_sample_v) as f32 / 2.0)) / 2.0;
 
-        self.current_pin.offset_i = offset_i;
-        self.voltage_pin.offset_v = offset_v;
+        self.current_channel.offset_i = offset_i;
+        self.voltage_channel.offset_v = offset_v;
+        let elapsed = self.source.clock() - start;
 
-        let v_ratio = self.voltage_pin.vcal * (SUPPLY_VOLTAGE / (MAX_MV_ATTEN_11 as f32));
-        let v_rms = v_ratio * f32::sqrt(sum_v / n_samples as f32);
+        Accumulators {
+            n_samples,
+            sum_v,
+            sum_i,
+            sum_p_last,
+            sum_p_delta,
+            sum_v_last,
+            sum_v_cross,
+            sum_v_delta,
+            periods: u32::saturating_sub(rising_crossings, 1),
+            periods_time: last_rising_crossing - first_rising_crossing,
+            elapsed,
+        }
+    }
 
-        let i_ratio = self.current_pin.ical * (SUPPLY_VOLTAGE / (MAX_MV_ATTEN_11 as f32));
-        let i_rms = i_ratio * f32::sqrt(sum_i / n_samples as f32);
+    /// The calibration the CT measures with, with the dc offsets it has settled on.
+    pub(crate) fn calibration(&self) -> Calibration {
+        Calibration {
+            ical: self.current_channel.ical,
+            vcal: self.voltage_channel.vcal,
+            phase_cal: self.voltage_channel.phase_cal,
+            offset_i: self.current_channel.offset_i,
+            offset_v: self.voltage_channel.offset_v,
+        }
+    }
 
-        // Calculate power values
-        let real_power = f32::abs(v_ratio * i_ratio * (sum_p / n_samples as f32));
-        let apparent_power = v_rms * i_rms;
-        let kwh =
-            (real_power / 1000.0) * start.elapsed().as_secs_f32() / SAVE_PERIOD_TIMEOUT as f32;
-        let new_reading = CTReading {
-            real_power,
-            apparent_power,
-            kwh,
-            i_rms,
-            v_rms,
-            timestamp: now().as_millis() as u64,
+    /// Start the readings of the next save period.
+    pub(crate) fn reset(&mut self) {
+        self.aggregate = ReadingAggregate::default();
+        self.reading = CTReading::default();
+    }
+}
+
+#[cfg(feature = "esp")]
+impl CT<Box<dyn SampleSource>> {
+    /// Create the CTs of the channel map, all of them sampled through ADC1. CT `i + 1` gets
+    /// `calibrations[i]`.
+    pub fn init(
+        pins: Pins,
+        powered_adc1: PoweredAdc<ADC1>,
+        map: &ChannelMap,
+        calibrations: &[Calibration],
+    ) -> anyhow::Result<Vec<CT<Box<dyn SampleSource>>>> {
+        map.validate()?;
+        let adc = Rc::new(RefCell::new(powered_adc1));
+        // set up the pins the map uses, the pins of every GPIO have their own type.
+        let used = |gpio| {
+            map.channels
+                .iter()
+                .any(|channel| channel.current_gpio == gpio || channel.voltage_gpio == gpio)
         };
-        self.reading += new_reading;
-        Ok(())
+        let mut analog_pins: Vec<(u8, Box<dyn AdcChannel>)> = Vec::new();
+        if used(32) {
+            analog_pins.push((32, Box::new(pins.gpio32.into_analog_atten_11db()?)));
+        }
+        if used(33) {
+            analog_pins.push((33, Box::new(pins.gpio33.into_analog_atten_11db()?)));
+        }
+        if used(34) {
+            analog_pins.push((34, Box::new(pins.gpio34.into_analog_atten_11db()?)));
+        }
+        if used(35) {
+            analog_pins.push((35, Box::new(pins.gpio35.into_analog_atten_11db()?)));
+        }
+        if used(36) {
+            analog_pins.push((36, Box::new(pins.gpio36.into_analog_atten_11db()?)));
+        }
+        if used(39) {
+            analog_pins.push((39, Box::new(pins.gpio39.into_analog_atten_11db()?)));
+        }
+        let mut take_pin = |gpio| -> anyhow::Result<Box<dyn AdcChannel>> {
+            let index = analog_pins
+                .iter()
+                .position(|(pin_gpio, _)| *pin_gpio == gpio)
+                .ok_or_else(|| anyhow::anyhow!("GPIO {} is not an ADC1 pin.", gpio))?;
+            Ok(analog_pins.remove(index).1)
+        };
+        // CTs on the same phase share the voltage pin.
+        let mut voltage_pins: Vec<(u8, Rc<RefCell<Box<dyn AdcChannel>>>)> = Vec::new();
+        let mut cts = Vec::with_capacity(map.len());
+        for (i, channel) in map.channels.iter().enumerate() {
+            let current_pin = take_pin(channel.current_gpio)?;
+            let voltage_pin = match voltage_pins
+                .iter()
+                .find(|(gpio, _)| *gpio == channel.voltage_gpio)
+            {
+                Some((_, pin)) => pin.clone(),
+                None => {
+                    let pin = Rc::new(RefCell::new(take_pin(channel.voltage_gpio)?));
+                    voltage_pins.push((channel.voltage_gpio, pin.clone()));
+                    pin
+                }
+            };
+            let calibration = calibrations
+                .get(i)
+                .copied()
+                .unwrap_or_else(|| map.default_calibration());
+            cts.push(CT::new(
+                i as u16 + 1,
+                Box::new(AdcSampleSource::new(adc.clone(), current_pin, voltage_pin)),
+                calibration,
+            ));
+        }
+        Ok(cts)
     }
+}
 
-    pub(crate) fn init(pins: Pins) -> anyhow::Result<[CT; AC_PHASE]> {
-        #[cfg(feature = "single-phase")]
-        {
-            Ok([CT {
-                id: 1,
-                current_pin: CurrentPin {
-                    pin: pins.gpio35.into_analog_atten_11db()?,
-                    ical: 102.0,
-                    offset_i: 1066.0,
-                },
-                voltage_pin: VoltagePin {
-                    pin: pins.gpio34.into_analog_atten_11db()?,
-                    vcal: 232.5,
-                    phase_cal: 1.7,
-                    offset_v: 1288.0,
-                },
-                reading: CTReading {
-                    i_rms: 0.0,
-                    v_rms: 0.0,
-                    timestamp: 0,
-                    real_power: 0.0,
-                    apparent_power: 0.0,
-                    kwh: 0.0,
-                },
-            }])
-        }
-        #[cfg(feature = "three-phase")]
+impl Accumulators {
+    fn ratio(cal: f32) -> f32 {
+        cal * (SUPPLY_VOLTAGE / (MAX_MV_ATTEN_11 as f32))
+    }
+
+    pub(crate) fn v_rms(&self, vcal: f32) -> f32 {
+        Accumulators::ratio(vcal) * f32::sqrt(self.sum_v / self.n_samples as f32)
+    }
+
+    pub(crate) fn i_rms(&self, ical: f32) -> f32 {
+        Accumulators::ratio(ical) * f32::sqrt(self.sum_i / self.n_samples as f32)
+    }
+
+    /// The real power, negative if the current flows against the voltage.
+    pub(crate) fn real_power(&self, calibration: &Calibration) -> f32 {
+        let sum_p = self.sum_p_last + calibration.phase_cal * self.sum_p_delta;
+        Accumulators::ratio(calibration.vcal)
+            * Accumulators::ratio(calibration.ical)
+            * (sum_p / self.n_samples as f32)
+    }
+
+    /// The mains frequency in Hz, 0 if not a single whole period was timed.
+    pub(crate) fn frequency(&self) -> f32 {
+        if self.periods == 0 || self.periods_time == Duration::ZERO {
+            return 0.0;
+        }
+        self.periods as f32 / self.periods_time.as_secs_f32()
+    }
+
+    /// 1 if the current lags the voltage, as with an inductive load, and -1 if it leads.
+    ///
+    /// The products of the current with the change of the voltage add up to about
+    /// `-sin(lag)` times a positive factor.
+    pub(crate) fn reactive_sign(&self) -> f32 {
+        if self.sum_p_delta > 0.0 {
+            -1.0
+        } else {
+            1.0
+        }
+    }
+
+    /// The `phase_cal` at which the phase shifted voltage and the current have the given power
+    /// factor, the one closest to `near` if there are two.
+    ///
+    /// The power factor is highest when they are in phase, which is the answer for a resistive
+    /// load, and falls off to both sides.
+    pub(crate) fn phase_cal_for(&self, power_factor: f32, near: f32) -> Option<f32> {
+        // the sign of the power doesn't matter, only how far the phases are apart.
+        let sign = if self.real_power(&Calibration {
+            phase_cal: near,
+            ..DEFAULT_CALIBRATION
+        }) < 0.0
         {
-            Ok([
-                CT {
-                    id: 1,
-                    current_pin: CurrentPin {
-                        pin: pins.gpio32.into_analog_atten_11db()?,
-                        ical: 30.0,
-                        offset_i: 1066.0,
-                    },
-                    voltage_pin: VoltagePin {
-                        pin: pins.gpio39.into_analog_atten_11db()?,
-                        vcal: 219.25,
-                        phase_cal: 1.7,
-                        offset_v: 1288.0,
-                    },
-                    reading: CTReading {
-                        i_rms: 0.0,
-                        v_rms: 0.0,
-                        timestamp: 0,
-                        real_power: 0.0,
-                        apparent_power: 0.0,
-                        kwh: 0.0,
-                    },
-                },
-                CT {
-                    id: 2,
-                    current_pin: CurrentPin {
-                        pin: pins.gpio35.into_analog_atten_11db()?,
-                        ical: 30.0,
-                        offset_i: 1066.0,
-                    },
-                    voltage_pin: VoltagePin {
-                        pin: pins.gpio36.into_analog_atten_11db()?,
-                        vcal: 219.25,
-                        phase_cal: 1.7,
-                        offset_v: 1288.0,
-                    },
-                    reading: CTReading {
-                        i_rms: 0.0,
-                        v_rms: 0.0,
-                        timestamp: 0,
-                        real_power: 0.0,
-                        apparent_power: 0.0,
-                        kwh: 0.0,
-                    },
-                },
-                CT {
-                    id: 3,
-                    current_pin: CurrentPin {
-                        pin: pins.gpio34.into_analog_atten_11db()?,
-                        ical: 30.0,
-                        offset_i: 1066.0,
-                    },
-                    voltage_pin: VoltagePin {
-                        pin: pins.gpio33.into_analog_atten_11db()?,
-                        vcal: 219.25,
-                        phase_cal: 1.7,
-                        offset_v: 1288.0,
-                    },
-                    reading: CTReading {
-                        i_rms: 0.0,
-                        v_rms: 0.0,
-                        timestamp: 0,
-                        real_power: 0.0,
-                        apparent_power: 0.0,
-                        kwh: 0.0,
-                    },
-                },
-            ])
+            -1.0
+        } else {
+            1.0
+        };
+        let (a, b) = (
+            sign * self.sum_p_last as f64,
+            sign * self.sum_p_delta as f64,
+        );
+        let (c, d, e) = (
+            self.sum_v_last as f64,
+            self.sum_v_cross as f64,
+            self.sum_v_delta as f64,
+        );
+        let sum_i = self.sum_i as f64;
+        let factor_at = |p: f64| (a + p * b) / f64::sqrt((c + 2.0 * p * d + p * p * e) * sum_i);
+
+        // where the derivative of `factor_at` is 0.
+        let in_phase = (a * d - b * c) / (b * d - a * e);
+        let target = power_factor as f64;
+        let phase_cal = if target >= factor_at(in_phase) {
+            in_phase
+        } else {
+            // solve (a + p * b)^2 = target^2 * sum_i * (c + 2 * p * d + p^2 * e) for p.
+            let t = target * target * sum_i;
+            let (qa, qb, qc) = (b * b - t * e, 2.0 * (a * b - t * d), a * a - t * c);
+            let root = f64::sqrt(qb * qb - 4.0 * qa * qc);
+            let (p, q) = ((-qb + root) / (2.0 * qa), (-qb - root) / (2.0 * qa));
+            // only the roots where the power is positive, the others are of -target.
+            match (a + p * b > 0.0, a + q * b > 0.0) {
+                (true, true) if (q - near as f64).abs() < (p - near as f64).abs() => q,
+                (true, _) => p,
+                (false, true) => q,
+                (false, false) => return None,
+            }
+        };
+        if phase_cal.is_finite() {
+            Some(phase_cal as f32)
+        } else {
+            None
         }
     }
+}
 
-    pub(crate) fn reset(&mut self) {
-        self.reading.reset();
+impl ops::AddAssign<Accumulators> for Accumulators {
+    fn add_assign(&mut self, rhs: Accumulators) {
+        self.n_samples += rhs.n_samples;
+        self.sum_v += rhs.sum_v;
+        self.sum_i += rhs.sum_i;
+        self.sum_p_last += rhs.sum_p_last;
+        self.sum_p_delta += rhs.sum_p_delta;
+        self.sum_v_last += rhs.sum_v_last;
+        self.sum_v_cross += rhs.sum_v_cross;
+        self.sum_v_delta += rhs.sum_v_delta;
+        self.periods += rhs.periods;
+        self.periods_time += rhs.periods_time;
+        self.elapsed += rhs.elapsed;
     }
 }
 
-impl ops::AddAssign<CTReading> for CTReading {
-    fn add_assign(&mut self, rhs: CTReading) {
-        self.i_rms = (self.i_rms + rhs.i_rms) / 2.0;
-        self.v_rms = (self.v_rms + rhs.v_rms) / 2.0;
-        self.real_power = (self.real_power + rhs.real_power) / 2.0;
-        self.apparent_power = (self.apparent_power + rhs.apparent_power) / 2.0;
-        self.kwh = self.kwh + rhs.kwh;
+/// The smallest, the largest and the last value of a quantity over a save period.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) struct MinMaxLast {
+    pub(crate) min: f32,
+    pub(crate) max: f32,
+    pub(crate) last: f32,
+}
+
+impl MinMaxLast {
+    /// All three are `value`, for records that only have the mean.
+    pub(crate) fn all(value: f32) -> Self {
+        MinMaxLast {
+            min: value,
+            max: value,
+            last: value,
+        }
+    }
+}
+
+/// Time-weighted statistics of a quantity over a save period.
+#[derive(Debug, Default, Clone, Copy)]
+struct Aggregate {
+    /// The sum of the values times the seconds they held for, and the seconds.
+    weighted_sum: f64,
+    seconds: f64,
+    samples: u32,
+    stats: MinMaxLast,
+}
+
+impl Aggregate {
+    fn add(&mut self, value: f32, seconds: f64) {
+        if self.samples == 0 {
+            self.stats = MinMaxLast::all(value);
+        } else {
+            self.stats.min = f32::min(self.stats.min, value);
+            self.stats.max = f32::max(self.stats.max, value);
+            self.stats.last = value;
+        }
+        self.weighted_sum += value as f64 * seconds;
+        self.seconds += seconds;
+        self.samples += 1;
+    }
+
+    /// The time-weighted mean. Measurements that held for no time have no weight, so if all of
+    /// them did it is the last value, which is 0 if nothing was added.
+    fn mean(&self) -> f32 {
+        if self.seconds > 0.0 {
+            (self.weighted_sum / self.seconds) as f32
+        } else {
+            self.stats.last
+        }
+    }
+}
+
+/// Adds up the measurements of a CT over a save period.
+#[derive(Debug, Default, Clone)]
+struct ReadingAggregate {
+    real_power: Aggregate,
+    apparent_power: Aggregate,
+    reactive_power: Aggregate,
+    i_rms: Aggregate,
+    v_rms: Aggregate,
+    frequency: Aggregate,
+    v_thd: Aggregate,
+    i_thd: Aggregate,
+    kwh: f32,
+    import_kwh: f32,
+    export_kwh: f32,
+    tariff_import_kwh: [f32; MAX_TARIFFS],
+    tariff_export_kwh: [f32; MAX_TARIFFS],
+}
+
+impl ReadingAggregate {
+    /// Add a measurement that holds for `interval`, which is its weight in the means.
+    fn add(&mut self, measurement: &CTReading, interval: Duration) {
+        let seconds = interval.as_secs_f64();
+        self.real_power.add(measurement.real_power, seconds);
+        self.apparent_power.add(measurement.apparent_power, seconds);
+        self.reactive_power.add(measurement.reactive_power, seconds);
+        self.i_rms.add(measurement.i_rms, seconds);
+        self.v_rms.add(measurement.v_rms, seconds);
+        // 0 means the frequency could not be measured.
+        if measurement.frequency > 0.0 {
+            self.frequency.add(measurement.frequency, seconds);
+        }
+        self.v_thd.add(measurement.v_thd, seconds);
+        self.i_thd.add(measurement.i_thd, seconds);
+        self.kwh += measurement.kwh;
+        self.import_kwh += measurement.import_kwh;
+        self.export_kwh += measurement.export_kwh;
+        for i in 0..MAX_TARIFFS {
+            self.tariff_import_kwh[i] += measurement.tariff_import_kwh[i];
+            self.tariff_export_kwh[i] += measurement.tariff_export_kwh[i];
+        }
+    }
+
+    /// The reading of everything added so far, without a time.
+    fn reading(&self) -> CTReading {
+        let real_power = self.real_power.mean();
+        let apparent_power = self.apparent_power.mean();
+        CTReading {
+            real_power,
+            apparent_power,
+            reactive_power: self.reactive_power.mean(),
+            power_factor: if apparent_power > 0.0 {
+                f32::clamp(real_power / apparent_power, -1.0, 1.0)
+            } else {
+                0.0
+            },
+            i_rms: self.i_rms.mean(),
+            v_rms: self.v_rms.mean(),
+            kwh: self.kwh,
+            import_kwh: self.import_kwh,
+            export_kwh: self.export_kwh,
+            frequency: self.frequency.mean(),
+            v_thd: self.v_thd.mean(),
+            i_thd: self.i_thd.mean(),
+            real_power_stats: self.real_power.stats,
+            i_rms_stats: self.i_rms.stats,
+            v_rms_stats: self.v_rms.stats,
+            samples: self.real_power.samples,
+            timestamp: 0,
+            uptime: 0,
+            demand: 0.0,
+            peak_demand: 0.0,
+            peak_demand_time: 0,
+            tariff_import_kwh: self.tariff_import_kwh,
+            tariff_export_kwh: self.tariff_export_kwh,
+        }
     }
 }
 
 impl CTReading {
-    fn reset(&mut self) {
-        self.i_rms = 0.0;
-        self.v_rms = 0.0;
-        self.real_power = 0.0;
-        self.apparent_power = 0.0;
-        self.kwh = 0.0;
-        self.timestamp = 0;
-    }
-    pub(crate) fn set_time(&mut self, time: u64) {
+    pub(crate) fn set_time(&mut self, time: u64, uptime: u64) {
         self.timestamp = time;
+        self.uptime = uptime;
+    }
+
+    pub(crate) fn set_demand(&mut self, demand: f32, peak: Peak) {
+        self.demand = demand;
+        self.peak_demand = peak.kw;
+        self.peak_demand_time = peak.time;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::{FRAC_PI_3, PI, SQRT_2};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::keystore::{self, FileKeyStore};
+    #[cfg(feature = "harmonics")]
+    use crate::sample::Harmonic;
+    use crate::sample::SyntheticSource;
+    use crate::{CROSSINGS, LEGACY_CT_READING_SIZE, MEASUREMENT_TIMEOUT};
+
+    // About 230V and 5A with the default calibration.
+    const V_AMPLITUDE: f32 = 1038.0;
+    const I_AMPLITUDE: f32 = 51.0;
+
+    /// A CT on a synthetic waveform whose dc offset filters have settled.
+    fn settled_ct(phase_shift: f32) -> CT<SyntheticSource> {
+        settled_ct_with(phase_shift, DEFAULT_CALIBRATION)
+    }
+
+    fn settled_ct_with(phase_shift: f32, calibration: Calibration) -> CT<SyntheticSource> {
+        let source = SyntheticSource::new(50.0, V_AMPLITUDE, I_AMPLITUDE, phase_shift);
+        let mut ct = CT::new(1, source, calibration);
+        measure(&mut ct);
+        ct.reset();
+        ct
+    }
+
+    fn measure(ct: &mut CT<SyntheticSource>) -> CTReading {
+        ct.calculate_energy(CROSSINGS, Duration::from_secs(MEASUREMENT_TIMEOUT))
+            .unwrap()
+    }
+
+    fn expected_v_rms() -> f32 {
+        Accumulators::ratio(DEFAULT_CALIBRATION.vcal) * V_AMPLITUDE / SQRT_2
+    }
+
+    fn expected_i_rms() -> f32 {
+        Accumulators::ratio(DEFAULT_CALIBRATION.ical) * I_AMPLITUDE / SQRT_2
+    }
+
+    fn assert_near(value: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (value - expected).abs() <= tolerance * expected.abs(),
+            "{} is not within {}% of {}",
+            value,
+            tolerance * 100.0,
+            expected
+        );
+    }
+
+    #[test]
+    fn resistive_load() {
+        let mut ct = settled_ct(0.0);
+        let measurement = measure(&mut ct);
+        assert_near(measurement.v_rms, expected_v_rms(), 0.01);
+        assert_near(measurement.i_rms, expected_i_rms(), 0.01);
+        assert_near(
+            measurement.real_power,
+            expected_v_rms() * expected_i_rms(),
+            0.02,
+        );
+        assert_near(measurement.power_factor, 1.0, 0.01);
+        assert_near(measurement.frequency, 50.0, 0.01);
+    }
+
+    #[test]
+    fn lagging_load() {
+        // the synthetic source reads the voltage half a pair after the current, unlike the ADC.
+        let calibration = Calibration {
+            phase_cal: 0.5,
+            ..DEFAULT_CALIBRATION
+        };
+        let mut ct = settled_ct_with(FRAC_PI_3, calibration);
+        let measurement = measure(&mut ct);
+        let apparent_power = expected_v_rms() * expected_i_rms();
+        assert_near(measurement.apparent_power, apparent_power, 0.02);
+        assert_near(measurement.real_power, apparent_power * 0.5, 0.02);
+        assert_near(measurement.power_factor, 0.5, 0.02);
+        assert!(measurement.reactive_power > 0.0);
+    }
+
+    #[test]
+    fn exported_power() {
+        let mut ct = settled_ct(PI);
+        let measurement = measure(&mut ct);
+        assert_near(
+            measurement.real_power,
+            -expected_v_rms() * expected_i_rms(),
+            0.02,
+        );
+        assert_near(measurement.power_factor, -1.0, 0.01);
+        assert_eq!(measurement.import_kwh, 0.0);
+        assert!(measurement.export_kwh > 0.0);
+    }
+
+    #[test]
+    fn energy_over_an_interval() {
+        let mut ct = settled_ct(0.0);
+        // the power holds since the previous measurement, which ended before the sleep.
+        let last_measured = uptime();
+        ct.last_measured = Some(last_measured);
+        std::thread::sleep(Duration::from_millis(500));
+        let shortest = (uptime() - last_measured).as_secs_f32();
+        let measurement = measure(&mut ct);
+        let longest = (uptime() - last_measured).as_secs_f32();
+        let kw = measurement.real_power / 1000.0;
+        assert!(measurement.kwh >= kw * shortest / SECONDS_PER_HOUR * 0.999);
+        assert!(measurement.kwh <= kw * longest / SECONDS_PER_HOUR * 1.001);
+        assert_eq!(measurement.import_kwh, measurement.kwh);
+        assert_eq!(ct.reading.kwh, measurement.kwh);
+    }
+
+    #[cfg(feature = "harmonics")]
+    #[test]
+    fn pure_sine_has_no_distortion() {
+        let mut ct = settled_ct(0.0);
+        let measurement = measure(&mut ct);
+        assert!(measurement.v_thd < 0.5, "v_thd is {}", measurement.v_thd);
+        assert!(measurement.i_thd < 0.5, "i_thd is {}", measurement.i_thd);
+    }
+
+    #[cfg(feature = "harmonics")]
+    #[test]
+    fn harmonic_distortion() {
+        let harmonic = |order, amplitude| Harmonic { order, amplitude };
+        let source = SyntheticSource::new(50.0, V_AMPLITUDE, I_AMPLITUDE, 0.0).with_harmonics(
+            vec![harmonic(3, 0.2), harmonic(5, 0.1)],
+            vec![harmonic(3, 0.4)],
+        );
+        let mut ct = CT::new(1, source, DEFAULT_CALIBRATION);
+        measure(&mut ct);
+        let measurement = measure(&mut ct);
+        // the rms of the harmonics relative to the fundamental.
+        assert_near(
+            measurement.v_thd,
+            100.0 * f32::sqrt(0.2 * 0.2 + 0.1 * 0.1),
+            0.02,
+        );
+        assert_near(measurement.i_thd, 40.0, 0.02);
+    }
+
+    /// Storage in a directory of its own under the temp dir, which is emptied first.
+    fn storage(name: &str) -> CTStorage {
+        let root = std::env::temp_dir().join(format!("sem-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("ct_readings")).unwrap();
+        let keystore = keystore::shared(FileKeyStore::new(root.join("nvs")).unwrap());
+        CTStorage::new(root, [0; 6], keystore, 1)
+    }
+
+    fn shard(storage: &CTStorage, shard_id: i32, header: Option<ShardHeader>, records: &[&[u8]]) {
+        let mut data = Vec::new();
+        if let Some(header) = header {
+            data.extend_from_slice(&header.to_le_bytes().unwrap());
+        }
+        for record in records {
+            data.extend_from_slice(record);
+        }
+        fs::write(storage.shard_path(shard_id), data).unwrap();
+    }
+
+    fn record_bytes(seq: u64) -> [u8; CT_READING_SIZE] {
+        Record {
+            ct_id: 1,
+            seq,
+            ..Default::default()
+        }
+        .to_le_bytes()
+        .unwrap()
+    }
+
+    fn shard_len(storage: &CTStorage, shard_id: i32) -> u64 {
+        fs::metadata(storage.shard_path(shard_id)).unwrap().len()
+    }
+
+    #[test]
+    fn torn_records_are_truncated() {
+        let storage = storage("torn");
+        let header = ShardHeader::current([0; 6]);
+        let (first, second) = (record_bytes(1), record_bytes(2));
+        let mut corrupted = record_bytes(3);
+        corrupted[10] ^= 0xff;
+        let whole_len = (SHARD_HEADER_SIZE + 2 * CT_READING_SIZE) as u64;
+
+        // complete shards are left alone.
+        shard(&storage, 1, Some(header), &[&first, &second]);
+        assert_eq!(storage.truncate_torn_records(1).unwrap(), 0);
+        assert_eq!(shard_len(&storage, 1), whole_len);
+
+        // a half written record is cut off.
+        shard(
+            &storage,
+            2,
+            Some(header),
+            &[&first, &second, &record_bytes(3)[..100]],
+        );
+        assert_eq!(storage.truncate_torn_records(2).unwrap(), 1);
+        assert_eq!(shard_len(&storage, 2), whole_len);
+        assert_eq!(storage.last_seq(2).unwrap(), Some(2));
+
+        // so is a whole record with a bad checksum, back to the last one that checks out.
+        shard(
+            &storage,
+            3,
+            Some(header),
+            &[&first, &second, &corrupted, &corrupted[..50]],
+        );
+        assert_eq!(storage.truncate_torn_records(3).unwrap(), 2);
+        assert_eq!(shard_len(&storage, 3), whole_len);
+
+        // legacy records have no checksum, only the partial one is cut off.
+        let legacy = Record::default().to_legacy_le_bytes().unwrap();
+        shard(&storage, 4, None, &[&legacy, &legacy, &legacy[..7]]);
+        assert_eq!(storage.truncate_torn_records(4).unwrap(), 1);
+        assert_eq!(shard_len(&storage, 4), 2 * LEGACY_CT_READING_SIZE as u64);
+
+        fs::remove_dir_all(&storage.root).unwrap();
     }
 }
@@ -0,0 +1,459 @@
+//! Demand registers of the CTs.
+//!
+//! The demand is the mean imported power over a window, 15 minutes by default, like the maximum
+//! demand that commercial tariffs bill on. Block windows are aligned to the clock and follow each
+//! other, sliding windows move in steps of `DEMAND_SUBINTERVAL` seconds. A window only counts if
+//! the clock was known for all of it, so the first one after boot or a time change doesn't.
+//!
+//! Every CT keeps the peak demand of the day and of the month, in the local time of the tariff
+//! schedule, and of the previous ones. A peak is timed by the end of its window, and a period only
+//! rolls over when a window ends in the next one. The peaks are written to the keystore whenever
+//! they change, so they survive reboots and `/reset`.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+use crate::ct::CTReading;
+use crate::keystore::SharedKeyStore;
+use crate::utils::{add_f32_to_buf, add_u64_to_buf, civil_from_days, json_number};
+use crate::{DEMAND_PEAKS_SIZE, DEMAND_SUBINTERVAL, MIN_KNOWN_TIME};
+
+const DAY: i64 = 24 * 60 * 60 * 1000; // in ms
+
+/// A peak demand, in kW, and the end of its window, in ms since the epoch. 0 if there is none.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Peak {
+    pub kw: f32,
+    pub time: u64,
+}
+
+/// Part of a window that is still being added up.
+#[derive(Debug, Clone, Copy)]
+struct Subinterval {
+    /// In ms since the epoch.
+    start: u64,
+    kwh: f64,
+    /// Whether it started with its first measurement.
+    whole: bool,
+}
+
+#[derive(Debug, Default, Clone)]
+struct DemandRegister {
+    current: Option<Subinterval>,
+    /// The imported kWh of the last whole subintervals, oldest first.
+    whole: VecDeque<f64>,
+    /// The demand of the last whole window, in kW.
+    demand: Option<f32>,
+    day: Peak,
+    previous_day: Peak,
+    month: Peak,
+    previous_month: Peak,
+}
+
+pub struct DemandRegisters {
+    keystore: SharedKeyStore,
+    window: Duration,
+    sliding: bool,
+    /// Local time minus UTC, in minutes, the days and months of the peaks are in local time.
+    utc_offset: i16,
+    registers: Vec<DemandRegister>,
+}
+
+pub type SharedDemand = Arc<Mutex<DemandRegisters>>;
+
+impl DemandRegister {
+    /// Adds the energy imported up to `time`, and returns whether a peak changed.
+    fn add(
+        &mut self,
+        import_kwh: f64,
+        time: u64,
+        window: Duration,
+        sliding: bool,
+        utc_offset: i16,
+    ) -> bool {
+        let window = window.as_millis() as u64;
+        let subinterval = if sliding {
+            DEMAND_SUBINTERVAL * 1000
+        } else {
+            window
+        };
+        let start = time - time % subinterval;
+        let mut changed = false;
+        let whole = match self.current {
+            Some(current) if current.start == start => true,
+            Some(current) => {
+                let contiguous = current.start + subinterval == start;
+                if current.whole && contiguous {
+                    self.whole.push_back(current.kwh);
+                    if self.whole.len() as u64 > window / subinterval {
+                        self.whole.pop_front();
+                    }
+                } else {
+                    self.whole.clear();
+                }
+                if self.whole.len() as u64 == window / subinterval {
+                    let kw = self.whole.iter().sum::<f64>() * (3_600_000.0 / window as f64);
+                    self.demand = Some(kw as f32);
+                    changed = self.raise_peaks(kw as f32, current.start + subinterval, utc_offset);
+                }
+                contiguous
+            }
+            None => false,
+        };
+        match &mut self.current {
+            Some(current) if current.start == start => current.kwh += import_kwh,
+            _ => {
+                self.current = Some(Subinterval {
+                    start,
+                    kwh: import_kwh,
+                    whole,
+                })
+            }
+        }
+        changed
+    }
+
+    fn raise_peaks(&mut self, kw: f32, end: u64, utc_offset: i16) -> bool {
+        // the local day, a window that ends at midnight is in the day before.
+        let local_day = |time: u64| (time as i64 + utc_offset as i64 * 60_000 - 1).div_euclid(DAY);
+        let day = |time: u64| local_day(time) as u64;
+        let month = |time: u64| {
+            let (year, month, _) = civil_from_days(local_day(time));
+            year as u64 * 12 + month as u64
+        };
+        let day_changed = raise(&mut self.day, &mut self.previous_day, kw, end, day);
+        let month_changed = raise(&mut self.month, &mut self.previous_month, kw, end, month);
+        if day_changed || month_changed {
+            info!("New peak demand of {} kW at {}.", kw, end);
+        }
+        day_changed || month_changed
+    }
+
+    /// Forgets the windows, e.g. when their length changed.
+    fn clear(&mut self) {
+        self.current = None;
+        self.whole.clear();
+        self.demand = None;
+    }
+
+    fn peaks_to_le_bytes(&self) -> anyhow::Result<[u8; DEMAND_PEAKS_SIZE]> {
+        let mut buf = [0_u8; DEMAND_PEAKS_SIZE];
+        let mut pos = 0;
+        for peak in [self.day, self.previous_day, self.month, self.previous_month] {
+            pos += add_f32_to_buf(&peak.kw, &mut buf, &pos)?;
+            pos += add_u64_to_buf(&peak.time, &mut buf, &pos)?;
+        }
+        Ok(buf)
+    }
+
+    fn peaks_from_le_bytes(&mut self, buf: &[u8; DEMAND_PEAKS_SIZE]) {
+        let peak = |pos: usize| {
+            let (mut kw, mut time) = ([0_u8; 4], [0_u8; 8]);
+            kw.copy_from_slice(&buf[pos..pos + 4]);
+            time.copy_from_slice(&buf[pos + 4..pos + 12]);
+            Peak {
+                kw: f32::from_le_bytes(kw),
+                time: u64::from_le_bytes(time),
+            }
+        };
+        self.day = peak(0);
+        self.previous_day = peak(12);
+        self.month = peak(24);
+        self.previous_month = peak(36);
+    }
+
+    fn to_json(&self, ct_id: u16) -> String {
+        format!(
+            concat!(
+                r#"{{"ct":{},"demand":{},"day_peak":{},"previous_day_peak":{},"#,
+                r#""month_peak":{},"previous_month_peak":{}}}"#
+            ),
+            ct_id,
+            self.demand
+                .map(json_number)
+                .unwrap_or_else(|| "null".to_string()),
+            peak_to_json(self.day),
+            peak_to_json(self.previous_day),
+            peak_to_json(self.month),
+            peak_to_json(self.previous_month)
+        )
+    }
+}
+
+impl DemandRegisters {
+    /// Loads the peaks of `ct_count` CTs from the keystore, the windows start empty.
+    pub fn load(keystore: SharedKeyStore, ct_count: usize) -> anyhow::Result<SharedDemand> {
+        let mut registers = vec![DemandRegister::default(); ct_count];
+        {
+            let keystore = match keystore.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            for (i, register) in registers.iter_mut().enumerate() {
+                let mut buf = [0_u8; DEMAND_PEAKS_SIZE];
+                if keystore
+                    .get_raw(&peaks_key(i as u16 + 1), &mut buf)?
+                    .is_some()
+                {
+                    register.peaks_from_le_bytes(&buf);
+                }
+            }
+        }
+        Ok(Arc::new(Mutex::new(DemandRegisters {
+            keystore,
+            window: Duration::ZERO,
+            sliding: false,
+            utc_offset: 0,
+            registers,
+        })))
+    }
+
+    /// Use windows of this length from now on. Changing them starts over with empty windows, the
+    /// peaks are kept.
+    pub(crate) fn set_window(&mut self, window: Duration, sliding: bool) {
+        if window != self.window || sliding != self.sliding {
+            info!("Demand window set to {:?}, sliding: {}.", window, sliding);
+            self.window = window;
+            self.sliding = sliding;
+            for register in self.registers.iter_mut() {
+                register.clear();
+            }
+        }
+    }
+
+    /// Roll the days and months of the peaks over at midnight of the local time that is
+    /// `utc_offset` minutes ahead of UTC.
+    pub(crate) fn set_utc_offset(&mut self, utc_offset: i16) {
+        if utc_offset != self.utc_offset {
+            info!(
+                "Demand peaks roll over at midnight, {} minutes from UTC.",
+                utc_offset
+            );
+            self.utc_offset = utc_offset;
+        }
+    }
+
+    /// Adds the energy a CT imported in a measurement. Returns the demand of its last whole
+    /// window, 0 if there is none yet, and its peak demand of the month.
+    pub(crate) fn add(&mut self, ct_id: u16, measurement: &CTReading) -> (f32, Peak) {
+        let registers = &mut self.registers;
+        let register = match (ct_id as usize)
+            .checked_sub(1)
+            .and_then(|i| registers.get_mut(i))
+        {
+            Some(register) => register,
+            None => return (0.0, Peak::default()),
+        };
+        let keystore = &self.keystore;
+        if self.window.is_zero() || measurement.timestamp < MIN_KNOWN_TIME {
+            register.current = None;
+        } else if register.add(
+            measurement.import_kwh as f64,
+            measurement.timestamp,
+            self.window,
+            self.sliding,
+            self.utc_offset,
+        ) {
+            let stored = register.peaks_to_le_bytes().and_then(|buf| {
+                let mut keystore = match keystore.lock() {
+                    Ok(gaurd) => gaurd,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+                keystore.put_raw(&peaks_key(ct_id), &buf)
+            });
+            if let Err(e) = stored {
+                error!("Failed to store the peak demand of CT {}: {:?}", ct_id, e);
+            }
+        }
+        (register.demand.unwrap_or_default(), register.month)
+    }
+
+    pub fn to_json(&self) -> String {
+        let registers = self
+            .registers
+            .iter()
+            .enumerate()
+            .map(|(i, register)| register.to_json(i as u16 + 1))
+            .collect::<Vec<String>>();
+        format!("[{}]", registers.join(","))
+    }
+}
+
+/// Raises `peak` to `kw` if it is higher or the first one of its period, after moving it to
+/// `previous` if `end` is in the next period.
+fn raise(
+    peak: &mut Peak,
+    previous: &mut Peak,
+    kw: f32,
+    end: u64,
+    period: impl Fn(u64) -> u64,
+) -> bool {
+    if peak.time != 0 && period(peak.time) != period(end) {
+        *previous = *peak;
+        *peak = Peak::default();
+    }
+    if peak.time == 0 || kw > peak.kw {
+        *peak = Peak { kw, time: end };
+        return true;
+    }
+    false
+}
+
+fn peak_to_json(peak: Peak) -> String {
+    if peak.time == 0 {
+        return "null".to_string();
+    }
+    format!(r#"{{"kw":{},"time":{}}}"#, json_number(peak.kw), peak.time)
+}
+
+fn peaks_key(ct_id: u16) -> String {
+    format!("demand{}", ct_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: u64 = 60_000; // in ms
+    const HOUR: u64 = 60 * MINUTE;
+    // 2023-01-02T00:00Z and 2023-02-01T00:00Z.
+    const JAN_2: u64 = 1_672_617_600_000;
+    const FEB_1: u64 = 1_675_209_600_000;
+    const WINDOW: Duration = Duration::from_secs(15 * 60);
+
+    /// Adds `kwh` every minute for `minutes` minutes from `start`, half a minute into each.
+    /// Returns whether a peak changed.
+    fn add_minutes(
+        register: &mut DemandRegister,
+        start: u64,
+        minutes: u64,
+        kwh: f64,
+        sliding: bool,
+        utc_offset: i16,
+    ) -> bool {
+        let mut changed = false;
+        for minute in 0..minutes {
+            let time = start + minute * MINUTE + MINUTE / 2;
+            changed |= register.add(kwh, time, WINDOW, sliding, utc_offset);
+        }
+        changed
+    }
+
+    fn assert_kw(kw: Option<f32>, expected: f32) {
+        let kw = kw.expect("there is no demand");
+        assert!(
+            (kw - expected).abs() < 1e-3,
+            "{} kW is not {} kW",
+            kw,
+            expected
+        );
+    }
+
+    #[test]
+    fn block_window() {
+        let mut register = DemandRegister::default();
+        // the first window started before the first measurement, so it doesn't count.
+        assert!(!add_minutes(
+            &mut register,
+            JAN_2 + 5 * MINUTE,
+            25,
+            0.25,
+            false,
+            0
+        ));
+        assert_eq!(register.demand, None);
+        // 3.75 kWh in 15 minutes, the window ends with the first measurement after it.
+        assert!(register.add(0.0, JAN_2 + 30 * MINUTE, WINDOW, false, 0));
+        assert_kw(register.demand, 15.0);
+        assert_kw(Some(register.day.kw), 15.0);
+        assert_eq!(register.day.time, JAN_2 + 30 * MINUTE);
+        assert_eq!(register.month, register.day);
+    }
+
+    #[test]
+    fn sliding_window() {
+        let mut register = DemandRegister::default();
+        // the first minute doesn't count, the next 15 fill the window.
+        assert!(!add_minutes(&mut register, JAN_2, 16, 0.1, true, 0));
+        assert_eq!(register.demand, None);
+        assert!(register.add(0.1, JAN_2 + 16 * MINUTE, WINDOW, true, 0));
+        assert_kw(register.demand, 6.0);
+        // every minute the window moves on by one.
+        assert!(!add_minutes(
+            &mut register,
+            JAN_2 + 17 * MINUTE,
+            1,
+            0.4,
+            true,
+            0
+        ));
+        assert_kw(register.demand, 6.0);
+        assert!(register.add(0.1, JAN_2 + 18 * MINUTE, WINDOW, true, 0));
+        assert_kw(register.demand, 7.2);
+        assert_kw(Some(register.day.kw), 7.2);
+        assert_eq!(register.day.time, JAN_2 + 18 * MINUTE);
+    }
+
+    #[test]
+    fn gap_clears_the_window() {
+        let mut register = DemandRegister::default();
+        add_minutes(&mut register, JAN_2, 17, 0.1, true, 0);
+        assert_kw(register.demand, 6.0);
+        // nothing was measured in minutes 17 to 19, and the minute after a gap doesn't count.
+        let restart = JAN_2 + 20 * MINUTE;
+        assert!(!add_minutes(&mut register, restart, 16, 0.2, true, 0));
+        assert_kw(register.demand, 6.0);
+        assert_eq!(register.whole.len(), 14);
+        assert!(register.add(0.2, restart + 16 * MINUTE, WINDOW, true, 0));
+        assert_kw(register.demand, 12.0);
+    }
+
+    #[test]
+    fn window_ending_at_local_midnight() {
+        let mut register = DemandRegister::default();
+        // local time is UTC+1, so the local day starts an hour before the UTC one.
+        let midnight = JAN_2 - HOUR;
+        add_minutes(&mut register, midnight - 45 * MINUTE, 30, 0.1, false, 60);
+        add_minutes(&mut register, midnight - 15 * MINUTE, 15, 0.5, false, 60);
+        assert!(register.add(0.1, midnight + MINUTE / 2, WINDOW, false, 60));
+        // the window that ends at midnight is the last one of the day before.
+        let peak = register.day;
+        assert_kw(Some(peak.kw), 30.0);
+        assert_eq!(peak.time, midnight);
+        add_minutes(&mut register, midnight + MINUTE, 14, 0.1, false, 60);
+        assert!(register.add(0.1, midnight + 15 * MINUTE, WINDOW, false, 60));
+        assert_eq!(register.previous_day, peak);
+        assert_kw(Some(register.day.kw), 6.0);
+        assert_eq!(register.day.time, midnight + 15 * MINUTE);
+        // both are in January.
+        assert_eq!(register.month, peak);
+        assert_eq!(register.previous_month, Peak::default());
+    }
+
+    #[test]
+    fn month_roll_over() {
+        let mut register = DemandRegister::default();
+        // local time is UTC-5, January ends at 05:00 UTC.
+        let midnight = FEB_1 + 5 * HOUR;
+        add_minutes(&mut register, midnight - 45 * MINUTE, 30, 0.5, false, -300);
+        add_minutes(&mut register, midnight - 15 * MINUTE, 15, 0.1, false, -300);
+        add_minutes(&mut register, midnight, 15, 0.2, false, -300);
+        assert!(register.add(0.0, midnight + 15 * MINUTE, WINDOW, false, -300));
+        // the 30 kW window is the peak of January, and of its last day.
+        let january = Peak {
+            kw: register.previous_month.kw,
+            time: midnight - 15 * MINUTE,
+        };
+        assert_kw(Some(january.kw), 30.0);
+        assert_eq!(register.previous_month, january);
+        assert_eq!(register.previous_day, january);
+        // February starts with the first window that ends in it, even if it is lower.
+        assert_kw(Some(register.month.kw), 12.0);
+        assert_eq!(register.month.time, midnight + 15 * MINUTE);
+        assert_eq!(register.day, register.month);
+    }
+}
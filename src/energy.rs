@@ -0,0 +1,223 @@
+//! Lifetime energy registers of the CTs.
+//!
+//! The kWh of a record only cover its save period, the registers keep adding up the imported and
+//! the exported energy like the counters of a utility meter. Every stored record carries the
+//! registers of its CT, and the registers are written to the keystore every
+//! `ENERGY_STORE_INTERVAL` saves, to spare the flash. On boot, they are restored from whichever
+//! is newer, so they survive reboots and `/reset`, which writes them to the keystore first.
+//!
+//! The energy is also split by tariff, see `tariffs`, into tariff registers that are kept the
+//! same way. Energy measured while there is no tariff schedule, or the time is not known, only
+//! goes to the lifetime registers.
+
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+use crate::keystore::SharedKeyStore;
+use crate::{ENERGY_STORE_INTERVAL, MAX_TARIFFS};
+
+pub struct EnergyRegisters {
+    keystore: SharedKeyStore,
+    /// The energy every CT has imported and exported since it was first used, in kWh.
+    import_kwh: Vec<f64>,
+    export_kwh: Vec<f64>,
+    /// The same by tariff, the registers of tariff `t` are at `t - 1`.
+    tariff_import_kwh: Vec<[f64; MAX_TARIFFS]>,
+    tariff_export_kwh: Vec<[f64; MAX_TARIFFS]>,
+    /// Number of saves since the registers were last written to the keystore.
+    unstored: u32,
+}
+
+impl EnergyRegisters {
+    pub(crate) fn new(keystore: SharedKeyStore, ct_count: usize) -> Self {
+        EnergyRegisters {
+            keystore,
+            import_kwh: vec![0.0; ct_count],
+            export_kwh: vec![0.0; ct_count],
+            tariff_import_kwh: vec![[0.0; MAX_TARIFFS]; ct_count],
+            tariff_export_kwh: vec![[0.0; MAX_TARIFFS]; ct_count],
+            unstored: 0,
+        }
+    }
+
+    /// Restores the registers from the keystore, registers that were never stored start at 0.
+    pub(crate) fn load(&mut self) -> anyhow::Result<()> {
+        let keystore = match self.keystore.lock() {
+            Ok(gaurd) => gaurd,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        for i in 0..self.import_kwh.len() {
+            let ct_id = i as u16 + 1;
+            let import_kwh = keystore.get_u64(&register_key("imp", ct_id))?;
+            let export_kwh = keystore.get_u64(&register_key("exp", ct_id))?;
+            match (import_kwh, export_kwh) {
+                (None, None) => {
+                    // older firmware only kept the net energy.
+                    if let Some(bits) = keystore.get_u64(&register_key("kwh", ct_id))? {
+                        let kwh = f64::from_bits(bits);
+                        self.import_kwh[i] = f64::max(kwh, 0.0);
+                        self.export_kwh[i] = f64::max(-kwh, 0.0);
+                    }
+                }
+                (import_kwh, export_kwh) => {
+                    self.import_kwh[i] = import_kwh.map(f64::from_bits).unwrap_or_default();
+                    self.export_kwh[i] = export_kwh.map(f64::from_bits).unwrap_or_default();
+                }
+            }
+            for t in 0..MAX_TARIFFS {
+                let tariff = t as u16 + 1;
+                if let Some(bits) = keystore.get_u64(&tariff_key("imp", tariff, ct_id))? {
+                    self.tariff_import_kwh[i][t] = f64::from_bits(bits);
+                }
+                if let Some(bits) = keystore.get_u64(&tariff_key("exp", tariff, ct_id))? {
+                    self.tariff_export_kwh[i][t] = f64::from_bits(bits);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The imported and exported kWh of the CT with this id, if there is such a CT.
+    pub fn get(&self, ct_id: u16) -> Option<(f64, f64)> {
+        let index = (ct_id as usize).checked_sub(1)?;
+        Some((*self.import_kwh.get(index)?, *self.export_kwh.get(index)?))
+    }
+
+    pub(crate) fn add(&mut self, ct_id: u16, import_kwh: f64, export_kwh: f64) {
+        if let Some(index) = (ct_id as usize)
+            .checked_sub(1)
+            .filter(|&i| i < self.import_kwh.len())
+        {
+            self.import_kwh[index] += import_kwh;
+            self.export_kwh[index] += export_kwh;
+        }
+    }
+
+    /// The imported and exported kWh of the CT with this id by tariff, if there is such a CT.
+    pub fn get_tariffs(&self, ct_id: u16) -> Option<([f64; MAX_TARIFFS], [f64; MAX_TARIFFS])> {
+        let index = (ct_id as usize).checked_sub(1)?;
+        Some((
+            *self.tariff_import_kwh.get(index)?,
+            *self.tariff_export_kwh.get(index)?,
+        ))
+    }
+
+    pub(crate) fn add_tariffs(
+        &mut self,
+        ct_id: u16,
+        import_kwh: &[f32; MAX_TARIFFS],
+        export_kwh: &[f32; MAX_TARIFFS],
+    ) {
+        if let Some(index) = (ct_id as usize)
+            .checked_sub(1)
+            .filter(|&i| i < self.import_kwh.len())
+        {
+            for t in 0..MAX_TARIFFS {
+                self.tariff_import_kwh[index][t] += import_kwh[t] as f64;
+                self.tariff_export_kwh[index][t] += export_kwh[t] as f64;
+            }
+        }
+    }
+
+    /// Raise the registers of a CT to the ones of a stored record, which are newer if the power
+    /// went out before the registers were written to the keystore.
+    pub(crate) fn restore(&mut self, ct_id: u16, import_kwh: f64, export_kwh: f64) {
+        if let Some(index) = (ct_id as usize)
+            .checked_sub(1)
+            .filter(|&i| i < self.import_kwh.len())
+        {
+            self.import_kwh[index] = f64::max(self.import_kwh[index], import_kwh);
+            self.export_kwh[index] = f64::max(self.export_kwh[index], export_kwh);
+        }
+    }
+
+    /// Like `restore`, for the tariff registers.
+    pub(crate) fn restore_tariffs(
+        &mut self,
+        ct_id: u16,
+        import_kwh: &[f64; MAX_TARIFFS],
+        export_kwh: &[f64; MAX_TARIFFS],
+    ) {
+        if let Some(index) = (ct_id as usize)
+            .checked_sub(1)
+            .filter(|&i| i < self.import_kwh.len())
+        {
+            for t in 0..MAX_TARIFFS {
+                self.tariff_import_kwh[index][t] =
+                    f64::max(self.tariff_import_kwh[index][t], import_kwh[t]);
+                self.tariff_export_kwh[index][t] =
+                    f64::max(self.tariff_export_kwh[index][t], export_kwh[t]);
+            }
+        }
+    }
+
+    /// Writes the registers to the keystore once every `ENERGY_STORE_INTERVAL` saves.
+    pub(crate) fn store_if_due(&mut self) -> anyhow::Result<()> {
+        self.unstored += 1;
+        if self.unstored >= ENERGY_STORE_INTERVAL {
+            self.store()?;
+        }
+        Ok(())
+    }
+
+    /// Writes all registers to the keystore.
+    pub(crate) fn store(&mut self) -> anyhow::Result<()> {
+        let mut keystore = match self.keystore.lock() {
+            Ok(gaurd) => gaurd,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        for i in 0..self.import_kwh.len() {
+            let ct_id = i as u16 + 1;
+            keystore.put_u64(&register_key("imp", ct_id), self.import_kwh[i].to_bits())?;
+            keystore.put_u64(&register_key("exp", ct_id), self.export_kwh[i].to_bits())?;
+            for t in 0..MAX_TARIFFS {
+                let tariff = t as u16 + 1;
+                keystore.put_u64(
+                    &tariff_key("imp", tariff, ct_id),
+                    self.tariff_import_kwh[i][t].to_bits(),
+                )?;
+                keystore.put_u64(
+                    &tariff_key("exp", tariff, ct_id),
+                    self.tariff_export_kwh[i][t].to_bits(),
+                )?;
+            }
+        }
+        self.unstored = 0;
+        info!("Stored the lifetime energy registers.");
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> String {
+        let registers = (0..self.import_kwh.len())
+            .map(|i| {
+                let tariffs = (0..MAX_TARIFFS)
+                    .map(|t| {
+                        format!(
+                            r#"{{"tariff":{},"import_kwh":{},"export_kwh":{}}}"#,
+                            t + 1,
+                            self.tariff_import_kwh[i][t],
+                            self.tariff_export_kwh[i][t]
+                        )
+                    })
+                    .collect::<Vec<String>>();
+                format!(
+                    r#"{{"ct":{},"import_kwh":{},"export_kwh":{},"net_kwh":{},"tariffs":[{}]}}"#,
+                    i + 1,
+                    self.import_kwh[i],
+                    self.export_kwh[i],
+                    self.import_kwh[i] - self.export_kwh[i],
+                    tariffs.join(",")
+                )
+            })
+            .collect::<Vec<String>>();
+        format!("[{}]", registers.join(","))
+    }
+}
+
+fn register_key(name: &str, ct_id: u16) -> String {
+    format!("{}{}", name, ct_id)
+}
+
+fn tariff_key(name: &str, tariff: u16, ct_id: u16) -> String {
+    format!("t{}{}{}", tariff, name, ct_id)
+}
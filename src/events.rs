@@ -0,0 +1,177 @@
+//! Voltage sags, swells and interruptions.
+//!
+//! While a CT is measured, the RMS voltage of every cycle is compared against thresholds in
+//! percent of the nominal voltage. A sag is a drop below the sag threshold, an interruption a drop
+//! below the interruption threshold and a swell a rise above the swell threshold. An event lasts
+//! from the first cycle past its threshold to the last one, and is only logged if it lasts at
+//! least the minimum duration. The voltage is not sampled between measurements, so an event that
+//! is still going on when one measurement ends is continued if the next one starts with it. Its
+//! duration only counts the cycles that were observed, the pause between the measurements is left
+//! out, so it is a lower bound of how long the event really lasted.
+
+use std::time::Duration;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+use crate::record::VoltageEvent;
+use crate::rtc::now;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Sag = 1,
+    Swell = 2,
+    Interruption = 3,
+}
+
+impl EventKind {
+    pub fn from_u16(kind: u16) -> Option<EventKind> {
+        match kind {
+            1 => Some(EventKind::Sag),
+            2 => Some(EventKind::Swell),
+            3 => Some(EventKind::Interruption),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::Sag => "sag",
+            EventKind::Swell => "swell",
+            EventKind::Interruption => "interruption",
+        }
+    }
+
+    /// Whether the voltage is below the nominal one during the event.
+    fn is_low(&self) -> bool {
+        *self != EventKind::Swell
+    }
+}
+
+/// When the voltage of a cycle is part of an event.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EventThresholds {
+    /// In volts.
+    pub nominal_voltage: f32,
+    /// In percent of the nominal voltage.
+    pub sag: f32,
+    pub swell: f32,
+    pub interruption: f32,
+    /// Shorter deviations are not logged.
+    pub min_duration: Duration,
+}
+
+/// An event that has not ended yet.
+#[derive(Debug)]
+struct Ongoing {
+    kind: EventKind,
+    /// In ms since the epoch.
+    start_time: u64,
+    /// Clock of the sample source when the last cycle of the event ended.
+    end: Duration,
+    /// The time of the cycles of the event, without the pauses between measurements.
+    observed: Duration,
+    /// The lowest cycle RMS voltage of a sag or interruption, the highest of a swell.
+    extreme: f32,
+}
+
+#[derive(Debug)]
+pub(crate) struct EventDetector {
+    thresholds: Option<EventThresholds>,
+    ongoing: Option<Ongoing>,
+    /// Events that ended and were not taken yet.
+    ended: Vec<VoltageEvent>,
+}
+
+impl EventDetector {
+    pub(crate) fn new() -> Self {
+        EventDetector {
+            thresholds: None,
+            ongoing: None,
+            ended: Vec::new(),
+        }
+    }
+
+    /// Nothing is detected until the thresholds are set.
+    pub(crate) fn set_thresholds(&mut self, thresholds: EventThresholds) {
+        self.thresholds = Some(thresholds);
+    }
+
+    /// Checks the RMS voltage of a cycle that started at `start` and ended at `end`, by the clock of
+    /// the sample source.
+    pub(crate) fn add_cycle(&mut self, ct_id: u16, v_rms: f32, start: Duration, end: Duration) {
+        let thresholds = match self.thresholds {
+            Some(thresholds) => thresholds,
+            None => return,
+        };
+        let percent = v_rms / thresholds.nominal_voltage * 100.0;
+        let kind = if percent < thresholds.interruption {
+            Some(EventKind::Interruption)
+        } else if percent < thresholds.sag {
+            Some(EventKind::Sag)
+        } else if percent > thresholds.swell {
+            Some(EventKind::Swell)
+        } else {
+            None
+        };
+        if let Some(ongoing) = &mut self.ongoing {
+            match kind {
+                Some(kind) if kind.is_low() == ongoing.kind.is_low() => {
+                    // a sag that drops below the interruption threshold becomes an interruption.
+                    if kind == EventKind::Interruption {
+                        ongoing.kind = kind;
+                    }
+                    ongoing.extreme = if kind.is_low() {
+                        f32::min(ongoing.extreme, v_rms)
+                    } else {
+                        f32::max(ongoing.extreme, v_rms)
+                    };
+                    ongoing.observed += end.saturating_sub(ongoing.end.max(start));
+                    ongoing.end = end;
+                    return;
+                }
+                _ => self.finish(ct_id, thresholds.min_duration),
+            }
+        }
+        if let Some(kind) = kind {
+            let since_start = end.saturating_sub(start).as_millis() as u64;
+            self.ongoing = Some(Ongoing {
+                kind,
+                start_time: (now().as_millis() as u64).saturating_sub(since_start),
+                end,
+                observed: end.saturating_sub(start),
+                extreme: v_rms,
+            });
+        }
+    }
+
+    /// Ends the ongoing event, which is kept if it lasted long enough.
+    fn finish(&mut self, ct_id: u16, min_duration: Duration) {
+        if let Some(ongoing) = self.ongoing.take() {
+            let duration = ongoing.observed;
+            if duration >= min_duration {
+                let event = VoltageEvent {
+                    ct_id,
+                    kind: ongoing.kind as u16,
+                    boot_count: 0,
+                    start_time: ongoing.start_time,
+                    duration: duration.as_millis() as u32,
+                    extreme_voltage: ongoing.extreme,
+                };
+                info!("Voltage {} detected: {:?}", ongoing.kind.name(), event);
+                self.ended.push(event);
+            }
+        }
+    }
+
+    /// The events that ended since the last call.
+    pub(crate) fn take_events(&mut self) -> Vec<VoltageEvent> {
+        std::mem::take(&mut self.ended)
+    }
+
+    /// Forgets the ongoing and the ended events, e.g. when the calibration changed.
+    pub(crate) fn clear(&mut self) {
+        self.ongoing = None;
+        self.ended.clear();
+    }
+}
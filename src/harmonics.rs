@@ -0,0 +1,108 @@
+//! Harmonic analysis of the voltage and current waveforms.
+//!
+//! While a CT measures, the samples of a whole number of voltage periods are kept in a
+//! `SampleBuffer`. The amplitude of every harmonic up to `MAX_HARMONIC` is then found with the
+//! Goertzel algorithm. Since the samples span whole periods, every harmonic falls exactly on a
+//! frequency bin and doesn't leak into the others.
+
+use std::f32::consts::PI;
+
+use crate::{HARMONICS_BUFFER_SIZE, MAX_HARMONIC};
+
+/// The filtered voltage and current samples of whole voltage periods.
+pub(crate) struct SampleBuffer {
+    voltage: Vec<f32>,
+    current: Vec<f32>,
+    /// Whether the first period has started, samples before that are not kept.
+    started: bool,
+    /// Number of whole periods in the buffer, and where the last of them ends.
+    periods: u32,
+    end: usize,
+}
+
+impl SampleBuffer {
+    pub(crate) fn new() -> Self {
+        SampleBuffer {
+            voltage: Vec::with_capacity(HARMONICS_BUFFER_SIZE),
+            current: Vec::with_capacity(HARMONICS_BUFFER_SIZE),
+            started: false,
+            periods: 0,
+            end: 0,
+        }
+    }
+
+    /// Empty the buffer for the next measurement.
+    pub(crate) fn clear(&mut self) {
+        self.voltage.clear();
+        self.current.clear();
+        self.started = false;
+        self.periods = 0;
+        self.end = 0;
+    }
+
+    /// Keep a pair of samples, if a period has started and there is room left.
+    pub(crate) fn push(&mut self, voltage: f32, current: f32) {
+        if self.started && self.voltage.len() < HARMONICS_BUFFER_SIZE {
+            self.voltage.push(voltage);
+            self.current.push(current);
+        }
+    }
+
+    /// Mark the start of a voltage period, which is the end of the previous one.
+    pub(crate) fn start_period(&mut self) {
+        if !self.started {
+            self.started = true;
+        } else if self.voltage.len() < HARMONICS_BUFFER_SIZE {
+            self.periods += 1;
+            self.end = self.voltage.len();
+        }
+    }
+
+    /// The total harmonic distortion of the voltage and the current in percent, both 0 if not a
+    /// single whole period was kept.
+    pub(crate) fn thd(&self) -> (f32, f32) {
+        (
+            thd(&self.voltage[..self.end], self.periods),
+            thd(&self.current[..self.end], self.periods),
+        )
+    }
+}
+
+/// The amplitudes of the harmonics of `samples`, which span `periods` whole periods of the
+/// fundamental. The first is the fundamental, harmonics above the Nyquist frequency are 0.
+pub(crate) fn amplitudes(samples: &[f32], periods: u32) -> [f32; MAX_HARMONIC] {
+    let mut amplitudes = [0.0; MAX_HARMONIC];
+    for (i, amplitude) in amplitudes.iter_mut().enumerate() {
+        let bin = (i + 1) * periods as usize;
+        if bin > 0 && 2 * bin < samples.len() {
+            *amplitude = goertzel(samples, bin);
+        }
+    }
+    amplitudes
+}
+
+/// The total harmonic distortion of `samples` in percent, i.e. the rms of the harmonics up to
+/// `MAX_HARMONIC` relative to the fundamental.
+pub(crate) fn thd(samples: &[f32], periods: u32) -> f32 {
+    let amplitudes = amplitudes(samples, periods);
+    let fundamental = amplitudes[0];
+    if fundamental <= 0.0 {
+        return 0.0;
+    }
+    let harmonics = amplitudes[1..].iter().map(|a| a * a).sum::<f32>();
+    100.0 * f32::sqrt(harmonics) / fundamental
+}
+
+/// The amplitude of the frequency that makes `bin` cycles over `samples`.
+fn goertzel(samples: &[f32], bin: usize) -> f32 {
+    let n = samples.len() as f32;
+    let coeff = 2.0 * f32::cos(2.0 * PI * bin as f32 / n);
+    let (mut s1, mut s2) = (0.0_f32, 0.0_f32);
+    for &sample in samples {
+        let s0 = sample + coeff * s1 - s2;
+        s2 = s1;
+        s1 = s0;
+    }
+    let power = s1 * s1 + s2 * s2 - coeff * s1 * s2;
+    2.0 * f32::sqrt(f32::max(power, 0.0)) / n
+}
@@ -0,0 +1,83 @@
+//! Small values that have to survive reboots and `/reset`.
+//!
+//! On the device they are kept in NVS, on the host every key is a file in a directory.
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "esp")]
+use embedded_svc::storage::RawStorage;
+#[cfg(feature = "esp")]
+use esp_idf_svc::nvs_storage::EspNvsStorage;
+
+/// The keystore shared between the main loop and the web server tasks.
+pub type SharedKeyStore = Arc<Mutex<Box<dyn KeyStore>>>;
+
+/// Keys are at most 15 characters long, which is the limit of NVS.
+pub trait KeyStore: Send {
+    /// Reads the value of `key` into `buf` and returns its size, or None if the key is not set.
+    fn get_raw(&self, key: &str, buf: &mut [u8]) -> anyhow::Result<Option<usize>>;
+
+    fn put_raw(&mut self, key: &str, value: &[u8]) -> anyhow::Result<()>;
+
+    fn get_u64(&self, key: &str) -> anyhow::Result<Option<u64>> {
+        let mut buf = [0_u8; std::mem::size_of::<u64>()];
+        Ok(self
+            .get_raw(key, &mut buf)?
+            .map(|_| u64::from_le_bytes(buf)))
+    }
+
+    fn put_u64(&mut self, key: &str, value: u64) -> anyhow::Result<()> {
+        self.put_raw(key, &value.to_le_bytes())
+    }
+}
+
+/// Wraps a keystore so it can be shared.
+pub fn shared(keystore: impl KeyStore + 'static) -> SharedKeyStore {
+    Arc::new(Mutex::new(Box::new(keystore)))
+}
+
+#[cfg(feature = "esp")]
+impl KeyStore for EspNvsStorage {
+    fn get_raw(&self, key: &str, buf: &mut [u8]) -> anyhow::Result<Option<usize>> {
+        Ok(RawStorage::get_raw(self, key, buf)?.map(|value| value.len()))
+    }
+
+    fn put_raw(&mut self, key: &str, value: &[u8]) -> anyhow::Result<()> {
+        RawStorage::put_raw(self, key, value)?;
+        Ok(())
+    }
+}
+
+/// Keeps every key in a file of its own under `root`.
+pub struct FileKeyStore {
+    root: PathBuf,
+}
+
+impl FileKeyStore {
+    pub fn new(root: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        Ok(FileKeyStore { root })
+    }
+}
+
+impl KeyStore for FileKeyStore {
+    fn get_raw(&self, key: &str, buf: &mut [u8]) -> anyhow::Result<Option<usize>> {
+        match fs::read(self.root.join(key)) {
+            Ok(value) => {
+                let size = usize::min(value.len(), buf.len());
+                buf[..size].copy_from_slice(&value[..size]);
+                Ok(Some(size))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn put_raw(&mut self, key: &str, value: &[u8]) -> anyhow::Result<()> {
+        fs::write(self.root.join(key), value)?;
+        Ok(())
+    }
+}
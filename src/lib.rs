@@ -0,0 +1,307 @@
+pub mod alarms;
+pub mod calibration;
+pub mod channels;
+pub mod ct;
+pub mod demand;
+pub mod energy;
+pub mod events;
+#[cfg(feature = "harmonics")]
+pub(crate) mod harmonics;
+pub mod keystore;
+#[cfg(feature = "esp")]
+pub mod ota;
+pub mod record;
+pub mod rtc;
+pub mod sample;
+pub(crate) mod schedule;
+pub mod settings;
+pub mod tariffs;
+pub(crate) mod utils;
+pub mod web;
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::Duration;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+use crate::alarms::AlarmTable;
+use crate::calibration::CalibrationTable;
+use crate::ct::{CTStorage, CT};
+use crate::demand::DemandRegisters;
+use crate::keystore::SharedKeyStore;
+use crate::sample::SampleSource;
+use crate::schedule::SaveSchedule;
+use crate::settings::Settings;
+
+pub use crate::rtc::{now, set_system_time, uptime};
+
+/// The most CTs a channel map can have, see `channels`.
+pub const MAX_CTS: usize = 5;
+
+// version used for OTA
+pub const VERSION: u32 = 102;
+
+// ADC constants
+// const ADC_BITS: u32 = 12;
+// const MAX_READING: u32 = 1 << ADC_BITS;
+const MAX_MV_ATTEN_11: u16 = 2450;
+const SUPPLY_VOLTAGE: f32 = 3.3;
+const NOISE_THRESHOLD: f32 = MAX_MV_ATTEN_11 as f32 / 8.0;
+
+// Periodic actions constants
+// The defaults of the settings, see `settings`.
+pub const SAVE_PERIOD_TIMEOUT: u64 = 3600; // 3600 for one hour
+const SECONDS_PER_HOUR: f32 = 3600.0;
+const CROSSINGS: u32 = 100; // voltage zero crossings in one measurement
+const MEASUREMENT_TIMEOUT: u64 = 3; // in seconds
+const LOOP_SLEEP: u64 = 1000; // in milliseconds, after every measurement of the CTs
+const CALIBRATION_RUNS: u32 = 5; // measurements of a guided calibration
+#[cfg(feature = "harmonics")]
+const MAX_HARMONIC: usize = 15; // highest harmonic in the total harmonic distortion
+#[cfg(feature = "harmonics")]
+const HARMONICS_BUFFER_SIZE: usize = 1024; // samples of each channel kept for the harmonics
+const MIN_KNOWN_TIME: u64 = 1_672_531_200_000; // in ms since the epoch, earlier clocks are not set
+const TIME_JUMP_TOLERANCE: u64 = 2000; // in ms, larger clock changes restart the save period
+const MAX_CYCLE_TIME: u64 = 50; // in ms, without a rising voltage crossing the voltage is gone
+const FREQUENCY_TOLERANCE: f32 = 0.02; // of the nominal frequency, before a deviation is logged
+const DEMAND_SUBINTERVAL: u64 = 60; // in seconds, the step of sliding demand windows
+
+// Storage constants
+const MAX_SHARD_SIZE: u64 = 4096; // in bytes
+const MAX_TIME_STORAGE_SIZE: u64 = 4096; // in bytes
+const BOOT_EVENT_SIZE: usize = 36; // in bytes
+const VOLTAGE_EVENT_SIZE: usize = 26; // in bytes
+const ALARM_EVENT_SIZE: usize = 34; // in bytes
+const MAX_EVENT_LOG_SIZE: u64 = 8192; // in bytes, of the event and the alarm log, the full log is kept as the previous one
+const MAX_ALARM_RULES: usize = 8;
+const DEMAND_PEAKS_SIZE: usize = 48; // in bytes, of the demand peaks of a CT in the keystore
+const MAX_TARIFFS: usize = 4;
+const MAX_TARIFF_SEASONS: usize = 4;
+const MAX_TARIFF_BANDS: usize = 8; // of one day of a season
+const MAX_TARIFF_SCHEDULE_SIZE: usize = 1024; // in bytes, of a schedule written as text
+const POWERLOSS_ENTRY_SIZE: usize = 28; // in bytes, in the powerloss log of older firmware
+const LEGACY_POWERLOSS_ENTRY_SIZE: usize = 16; // in bytes, before the boot counter was added
+const CT_READING_SIZE: usize = 220; // in bytes, including the crc
+const LEGACY_CT_READING_SIZE: usize = 30; // in bytes, in shards without a header
+const SHARD_HEADER_SIZE: usize = 20; // in bytes
+const SHARD_MAGIC: [u8; 4] = *b"SEMR";
+const SHARD_FORMAT_VERSION: u16 = 11;
+const SEQ_RESERVE: u64 = 64; // records stored before the sequence number is written to NVS again
+const ENERGY_STORE_INTERVAL: u32 = 24; // saves before the energy registers are written to NVS again
+
+// Network constants
+const ACCESS_TOKEN_SIZE: usize = 56;
+pub const MAX_REQUEST_BODY_SIZE: usize = MAX_TARIFF_SCHEDULE_SIZE; // in bytes, the longest body is a tariff schedule
+
+/// Prepares the readings storage under `root` for the device with the given MAC address.
+///
+/// Finds the shard to append to, restores the last stored time, counts the boot and logs it in the
+/// boot log. The record sequence numbers, the boot counter and the lifetime energy registers of the
+/// `ct_count` CTs are kept in `keystore`.
+pub fn init_ct_storage(
+    root: impl Into<PathBuf>,
+    mac: [u8; 6],
+    keystore: SharedKeyStore,
+    ct_count: usize,
+) -> anyhow::Result<Arc<Mutex<CTStorage>>> {
+    // the time since boot is measured from here on the host.
+    uptime();
+    let storage_lock = Arc::new(Mutex::new(CTStorage::new(
+        root.into(),
+        mac,
+        keystore,
+        ct_count,
+    )));
+    {
+        let mut ct_storage = match storage_lock.lock() {
+            Ok(gaurd) => gaurd,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        info!("Finding newest shard.");
+        ct_storage.find_newest_readings_shard_num()?;
+        ct_storage.update_system_time()?;
+        ct_storage.count_boot()?;
+        ct_storage.log_boot(reset_reason())?;
+        ct_storage.load_energy_registers()?;
+    }
+    Ok(storage_lock)
+}
+
+/// Why the device was reset, an `esp_reset_reason_t`.
+#[cfg(feature = "esp")]
+fn reset_reason() -> u16 {
+    unsafe { esp_idf_sys::esp_reset_reason() as u16 }
+}
+
+/// The simulator always starts from a power-on, `ESP_RST_POWERON`.
+#[cfg(not(feature = "esp"))]
+fn reset_reason() -> u16 {
+    1
+}
+
+/// The main measurement loop. Never returns unless a measurement fails.
+///
+/// Periodically reads and aggregates the values of every CT, and stores the aggregated values
+/// once every save period, aligned to the clock once the time is known (see `schedule`).
+/// Calibrations and settings changed over http are applied before the next reading, and guided
+/// calibrations run in place of a reading. The energy of a reading goes to the tariff in effect
+/// when it started. The alarm rules are checked and the demand registers updated after every
+/// reading. `save_period` overrides the save period of the settings.
+pub fn run_measurement_loop<S: SampleSource>(
+    cts: &mut [CT<S>],
+    storage_lock: &Mutex<CTStorage>,
+    calibrations: &Mutex<CalibrationTable>,
+    settings: &Mutex<Settings>,
+    alarms: &Mutex<AlarmTable>,
+    demand: &Mutex<DemandRegisters>,
+    save_period: Option<Duration>,
+) -> anyhow::Result<()> {
+    let mut schedule = {
+        let settings = match settings.lock() {
+            Ok(gaurd) => gaurd,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        SaveSchedule::new(
+            save_period.unwrap_or_else(|| settings.save_period()),
+            now().as_millis() as u64,
+            uptime(),
+        )
+    };
+    loop {
+        {
+            let mut calibrations = match calibrations.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            for ct in cts.iter_mut() {
+                if let Some(calibration) = calibrations.take_changed(ct.id()) {
+                    ct.set_calibration(calibration);
+                }
+            }
+        }
+
+        let (nominal_frequency, crossings, measurement_timeout, loop_sleep, settings_save_period) = {
+            let settings = match settings.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            let tariff = settings.tariffs().tariff_at(now().as_millis() as u64);
+            for ct in cts.iter_mut() {
+                ct.set_event_thresholds(settings.event_thresholds());
+                ct.set_tariff(tariff);
+            }
+            {
+                let mut demand = match demand.lock() {
+                    Ok(gaurd) => gaurd,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+                demand.set_window(settings.demand_window(), settings.demand_sliding());
+                demand.set_utc_offset(settings.tariffs().utc_offset());
+            }
+            (
+                settings.nominal_frequency() as f32,
+                settings.crossings(),
+                settings.measurement_timeout(),
+                settings.loop_sleep(),
+                settings.save_period(),
+            )
+        };
+
+        // save the readings of CTs to storage, before the next measurement begins.
+        let period = save_period.unwrap_or(settings_save_period);
+        let (time, since_boot) = (now().as_millis() as u64, uptime());
+        if schedule.is_due(period, time, since_boot) {
+            info!("Saving to storage.");
+            let flags = schedule.next(time, since_boot);
+            let mut ct_storage = match storage_lock.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            info!("Got storage lock.");
+            let res = ct_storage.save_to_storage(cts, flags);
+            println!("{:?}", res);
+            let res = ct_storage.store_time(time);
+            println!("{:?}", res);
+
+            // Reset CT readings.
+            for ct in cts.iter_mut() {
+                ct.reset();
+            }
+        }
+
+        let job = match calibrations.lock() {
+            Ok(mut gaurd) => gaurd.take_job(),
+            Err(poisoned) => poisoned.into_inner().take_job(),
+        };
+        if let Some(job) = job {
+            let result = match cts.iter_mut().find(|ct| ct.id() == job.ct_id) {
+                Some(ct) => {
+                    let result = calibration::run(
+                        ct,
+                        &job,
+                        CALIBRATION_RUNS,
+                        crossings,
+                        measurement_timeout,
+                    );
+                    ct.clear_events();
+                    result
+                }
+                None => Err(anyhow::anyhow!("There is no CT {}.", job.ct_id)),
+            };
+            let mut calibrations = match calibrations.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            calibrations.finish(result);
+            continue;
+        }
+
+        for ct in cts.iter_mut() {
+            let measurement = ct.calculate_energy(crossings, measurement_timeout)?;
+            ct.reading
+                .set_time(now().as_millis() as u64, uptime().as_millis() as u64);
+            info!("Energy Reading: {:?}", ct.reading);
+            let frequency = ct.reading.frequency;
+            if frequency > 0.0
+                && f32::abs(frequency - nominal_frequency) > nominal_frequency * FREQUENCY_TOLERANCE
+            {
+                warn!(
+                    "Frequency of CT {} is {} Hz, nominal is {} Hz.",
+                    ct.id(),
+                    frequency,
+                    nominal_frequency
+                );
+            }
+            let (demand_kw, peak) = match demand.lock() {
+                Ok(mut gaurd) => gaurd.add(ct.id(), &measurement),
+                Err(poisoned) => poisoned.into_inner().add(ct.id(), &measurement),
+            };
+            ct.reading.set_demand(demand_kw, peak);
+            let events = ct.take_events();
+            let measured_at = Duration::from_millis(measurement.uptime);
+            let triggered = match alarms.lock() {
+                Ok(mut gaurd) => gaurd.evaluate(ct.id(), &measurement, measured_at),
+                Err(poisoned) => poisoned
+                    .into_inner()
+                    .evaluate(ct.id(), &measurement, measured_at),
+            };
+            if !events.is_empty() || !triggered.is_empty() {
+                let mut ct_storage = match storage_lock.lock() {
+                    Ok(gaurd) => gaurd,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+                if let Err(e) = ct_storage.log_events(&events) {
+                    error!("Failed to log voltage events: {:?}", e);
+                }
+                if let Err(e) = ct_storage.log_alarms(&triggered) {
+                    error!("Failed to log alarms: {:?}", e);
+                }
+            }
+        }
+
+        sleep(loop_sleep);
+    }
+}
@@ -1,16 +1,13 @@
-mod ct;
-mod ota;
-pub(crate) mod utils;
-
-use std::sync::{Arc, Mutex};
-use std::thread::sleep;
-use std::time::{Duration, Instant};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
 use embedded_svc::http::server::registry::Registry;
 use embedded_svc::http::server::{Request, Response};
-use embedded_svc::http::{Headers, SendStatus};
+use embedded_svc::http::{Headers, SendHeaders, SendStatus};
 
 use embedded_svc::io::Read as SvcRead;
+use embedded_svc::io::Write as SvcWrite;
 use embedded_svc::ipv4::{Ipv4Addr, Mask, RouterConfiguration, Subnet};
 use embedded_svc::wifi::Wifi;
 use embedded_svc::wifi::{AccessPointConfiguration, ApIpStatus, ApStatus, AuthMethod, Status};
@@ -21,7 +18,7 @@ use esp_idf_svc::nvs_storage::EspNvsStorage;
 
 use esp_idf_svc::sysloop::EspSysLoopStack;
 use esp_idf_svc::wifi::EspWifi;
-use esp_idf_sys::{esp, gettimeofday, settimeofday, timeval};
+use esp_idf_sys::esp;
 
 use esp_idf_hal::adc;
 use esp_idf_hal::prelude::Peripherals;
@@ -31,8 +28,16 @@ use cstr::cstr;
 #[allow(unused_imports)]
 use log::{debug, error, info, warn};
 
-use crate::ct::{CTStorage, CT};
-use crate::ota::{first_run_validate, ota_update_from_reader};
+use sem::alarms::AlarmTable;
+use sem::calibration::CalibrationTable;
+use sem::channels::Channels;
+use sem::ct::CT;
+use sem::demand::DemandRegisters;
+use sem::keystore::{self, SharedKeyStore};
+use sem::ota::{first_run_validate, ota_update_from_reader};
+use sem::settings::Settings;
+use sem::web::{self, Method};
+use sem::{MAX_REQUEST_BODY_SIZE, VERSION};
 
 // const SINGLE_PHASE_CURRENT_PIN: u8 = 35;
 // const SINGLE_PHASE_VOLTAGE_PIN: u8 = 34;
@@ -44,33 +49,7 @@ use crate::ota::{first_run_validate, ota_update_from_reader};
 // const CURRENT_SCALE: [f32; 3] = [102.0; 3]; //111.1;
 // const VOLTAGE_SCALE: [f32; 3] = [232.5; 3];
 
-/// Specify the number of CT modules that will be connected
-/// to this system.
-#[cfg(feature = "single-phase")]
-const AC_PHASE: usize = 1;
-#[cfg(feature = "three-phase")]
-const AC_PHASE: usize = 3;
-
-// version used for OTA
-const VERSION: u32 = 102;
-
-// ADC constants
-// const ADC_BITS: u32 = 12;
-// const MAX_READING: u32 = 1 << ADC_BITS;
-const MAX_MV_ATTEN_11: u16 = 2450;
-const SUPPLY_VOLTAGE: f32 = 3.3;
-const NOISE_THRESHOLD: f32 = MAX_MV_ATTEN_11 as f32 / 8.0;
-
-// Periodic actions constants
-const SAVE_PERIOD_TIMEOUT: u64 = 3600; // 3600 for one hour
-
-// Storage constants
-const MAX_SHARD_SIZE: u64 = 4096; // in bytes
-const MAX_TIME_STORAGE_SIZE: u64 = 4096; // in bytes
-const CT_READING_SIZE: usize = 30; // in bytes
-
 // Network constants
-const ACCESS_TOKEN_SIZE: usize = 56;
 const AP_PASSWORD: &str = "12345678";
 const GATEWAY_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
 
@@ -86,33 +65,42 @@ fn main() -> anyhow::Result<()> {
     let _fs_conf = init_littlefs_storage()?;
     info!("Initialized and mounted littlefs storage.");
 
-    // Initialize CT readings shards
-    let storage_lock = Arc::new(Mutex::new(CTStorage::new()));
-    {
-        let mut ct_storage = match storage_lock.lock() {
-            Ok(gaurd) => gaurd,
-            Err(poisoned) => poisoned.into_inner(),
-        };
-        info!("Finding newest shard.");
-        ct_storage.find_newest_readings_shard_num()?;
-        ct_storage.update_system_time()?;
-        ct_storage.log_powerloss()?;
-    }
-
     // Initialize NVS storage
-    let (default_nvs, _keystore) = init_nvs_storage()?;
+    let (default_nvs, keystore) = init_nvs_storage()?;
     info!("Initialized default NVS storage.");
 
+    // Initialize CT readings shards
+    let mac = read_mac()?;
+    let channels = Channels::load(keystore.clone(), Path::new("/littlefs"))?;
+    let channel_map = match channels.lock() {
+        Ok(gaurd) => gaurd.active().clone(),
+        Err(poisoned) => poisoned.into_inner().active().clone(),
+    };
+    let ct_count = channel_map.len();
+    let storage_lock = sem::init_ct_storage("/littlefs", mac, keystore.clone(), ct_count)?;
+    let calibrations = CalibrationTable::load(keystore.clone(), &channel_map)?;
+    let settings = Settings::load(keystore.clone())?;
+    let alarms = AlarmTable::load(keystore.clone(), ct_count)?;
+    let demand = DemandRegisters::load(keystore, ct_count)?;
+
     // SSID and password for the Wifi access point.
     let mut ap_ssid: String = String::new();
     let ap_password: &str = AP_PASSWORD;
-    configure_access_point_ssid(&mut ap_ssid)?;
+    configure_access_point_ssid(&mut ap_ssid, &mac)?;
     info!("Configured AP SSID as: {}.", ap_ssid);
 
     let _wifi = init_access_point(&ap_ssid, ap_password, default_nvs)?;
     info!("Initialized Wifi.");
 
-    let _web_server = init_web_server(storage_lock.clone())?;
+    let context = Arc::new(web::Context {
+        storage: storage_lock.clone(),
+        calibrations: calibrations.clone(),
+        channels,
+        settings: settings.clone(),
+        alarms: alarms.clone(),
+        demand: demand.clone(),
+    });
+    let _web_server = init_web_server(context)?;
     info!("Initialized Web Server.");
 
     // Initilize peripherals and pins
@@ -120,46 +108,30 @@ fn main() -> anyhow::Result<()> {
     let pins = peripherals.pins;
 
     // Initilize ADC
-    let mut powered_adc1 = adc::PoweredAdc::new(
+    let powered_adc1 = adc::PoweredAdc::new(
         peripherals.adc1,
         adc::config::Config::new().calibration(false),
     )?;
-    let mut cts = CT::init(pins)?;
+    let initial_calibrations = match calibrations.lock() {
+        Ok(gaurd) => gaurd.all(),
+        Err(poisoned) => poisoned.into_inner().all(),
+    };
+    let mut cts = CT::init(pins, powered_adc1, &channel_map, &initial_calibrations)?;
     info!("Initialized ADC 1.");
 
     // If everything is working fine, cancel rollback on the next restart to the previous firmware
     first_run_validate()?;
 
     // Main Loop
-    let mut save_period_start = Instant::now();
-    loop {
-        for ct in &mut cts {
-            ct.calculate_energy(&mut powered_adc1, 100, std::time::Duration::new(3, 0))?;
-            ct.reading.set_time(now().as_millis() as u64);
-            info!("Energy Reading: {:?}", ct.reading);
-        }
-
-        // save the readings of CTs to storage.
-        if save_period_start.elapsed() > Duration::new(SAVE_PERIOD_TIMEOUT, 0) {
-            info!("Saving to storage.");
-            let mut ct_storage = match storage_lock.lock() {
-                Ok(gaurd) => gaurd,
-                Err(poisoned) => poisoned.into_inner(),
-            };
-            info!("Got storage lock.");
-            let res = ct_storage.save_to_storage(&cts);
-            println!("{:?}", res);
-            let res = ct_storage.store_time(now().as_millis() as u64);
-            println!("{:?}", res);
-
-            // Reset CT readings.
-            for ct in &mut cts {
-                ct.reset();
-            }
-            save_period_start = Instant::now();
-        }
-        sleep(Duration::from_millis(1000));
-    }
+    sem::run_measurement_loop(
+        &mut cts,
+        &storage_lock,
+        &calibrations,
+        &settings,
+        &alarms,
+        &demand,
+        None,
+    )
 }
 
 /// Initializes a littlefs file system.
@@ -196,15 +168,14 @@ fn init_littlefs_storage() -> anyhow::Result<esp_idf_sys::esp_vfs_littlefs_conf_
 ///
 /// A partition with name `NVS_PARTITION_NAME` has to be specified
 /// in the partition table csv file.
-fn init_nvs_storage() -> anyhow::Result<(Arc<EspDefaultNvs>, Arc<EspNvsStorage>)> {
+fn init_nvs_storage() -> anyhow::Result<(Arc<EspDefaultNvs>, SharedKeyStore)> {
     let default_nvs = Arc::new(EspDefaultNvs::new()?);
-    let keystore = Arc::new(EspNvsStorage::new_default(default_nvs.clone(), "f", true)?);
-    Ok((default_nvs, keystore))
+    let nvs_storage = EspNvsStorage::new_default(default_nvs.clone(), "f", true)?;
+    Ok((default_nvs, keystore::shared(nvs_storage)))
 }
 
-/// Sets the value of `ap_ssid` as a combination of this
-/// device MAC address and a custom string.
-fn configure_access_point_ssid(ap_ssid: &mut String) -> anyhow::Result<()> {
+/// Reads the MAC address of the access point interface.
+fn read_mac() -> anyhow::Result<[u8; 6]> {
     let mut mac = [0u8; 6];
     esp!(unsafe {
         esp_idf_sys::esp_read_mac(
@@ -212,6 +183,12 @@ fn configure_access_point_ssid(ap_ssid: &mut String) -> anyhow::Result<()> {
             esp_idf_sys::esp_mac_type_t_ESP_MAC_WIFI_SOFTAP,
         )
     })?;
+    Ok(mac)
+}
+
+/// Sets the value of `ap_ssid` as a combination of this
+/// device MAC address and a custom string.
+fn configure_access_point_ssid(ap_ssid: &mut String, mac: &[u8; 6]) -> anyhow::Result<()> {
     ap_ssid.push_str("SEM-");
     ap_ssid.push_str(
         format!(
@@ -267,137 +244,83 @@ fn init_access_point(
     Ok(wifi)
 }
 
-/// Initilizes the web server and registers some handlers.
-fn init_web_server(storage_lock: Arc<Mutex<CTStorage>>) -> anyhow::Result<EspHttpServer> {
-    let mut server = EspHttpServer::new(&Default::default())?;
-
-    server.handle_get("/", |_req, res| {
-        res.send_str(&templated_webpage("You should not be here."))?;
-        log::info!("Request handler done");
-        Ok(())
-    })?;
+/// Adapts the writer of an http response to `std::io::Write`, which the shared handlers use.
+struct ResponseWriter<W>(W);
 
-    let handler_storage_lock = storage_lock.clone();
-    server.handle_get("/telemetry", move |_req, res| {
-        log::info!("Handling telemetry reqeuest.");
-        let mut writer = res.into_writer()?;
-        {
-            let mut ct_storage = match handler_storage_lock.lock() {
-                Ok(gaurd) => gaurd,
-                Err(poisoned) => poisoned.into_inner(),
-            };
-            ct_storage.send_readings_shards(&mut writer)?;
-        }
-        log::info!("Request handler done");
-        Ok(())
-    })?;
+impl<W: SvcWrite> std::io::Write for ResponseWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .write(buf)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{:?}", e)))
+    }
 
-    let handler_storage_lock = storage_lock.clone();
-    server.handle_get("/powerloss_log", move |_req, res| {
-        log::info!("Handling powerloss log reqeuest.");
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0
+            .flush()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{:?}", e)))
+    }
+}
 
-        let mut writer = res.into_writer()?;
-        {
-            let mut ct_storage = match handler_storage_lock.lock() {
-                Ok(gaurd) => gaurd,
-                Err(poisoned) => poisoned.into_inner(),
-            };
-            ct_storage.send_powerloss_log(&mut writer)?;
-        }
-        log::info!("Request handler done");
-        Ok(())
-    })?;
-    let handler_storage_lock = storage_lock.clone();
-    server.handle_post("/time", move |mut req, _res| {
-        log::info!("Handling time post request.");
-        let mut buf = [0_u8; std::mem::size_of::<u64>()];
-        let mut size = 0;
-        let mut reader = req.reader();
-        loop {
-            let n = reader.read(&mut buf[size..])?;
-            if n == 0 {
-                break;
+/// Builds a handler that reads the request body and passes it to `web::handle`.
+macro_rules! shared_handler {
+    ($context:expr, $method:expr, $path:expr) => {{
+        let context = $context.clone();
+        let (method, path) = ($method, $path);
+        move |mut req, mut res| {
+            let query = req.query_string().to_string();
+            let too_large = matches!(req.content_len(), Some(len) if len > MAX_REQUEST_BODY_SIZE);
+            // one byte more than the limit, to tell a body that fits from one that is cut off.
+            let mut body = vec![0_u8; MAX_REQUEST_BODY_SIZE + 1];
+            let mut size = 0;
+            if !too_large {
+                let mut reader = req.reader();
+                while size < body.len() {
+                    let n = reader.read(&mut body[size..])?;
+                    if n == 0 {
+                        break;
+                    }
+                    size += n;
+                }
             }
-            size += n;
-        }
-        println!("Read {} bytes of data", size);
-
-        // Convert raw bytes to time. Store it and update system time.
-        let time = u64::from_le_bytes(buf);
-        {
-            let mut ct_storage = match handler_storage_lock.lock() {
-                Ok(gaurd) => gaurd,
-                Err(poisoned) => poisoned.into_inner(),
-            };
-            ct_storage.store_time(time)?;
-            println!("Response: {}", time);
-        }
-        set_system_time(time)?;
-
-        log::info!("Request handler done");
-        Ok(())
-    })?;
 
-    let handler_storage_lock = storage_lock.clone();
-    server.handle_post("/token", move |mut req, _res| {
-        log::info!("Handling token post request.");
-        let mut buf = [0_u8; ACCESS_TOKEN_SIZE];
-        let mut size = 0;
-        let mut reader = req.reader();
-        loop {
-            let n = reader.read(&mut buf[size..])?;
-            if n == 0 {
-                break;
-            }
-            size += n;
-        }
-        println!("Read {} bytes of data", size);
-
-        let token = buf;
-        // Store the given token in storage.
-        {
-            let mut ct_storage = match handler_storage_lock.lock() {
-                Ok(gaurd) => gaurd,
-                Err(poisoned) => poisoned.into_inner(),
+            let response = if too_large || size > MAX_REQUEST_BODY_SIZE {
+                warn!(
+                    "Refused a request to {} with a body that is too long.",
+                    path
+                );
+                web::Response::payload_too_large()
+            } else {
+                web::handle(&context, method, path, &query, &body[..size])?
             };
-            ct_storage.store_token(&token)?;
-            println!("Response: {}", std::str::from_utf8(&token)?);
+            res.set_status(response.status);
+            res.set_header("Content-Type", response.content_type);
+            match response.body {
+                web::Body::Empty => {}
+                web::Body::Bytes(bytes) => {
+                    res.send_bytes(&bytes)?;
+                }
+                web::Body::Stream(stream) => {
+                    let mut writer = ResponseWriter(res.into_writer()?);
+                    stream(&mut writer)?;
+                }
+            }
+            log::info!("Request handler done");
+            Ok(())
         }
-        log::info!("Request handler done");
-        Ok(())
-    })?;
+    }};
+}
 
-    let handler_storage_lock = storage_lock.clone();
-    server.handle_get("/token", move |_req, res| {
-        log::info!("Handling token get request.");
-        {
-            let mut ct_storage = match handler_storage_lock.lock() {
-                Ok(gaurd) => gaurd,
-                Err(poisoned) => poisoned.into_inner(),
-            };
-            let token_buf = ct_storage.retrieve_token()?;
-            res.send_bytes(&token_buf)?;
-            log::info!("Sent token: {}", std::str::from_utf8(&token_buf)?);
-        }
-        log::info!("Request handler done");
-        Ok(())
-    })?;
+/// Initilizes the web server and registers some handlers.
+fn init_web_server(context: Arc<web::Context>) -> anyhow::Result<EspHttpServer> {
+    let mut server = EspHttpServer::new(&Default::default())?;
 
-    let handler_storage_lock = storage_lock.clone();
-    server.handle_get("/reset", move |_req, _res| {
-        log::info!("Handling reset request.");
-        {
-            let mut ct_storage = match handler_storage_lock.lock() {
-                Ok(gaurd) => gaurd,
-                Err(poisoned) => poisoned.into_inner(),
-            };
-            ct_storage.reset_storage()?;
-        }
-        log::info!("Request handler done");
-        Ok(())
-    })?;
+    for &(method, path) in web::ROUTES {
+        match method {
+            Method::Get => server.handle_get(path, shared_handler!(context, method, path))?,
+            Method::Post => server.handle_post(path, shared_handler!(context, method, path))?,
+        };
+    }
 
-    let handler_storage_lock = storage_lock.clone();
     server.handle_post("/ota", move |mut req, mut res| {
         log::info!("Handling ota post request.");
         let version_str_wrapper = req.header("X-FIRMWARE-VERSION");
@@ -425,58 +348,11 @@ fn init_web_server(storage_lock: Arc<Mutex<CTStorage>>) -> anyhow::Result<EspHtt
         Ok(())
     })?;
 
-    let handler_storage_lock = storage_lock.clone();
-    server.handle_get("/version", move |_req, res| {
-        log::info!("Handling version get request.");
-        res.send_str(&VERSION.to_string())?;
-        log::info!("Sent version: {}", VERSION);
-        log::info!("Request handler done");
-        Ok(())
-    })?;
-
     Ok(server)
 }
 
-fn templated_webpage(content: impl AsRef<str>) -> String {
-    format!(
-        r#"
-<!DOCTYPE html>
-<html>
-    <head>
-        <meta charset="utf-8">
-        <title>esp-rs web server</title>
-    </head>
-    <body>
-        {}
-    </body>
-</html>
-"#,
-        content.as_ref()
-    )
-}
-
 /// Calculates the RMS value for a given slice of samples.
 #[allow(dead_code)]
 fn calc_rms(samples: &[f32], size: usize) -> f32 {
     (samples[..size].iter().fold(0.0, |sum, &x| sum + (x * x)) / size as f32).sqrt()
 }
-
-fn now() -> Duration {
-    let mut tv_now: timeval = Default::default();
-
-    unsafe {
-        gettimeofday(&mut tv_now as *mut _, core::ptr::null_mut());
-    }
-
-    Duration::from_micros(tv_now.tv_sec as u64 * 1000000_u64 + tv_now.tv_usec as u64)
-}
-
-fn set_system_time(time_milis: u64) -> anyhow::Result<()> {
-    let mut tv_now: timeval = timeval {
-        tv_sec: (time_milis / 1000) as i32,
-        tv_usec: 0,
-    };
-    println!("settimeofday milis: {} sec: {}", time_milis, tv_now.tv_sec);
-    esp!(unsafe { settimeofday(&mut tv_now as *mut _, core::ptr::null_mut()) })?;
-    Ok(())
-}
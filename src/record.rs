@@ -0,0 +1,911 @@
+//! On-flash formats of the readings shards and the boot log.
+//!
+//! Every shard starts with a header, all integers are little-endian:
+//!
+//! | offset | size | field                                              |
+//! |--------|------|----------------------------------------------------|
+//! | 0      | 4    | magic, `SEMR`                                      |
+//! | 4      | 2    | format version                                     |
+//! | 6      | 2    | header size, readers skip anything they don't know |
+//! | 8      | 2    | record size, including the crc                     |
+//! | 10     | 4    | firmware `VERSION` that created the shard          |
+//! | 14     | 6    | MAC address of the device                          |
+//!
+//! followed by records of `record size` bytes each. A record is its fields followed by the CRC-32
+//! of those fields:
+//!
+//! | offset | size | field                     |
+//! |--------|------|---------------------------|
+//! | 0      | 2    | CT id                     |
+//! | 2      | 4    | real power                |
+//! | 6      | 4    | apparent power            |
+//! | 10     | 4    | Irms                      |
+//! | 14     | 4    | Vrms                      |
+//! | 18     | 4    | kWh                       |
+//! | 22     | 8    | timestamp (ms)            |
+//! | 30     | 8    | sequence                  |
+//! | 38     | 4    | boot counter              |
+//! | 42     | 8    | uptime (ms)               |
+//! | 50     | 4    | reactive power            |
+//! | 54     | 4    | power factor              |
+//! | 58     | 4    | import kWh                |
+//! | 62     | 4    | export kWh                |
+//! | 66     | 4    | frequency (Hz)            |
+//! | 70     | 4    | voltage THD (%)           |
+//! | 74     | 4    | current THD (%)           |
+//! | 78     | 12   | real power min, max, last |
+//! | 90     | 12   | Irms min, max, last       |
+//! | 102    | 12   | Vrms min, max, last       |
+//! | 114    | 4    | measurements (u32)        |
+//! | 118    | 8    | lifetime import kWh (f64) |
+//! | 126    | 8    | lifetime export kWh (f64) |
+//! | 134    | 2    | flags                     |
+//! | 136    | 4    | demand (kW)               |
+//! | 140    | 4    | peak demand of the month  |
+//! | 144    | 8    | time of the peak (ms)     |
+//! | 152    | 64   | tariff import, export kWh |
+//! | 216    | 4    | CRC-32                    |
+//!
+//! New fields are only ever appended, so a reader can decode the fields it knows from records of
+//! any size, fields a record doesn't have read as zero. Shards written before the header existed
+//! are 30 byte records without a crc. Sequence numbers and boot counters start at 1, so records
+//! written before they were added have 0 for both. The same goes for the frequency and the THD,
+//! which is also 0 if the firmware was built without the `harmonics` feature.
+//!
+//! Real power is positive when it is imported from the grid and negative when it is exported, kWh is
+//! the net energy, i.e. import kWh - export kWh. Records written before the direction was known
+//! have the absolute real power and all of their energy is read as import.
+//!
+//! The powers, Irms, Vrms, frequency and THD are means over the save period, each measurement
+//! weighted by the time since the previous one, which is also the time its energy is counted for.
+//! Records written before the minimum, maximum and last values were stored have the mean for all
+//! three, and 0 measurements. The lifetime energy registers are 0 in records written before they
+//! were stored.
+//!
+//! The flags tell how the save period of a record was timed, see `Record::PARTIAL` and
+//! `Record::ALIGNED`. Records written before they were stored have none set.
+//!
+//! The demand is the one of the last whole demand window when the record was stored, and the peak
+//! demand the highest of the month up to then, see `demand`. Both are 0 until there is one, and in
+//! records written before they were stored.
+//!
+//! The tariff registers are the lifetime imported and exported kWh by tariff, an f64 pair for each
+//! of the `MAX_TARIFFS` tariffs in order, see `tariffs`. They are 0 in records written before they
+//! were stored.
+//!
+//! The boot log is a list of boot events, one for every time the device started:
+//!
+//! | offset | size | field                                                       |
+//! |--------|------|-------------------------------------------------------------|
+//! | 0      | 2    | entry size, readers skip anything they don't know           |
+//! | 2      | 2    | ESP reset reason, `esp_reset_reason_t`                      |
+//! | 4      | 4    | firmware `VERSION`                                          |
+//! | 8      | 4    | boot counter                                                |
+//! | 12     | 8    | time the clock was restored to (ms)                         |
+//! | 20     | 8    | time of the last successful `store_time` (ms), 0 if unknown |
+//! | 28     | 8    | uptime when the event was logged (ms)                       |
+//!
+//! The event log is a list of the voltage sags, swells and interruptions that were detected:
+//!
+//! | offset | size | field                                                            |
+//! |--------|------|------------------------------------------------------------------|
+//! | 0      | 2    | entry size, readers skip anything they don't know                |
+//! | 2      | 2    | CT id                                                            |
+//! | 4      | 2    | kind, `EventKind`: 1 sag, 2 swell, 3 interruption                |
+//! | 6      | 4    | boot counter                                                     |
+//! | 10     | 8    | start time (ms)                                                  |
+//! | 18     | 4    | duration (ms)                                                    |
+//! | 22     | 4    | lowest cycle Vrms of a sag or interruption, highest of a swell   |
+//!
+//! The alarm log is a list of the alarms that triggered or cleared:
+//!
+//! | offset | size | field                                               |
+//! |--------|------|-----------------------------------------------------|
+//! | 0      | 2    | entry size, readers skip anything they don't know   |
+//! | 2      | 2    | alarm rule id                                       |
+//! | 4      | 2    | CT id                                               |
+//! | 6      | 2    | quantity, `Quantity`                                |
+//! | 8      | 2    | 1 if the alarm triggered, 0 if it cleared           |
+//! | 10     | 4    | boot counter                                        |
+//! | 14     | 8    | time of the measurement (ms)                        |
+//! | 22     | 4    | measured value                                      |
+//! | 26     | 4    | min of the rule, NaN if it has none                 |
+//! | 30     | 4    | max of the rule, NaN if it has none                 |
+
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::alarms::Quantity;
+use crate::ct::{CTReading, MinMaxLast};
+use crate::events::EventKind;
+use crate::utils::*;
+use crate::{
+    ALARM_EVENT_SIZE, BOOT_EVENT_SIZE, CT_READING_SIZE, LEGACY_CT_READING_SIZE, MAX_TARIFFS,
+    SHARD_FORMAT_VERSION, SHARD_HEADER_SIZE, SHARD_MAGIC, VERSION, VOLTAGE_EVENT_SIZE,
+};
+
+const CRC_SIZE: usize = std::mem::size_of::<u32>();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShardHeader {
+    pub format_version: u16,
+    pub header_size: u16,
+    pub record_size: u16,
+    pub firmware_version: u32,
+    pub mac: [u8; 6],
+}
+
+/// A reading of one CT as stored in a shard.
+#[derive(Debug, Default)]
+pub struct Record {
+    pub ct_id: u16,
+    /// Increases by one with every stored record, used as the download cursor.
+    pub seq: u64,
+    /// The boot the record was stored in.
+    pub boot_count: u32,
+    pub reading: CTReading,
+    /// The lifetime energy registers of the CT, including this record.
+    pub lifetime_import_kwh: f64,
+    pub lifetime_export_kwh: f64,
+    /// The lifetime tariff registers of the CT, including this record.
+    pub lifetime_tariff_import_kwh: [f64; MAX_TARIFFS],
+    pub lifetime_tariff_export_kwh: [f64; MAX_TARIFFS],
+    /// How the save period was timed, a combination of `Record::PARTIAL` and `Record::ALIGNED`.
+    pub flags: u16,
+}
+
+/// An entry of the boot log.
+#[derive(Debug, Default)]
+pub struct BootEvent {
+    pub reset_reason: u16,
+    pub firmware_version: u32,
+    pub boot_count: u32,
+    pub restored_time: u64,
+    pub last_stored_time: u64,
+    pub uptime: u64,
+}
+
+/// An entry of the event log.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VoltageEvent {
+    pub ct_id: u16,
+    /// An `EventKind`.
+    pub kind: u16,
+    pub boot_count: u32,
+    /// In ms since the epoch.
+    pub start_time: u64,
+    /// In ms.
+    pub duration: u32,
+    /// The lowest cycle Vrms of a sag or interruption, the highest of a swell.
+    pub extreme_voltage: f32,
+}
+
+/// An entry of the alarm log.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AlarmEvent {
+    pub rule_id: u16,
+    pub ct_id: u16,
+    /// A `Quantity`.
+    pub quantity: u16,
+    /// Whether the alarm triggered or cleared.
+    pub active: bool,
+    pub boot_count: u32,
+    /// In ms since the epoch.
+    pub time: u64,
+    pub value: f32,
+    /// The range of the rule, NaN for an open end.
+    pub min: f32,
+    pub max: f32,
+}
+
+impl ShardHeader {
+    /// The header of shards written by this firmware.
+    pub fn current(mac: [u8; 6]) -> Self {
+        ShardHeader {
+            format_version: SHARD_FORMAT_VERSION,
+            header_size: SHARD_HEADER_SIZE as u16,
+            record_size: CT_READING_SIZE as u16,
+            firmware_version: VERSION,
+            mac,
+        }
+    }
+
+    /// Stands in for the missing header of shards written before the format was versioned.
+    pub fn legacy() -> Self {
+        ShardHeader {
+            format_version: 0,
+            header_size: 0,
+            record_size: LEGACY_CT_READING_SIZE as u16,
+            firmware_version: 0,
+            mac: [0; 6],
+        }
+    }
+
+    /// Whether records with this header can be appended to a shard with the `other` header.
+    pub fn is_compatible(&self, other: &ShardHeader) -> bool {
+        self.format_version == other.format_version && self.record_size == other.record_size
+    }
+
+    pub fn to_le_bytes(&self) -> anyhow::Result<[u8; SHARD_HEADER_SIZE]> {
+        let mut buf = [0_u8; SHARD_HEADER_SIZE];
+        buf[..SHARD_MAGIC.len()].copy_from_slice(&SHARD_MAGIC);
+        let mut pos = SHARD_MAGIC.len();
+        pos += add_u16_to_buf(&self.format_version, &mut buf, &pos)?;
+        pos += add_u16_to_buf(&self.header_size, &mut buf, &pos)?;
+        pos += add_u16_to_buf(&self.record_size, &mut buf, &pos)?;
+        pos += add_u32_to_buf(&self.firmware_version, &mut buf, &pos)?;
+        buf[pos..pos + self.mac.len()].copy_from_slice(&self.mac);
+        Ok(buf)
+    }
+
+    /// Reads the header at the start of a shard and leaves `reader` at the first record.
+    ///
+    /// Shards that don't start with the magic are legacy shards and are rewound to the start.
+    pub fn read_from(reader: &mut (impl Read + Seek)) -> anyhow::Result<ShardHeader> {
+        let mut buf = [0_u8; SHARD_HEADER_SIZE];
+        reader.seek(SeekFrom::Start(0))?;
+        if reader.read_exact(&mut buf).is_err() || buf[..SHARD_MAGIC.len()] != SHARD_MAGIC {
+            reader.seek(SeekFrom::Start(0))?;
+            return Ok(ShardHeader::legacy());
+        }
+        let mut fields = FieldReader::new(&buf[SHARD_MAGIC.len()..]);
+        let header = ShardHeader {
+            format_version: fields.u16(),
+            header_size: fields.u16(),
+            record_size: fields.u16(),
+            firmware_version: fields.u32(),
+            mac: fields.bytes(),
+        };
+        if (header.record_size as usize) <= CRC_SIZE {
+            anyhow::bail!(
+                "Invalid record size {} in shard header.",
+                header.record_size
+            );
+        }
+        if (header.header_size as usize) < SHARD_HEADER_SIZE {
+            anyhow::bail!(
+                "Invalid header size {} in shard header.",
+                header.header_size
+            );
+        }
+        reader.seek(SeekFrom::Start(header.header_size as u64))?;
+        Ok(header)
+    }
+
+    fn has_crc(&self) -> bool {
+        self.format_version > 0
+    }
+}
+
+impl Record {
+    pub fn to_le_bytes(&self) -> anyhow::Result<[u8; CT_READING_SIZE]> {
+        let mut buf = [0_u8; CT_READING_SIZE];
+        let mut pos = 0;
+        pos += add_u16_to_buf(&self.ct_id, &mut buf, &pos)?;
+        pos += add_f32_to_buf(&self.reading.real_power, &mut buf, &pos)?;
+        pos += add_f32_to_buf(&self.reading.apparent_power, &mut buf, &pos)?;
+        pos += add_f32_to_buf(&self.reading.i_rms, &mut buf, &pos)?;
+        pos += add_f32_to_buf(&self.reading.v_rms, &mut buf, &pos)?;
+        pos += add_f32_to_buf(&self.reading.kwh, &mut buf, &pos)?;
+        pos += add_u64_to_buf(&self.reading.timestamp, &mut buf, &pos)?;
+        pos += add_u64_to_buf(&self.seq, &mut buf, &pos)?;
+        pos += add_u32_to_buf(&self.boot_count, &mut buf, &pos)?;
+        pos += add_u64_to_buf(&self.reading.uptime, &mut buf, &pos)?;
+        pos += add_f32_to_buf(&self.reading.reactive_power, &mut buf, &pos)?;
+        pos += add_f32_to_buf(&self.reading.power_factor, &mut buf, &pos)?;
+        pos += add_f32_to_buf(&self.reading.import_kwh, &mut buf, &pos)?;
+        pos += add_f32_to_buf(&self.reading.export_kwh, &mut buf, &pos)?;
+        pos += add_f32_to_buf(&self.reading.frequency, &mut buf, &pos)?;
+        pos += add_f32_to_buf(&self.reading.v_thd, &mut buf, &pos)?;
+        pos += add_f32_to_buf(&self.reading.i_thd, &mut buf, &pos)?;
+        for stats in [
+            self.reading.real_power_stats,
+            self.reading.i_rms_stats,
+            self.reading.v_rms_stats,
+        ] {
+            pos += add_f32_to_buf(&stats.min, &mut buf, &pos)?;
+            pos += add_f32_to_buf(&stats.max, &mut buf, &pos)?;
+            pos += add_f32_to_buf(&stats.last, &mut buf, &pos)?;
+        }
+        pos += add_u32_to_buf(&self.reading.samples, &mut buf, &pos)?;
+        pos += add_f64_to_buf(&self.lifetime_import_kwh, &mut buf, &pos)?;
+        pos += add_f64_to_buf(&self.lifetime_export_kwh, &mut buf, &pos)?;
+        pos += add_u16_to_buf(&self.flags, &mut buf, &pos)?;
+        pos += add_f32_to_buf(&self.reading.demand, &mut buf, &pos)?;
+        pos += add_f32_to_buf(&self.reading.peak_demand, &mut buf, &pos)?;
+        pos += add_u64_to_buf(&self.reading.peak_demand_time, &mut buf, &pos)?;
+        for t in 0..MAX_TARIFFS {
+            pos += add_f64_to_buf(&self.lifetime_tariff_import_kwh[t], &mut buf, &pos)?;
+            pos += add_f64_to_buf(&self.lifetime_tariff_export_kwh[t], &mut buf, &pos)?;
+        }
+        let crc = crc32(&buf[..pos]);
+        add_u32_to_buf(&crc, &mut buf, &pos)?;
+        Ok(buf)
+    }
+
+    /// The record in the layout of shards without a header, which `/telemetry` has always sent:
+    /// the CT id, real power, apparent power, Irms, Vrms, kWh and the timestamp.
+    pub fn to_legacy_le_bytes(&self) -> anyhow::Result<[u8; LEGACY_CT_READING_SIZE]> {
+        let mut buf = [0_u8; LEGACY_CT_READING_SIZE];
+        let mut pos = 0;
+        pos += add_u16_to_buf(&self.ct_id, &mut buf, &pos)?;
+        pos += add_f32_to_buf(&self.reading.real_power, &mut buf, &pos)?;
+        pos += add_f32_to_buf(&self.reading.apparent_power, &mut buf, &pos)?;
+        pos += add_f32_to_buf(&self.reading.i_rms, &mut buf, &pos)?;
+        pos += add_f32_to_buf(&self.reading.v_rms, &mut buf, &pos)?;
+        pos += add_f32_to_buf(&self.reading.kwh, &mut buf, &pos)?;
+        add_u64_to_buf(&self.reading.timestamp, &mut buf, &pos)?;
+        Ok(buf)
+    }
+
+    /// Decodes a record of `header.record_size` bytes from a shard with the given header.
+    pub fn from_le_bytes(header: &ShardHeader, buf: &[u8]) -> anyhow::Result<Record> {
+        let fields_buf = if header.has_crc() {
+            let (fields_buf, crc_buf) = buf.split_at(buf.len() - CRC_SIZE);
+            if crc32(fields_buf) != FieldReader::new(crc_buf).u32() {
+                anyhow::bail!("Record checksum mismatch.");
+            }
+            fields_buf
+        } else {
+            buf
+        };
+        let mut fields = FieldReader::new(fields_buf);
+        let ct_id = fields.u16();
+        let mut reading = CTReading {
+            real_power: fields.f32(),
+            apparent_power: fields.f32(),
+            i_rms: fields.f32(),
+            v_rms: fields.f32(),
+            kwh: fields.f32(),
+            timestamp: fields.u64(),
+            ..Default::default()
+        };
+        let seq = fields.u64();
+        let boot_count = fields.u32();
+        reading.uptime = fields.u64();
+        if fields.is_empty() {
+            reading.power_factor = if reading.apparent_power > 0.0 {
+                reading.real_power / reading.apparent_power
+            } else {
+                0.0
+            };
+            reading.import_kwh = reading.kwh;
+        } else {
+            reading.reactive_power = fields.f32();
+            reading.power_factor = fields.f32();
+            reading.import_kwh = fields.f32();
+            reading.export_kwh = fields.f32();
+        }
+        reading.frequency = fields.f32();
+        reading.v_thd = fields.f32();
+        reading.i_thd = fields.f32();
+        if fields.is_empty() {
+            reading.real_power_stats = MinMaxLast::all(reading.real_power);
+            reading.i_rms_stats = MinMaxLast::all(reading.i_rms);
+            reading.v_rms_stats = MinMaxLast::all(reading.v_rms);
+        } else {
+            reading.real_power_stats = fields.min_max_last();
+            reading.i_rms_stats = fields.min_max_last();
+            reading.v_rms_stats = fields.min_max_last();
+            reading.samples = fields.u32();
+        }
+        let lifetime_import_kwh = fields.f64();
+        let lifetime_export_kwh = fields.f64();
+        let flags = fields.u16();
+        reading.demand = fields.f32();
+        reading.peak_demand = fields.f32();
+        reading.peak_demand_time = fields.u64();
+        let mut lifetime_tariff_import_kwh = [0.0; MAX_TARIFFS];
+        let mut lifetime_tariff_export_kwh = [0.0; MAX_TARIFFS];
+        for t in 0..MAX_TARIFFS {
+            lifetime_tariff_import_kwh[t] = fields.f64();
+            lifetime_tariff_export_kwh[t] = fields.f64();
+        }
+        Ok(Record {
+            ct_id,
+            seq,
+            boot_count,
+            reading,
+            lifetime_import_kwh,
+            lifetime_export_kwh,
+            lifetime_tariff_import_kwh,
+            lifetime_tariff_export_kwh,
+            flags,
+        })
+    }
+}
+
+impl BootEvent {
+    pub fn to_le_bytes(&self) -> anyhow::Result<[u8; BOOT_EVENT_SIZE]> {
+        let mut buf = [0_u8; BOOT_EVENT_SIZE];
+        let mut pos = 0;
+        pos += add_u16_to_buf(&(BOOT_EVENT_SIZE as u16), &mut buf, &pos)?;
+        pos += add_u16_to_buf(&self.reset_reason, &mut buf, &pos)?;
+        pos += add_u32_to_buf(&self.firmware_version, &mut buf, &pos)?;
+        pos += add_u32_to_buf(&self.boot_count, &mut buf, &pos)?;
+        pos += add_u64_to_buf(&self.restored_time, &mut buf, &pos)?;
+        pos += add_u64_to_buf(&self.last_stored_time, &mut buf, &pos)?;
+        add_u64_to_buf(&self.uptime, &mut buf, &pos)?;
+        Ok(buf)
+    }
+}
+
+impl VoltageEvent {
+    pub fn to_le_bytes(&self) -> anyhow::Result<[u8; VOLTAGE_EVENT_SIZE]> {
+        let mut buf = [0_u8; VOLTAGE_EVENT_SIZE];
+        let mut pos = 0;
+        pos += add_u16_to_buf(&(VOLTAGE_EVENT_SIZE as u16), &mut buf, &pos)?;
+        pos += add_u16_to_buf(&self.ct_id, &mut buf, &pos)?;
+        pos += add_u16_to_buf(&self.kind, &mut buf, &pos)?;
+        pos += add_u32_to_buf(&self.boot_count, &mut buf, &pos)?;
+        pos += add_u64_to_buf(&self.start_time, &mut buf, &pos)?;
+        pos += add_u32_to_buf(&self.duration, &mut buf, &pos)?;
+        add_f32_to_buf(&self.extreme_voltage, &mut buf, &pos)?;
+        Ok(buf)
+    }
+
+    /// Decodes an entry, `buf` starts with the entry size.
+    pub fn from_le_bytes(buf: &[u8]) -> VoltageEvent {
+        let mut fields = FieldReader::new(buf);
+        fields.u16();
+        VoltageEvent {
+            ct_id: fields.u16(),
+            kind: fields.u16(),
+            boot_count: fields.u32(),
+            start_time: fields.u64(),
+            duration: fields.u32(),
+            extreme_voltage: fields.f32(),
+        }
+    }
+
+    /// The event as JSON, e.g.
+    /// `{"ct":1,"kind":"sag","start":1673000000000,"duration":120,"extreme_voltage":181.2,"boot_count":3}`.
+    pub fn to_json(&self) -> String {
+        format!(
+            r#"{{"ct":{},"kind":"{}","start":{},"duration":{},"extreme_voltage":{},"boot_count":{}}}"#,
+            self.ct_id,
+            EventKind::from_u16(self.kind)
+                .map(|kind| kind.name())
+                .unwrap_or("unknown"),
+            self.start_time,
+            self.duration,
+            json_number(self.extreme_voltage),
+            self.boot_count
+        )
+    }
+}
+
+impl AlarmEvent {
+    pub fn to_le_bytes(&self) -> anyhow::Result<[u8; ALARM_EVENT_SIZE]> {
+        let mut buf = [0_u8; ALARM_EVENT_SIZE];
+        let mut pos = 0;
+        pos += add_u16_to_buf(&(ALARM_EVENT_SIZE as u16), &mut buf, &pos)?;
+        pos += add_u16_to_buf(&self.rule_id, &mut buf, &pos)?;
+        pos += add_u16_to_buf(&self.ct_id, &mut buf, &pos)?;
+        pos += add_u16_to_buf(&self.quantity, &mut buf, &pos)?;
+        pos += add_u16_to_buf(&(self.active as u16), &mut buf, &pos)?;
+        pos += add_u32_to_buf(&self.boot_count, &mut buf, &pos)?;
+        pos += add_u64_to_buf(&self.time, &mut buf, &pos)?;
+        pos += add_f32_to_buf(&self.value, &mut buf, &pos)?;
+        pos += add_f32_to_buf(&self.min, &mut buf, &pos)?;
+        add_f32_to_buf(&self.max, &mut buf, &pos)?;
+        Ok(buf)
+    }
+
+    /// Decodes an entry, `buf` starts with the entry size.
+    pub fn from_le_bytes(buf: &[u8]) -> AlarmEvent {
+        let mut fields = FieldReader::new(buf);
+        fields.u16();
+        AlarmEvent {
+            rule_id: fields.u16(),
+            ct_id: fields.u16(),
+            quantity: fields.u16(),
+            active: fields.u16() != 0,
+            boot_count: fields.u32(),
+            time: fields.u64(),
+            value: fields.f32(),
+            min: fields.f32(),
+            max: fields.f32(),
+        }
+    }
+
+    /// The event as JSON, e.g.
+    /// `{"rule":1,"ct":1,"quantity":"i_rms","state":"triggered","time":1673000000000,"value":33.1,"min":null,"max":32,"boot_count":3}`.
+    pub fn to_json(&self) -> String {
+        format!(
+            r#"{{"rule":{},"ct":{},"quantity":"{}","state":"{}","time":{},"value":{},"min":{},"max":{},"boot_count":{}}}"#,
+            self.rule_id,
+            self.ct_id,
+            Quantity::from_u16(self.quantity)
+                .map(|quantity| quantity.name())
+                .unwrap_or("unknown"),
+            if self.active { "triggered" } else { "cleared" },
+            self.time,
+            json_number(self.value),
+            json_number(self.min),
+            json_number(self.max),
+            self.boot_count
+        )
+    }
+
+    /// The event as a ThingsBoard telemetry object, e.g.
+    /// `{"ts":1673000000000,"values":{"ct1_alarm1":true,"ct1_alarm1_value":33.1}}`, with the same
+    /// keys as `AlarmTable::to_thingsboard_attributes`.
+    pub fn to_thingsboard_json(&self) -> String {
+        format!(
+            r#"{{"ts":{},"values":{{"ct{ct}_alarm{rule}":{},"ct{ct}_alarm{rule}_value":{}}}}}"#,
+            self.time,
+            self.active,
+            json_number(self.value),
+            ct = self.ct_id,
+            rule = self.rule_id,
+        )
+    }
+}
+
+impl Record {
+    /// The save period didn't run its full length, because it began at boot, the clock was changed
+    /// or the save period was.
+    pub const PARTIAL: u16 = 1;
+    /// The save period ended on a multiple of its length since the epoch, e.g. at the top of the
+    /// hour, rather than a save period after the previous one.
+    pub const ALIGNED: u16 = 1 << 1;
+
+    pub fn is_partial(&self) -> bool {
+        self.flags & Record::PARTIAL != 0
+    }
+
+    pub fn is_aligned(&self) -> bool {
+        self.flags & Record::ALIGNED != 0
+    }
+
+    /// The record as a ThingsBoard telemetry object, e.g.
+    /// `{"ts":1673000000000,"values":{"ct1_real_power":10.5,...}}`.
+    ///
+    /// The keys are prefixed with the CT id, so the readings of all CTs can be sent to the same
+    /// device. The tariff registers are `ct1_tariff1_import_kwh` and so on, for every tariff. The
+    /// THD is only included with the `harmonics` feature.
+    pub fn to_thingsboard_json(&self) -> String {
+        let r = &self.reading;
+        let mut json = format!(
+            concat!(
+                r#"{{"ts":{},"values":{{"ct{id}_real_power":{},"ct{id}_apparent_power":{},"#,
+                r#""ct{id}_reactive_power":{},"ct{id}_power_factor":{},"ct{id}_i_rms":{},"#,
+                r#""ct{id}_v_rms":{},"ct{id}_frequency":{},"ct{id}_kwh":{},"ct{id}_import_kwh":{},"#,
+                r#""ct{id}_export_kwh":{},"ct{id}_real_power_min":{},"ct{id}_real_power_max":{},"#,
+                r#""ct{id}_real_power_last":{},"ct{id}_i_rms_min":{},"ct{id}_i_rms_max":{},"#,
+                r#""ct{id}_i_rms_last":{},"ct{id}_v_rms_min":{},"ct{id}_v_rms_max":{},"#,
+                r#""ct{id}_v_rms_last":{},"ct{id}_samples":{},"ct{id}_lifetime_import_kwh":{},"#,
+                r#""ct{id}_lifetime_export_kwh":{},"ct{id}_partial":{},"ct{id}_aligned":{},"#,
+                r#""ct{id}_demand":{},"ct{id}_peak_demand":{},"ct{id}_peak_demand_time":{}"#
+            ),
+            r.timestamp,
+            json_number(r.real_power),
+            json_number(r.apparent_power),
+            json_number(r.reactive_power),
+            json_number(r.power_factor),
+            json_number(r.i_rms),
+            json_number(r.v_rms),
+            json_number(r.frequency),
+            json_number(r.kwh),
+            json_number(r.import_kwh),
+            json_number(r.export_kwh),
+            json_number(r.real_power_stats.min),
+            json_number(r.real_power_stats.max),
+            json_number(r.real_power_stats.last),
+            json_number(r.i_rms_stats.min),
+            json_number(r.i_rms_stats.max),
+            json_number(r.i_rms_stats.last),
+            json_number(r.v_rms_stats.min),
+            json_number(r.v_rms_stats.max),
+            json_number(r.v_rms_stats.last),
+            r.samples,
+            self.lifetime_import_kwh,
+            self.lifetime_export_kwh,
+            self.is_partial(),
+            self.is_aligned(),
+            json_number(r.demand),
+            json_number(r.peak_demand),
+            r.peak_demand_time,
+            id = self.ct_id,
+        );
+        for t in 0..MAX_TARIFFS {
+            json.push_str(&format!(
+                r#","ct{id}_tariff{tariff}_import_kwh":{},"ct{id}_tariff{tariff}_export_kwh":{}"#,
+                self.lifetime_tariff_import_kwh[t],
+                self.lifetime_tariff_export_kwh[t],
+                id = self.ct_id,
+                tariff = t + 1,
+            ));
+        }
+        #[cfg(feature = "harmonics")]
+        json.push_str(&format!(
+            r#","ct{id}_v_thd":{},"ct{id}_i_thd":{}"#,
+            json_number(r.v_thd),
+            json_number(r.i_thd),
+            id = self.ct_id,
+        ));
+        json.push_str("}}");
+        json
+    }
+}
+
+impl Record {
+    /// The columns of `to_csv`.
+    #[cfg(not(feature = "harmonics"))]
+    pub const CSV_HEADER: &'static str =
+        "time,ct_id,real_power,apparent_power,i_rms,v_rms,kwh,power_factor,reactive_power,import_kwh,export_kwh,frequency,real_power_min,real_power_max,real_power_last,i_rms_min,i_rms_max,i_rms_last,v_rms_min,v_rms_max,v_rms_last,samples,lifetime_import_kwh,lifetime_export_kwh,partial,aligned,demand,peak_demand,peak_demand_time,tariff1_import_kwh,tariff1_export_kwh,tariff2_import_kwh,tariff2_export_kwh,tariff3_import_kwh,tariff3_export_kwh,tariff4_import_kwh,tariff4_export_kwh\r\n";
+    #[cfg(feature = "harmonics")]
+    pub const CSV_HEADER: &'static str =
+        "time,ct_id,real_power,apparent_power,i_rms,v_rms,kwh,power_factor,reactive_power,import_kwh,export_kwh,frequency,real_power_min,real_power_max,real_power_last,i_rms_min,i_rms_max,i_rms_last,v_rms_min,v_rms_max,v_rms_last,samples,lifetime_import_kwh,lifetime_export_kwh,partial,aligned,demand,peak_demand,peak_demand_time,tariff1_import_kwh,tariff1_export_kwh,tariff2_import_kwh,tariff2_export_kwh,tariff3_import_kwh,tariff3_export_kwh,tariff4_import_kwh,tariff4_export_kwh,v_thd,i_thd\r\n";
+
+    /// The record as a line of CSV, with the times in ISO-8601. The time of the peak demand is
+    /// empty if there is none.
+    pub fn to_csv(&self) -> String {
+        let r = &self.reading;
+        let mut line = format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            iso8601(r.timestamp),
+            self.ct_id,
+            r.real_power,
+            r.apparent_power,
+            r.i_rms,
+            r.v_rms,
+            r.kwh,
+            r.power_factor,
+            r.reactive_power,
+            r.import_kwh,
+            r.export_kwh,
+            r.frequency,
+            r.real_power_stats.min,
+            r.real_power_stats.max,
+            r.real_power_stats.last,
+            r.i_rms_stats.min,
+            r.i_rms_stats.max,
+            r.i_rms_stats.last,
+            r.v_rms_stats.min,
+            r.v_rms_stats.max,
+            r.v_rms_stats.last,
+            r.samples,
+            self.lifetime_import_kwh,
+            self.lifetime_export_kwh,
+            self.is_partial(),
+            self.is_aligned(),
+            r.demand,
+            r.peak_demand,
+            if r.peak_demand_time > 0 {
+                iso8601(r.peak_demand_time)
+            } else {
+                String::new()
+            }
+        );
+        for t in 0..MAX_TARIFFS {
+            line.push_str(&format!(
+                ",{},{}",
+                self.lifetime_tariff_import_kwh[t], self.lifetime_tariff_export_kwh[t]
+            ));
+        }
+        #[cfg(feature = "harmonics")]
+        line.push_str(&format!(",{},{}", r.v_thd, r.i_thd));
+        line.push_str("\r\n");
+        line
+    }
+}
+
+/// Reads little-endian fields one after another.
+///
+/// Fields past the end of the buffer read as zero, so records written with fewer fields than this
+/// firmware knows about can still be decoded.
+struct FieldReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> FieldReader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        FieldReader { buf, pos: 0 }
+    }
+
+    fn bytes<const N: usize>(&mut self) -> [u8; N] {
+        let mut bytes = [0_u8; N];
+        if self.pos + N <= self.buf.len() {
+            bytes.copy_from_slice(&self.buf[self.pos..self.pos + N]);
+        }
+        self.pos += N;
+        bytes
+    }
+
+    /// Whether all fields have been read.
+    fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_le_bytes(self.bytes())
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.bytes())
+    }
+
+    fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.bytes())
+    }
+
+    fn f32(&mut self) -> f32 {
+        f32::from_le_bytes(self.bytes())
+    }
+
+    fn f64(&mut self) -> f64 {
+        f64::from_le_bytes(self.bytes())
+    }
+
+    fn min_max_last(&mut self) -> MinMaxLast {
+        MinMaxLast {
+            min: self.f32(),
+            max: self.f32(),
+            last: self.f32(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const MAC: [u8; 6] = [0x24, 0x0a, 0xc4, 0x01, 0x02, 0x03];
+
+    fn record() -> Record {
+        let mut record = Record {
+            ct_id: 2,
+            seq: 42,
+            boot_count: 7,
+            lifetime_import_kwh: 1520.4,
+            lifetime_export_kwh: 12.7,
+            flags: Record::ALIGNED,
+            ..Default::default()
+        };
+        record.reading.real_power = 1150.5;
+        record.reading.apparent_power = 1200.0;
+        record.reading.reactive_power = 340.2;
+        record.reading.power_factor = 0.96;
+        record.reading.i_rms = 5.2;
+        record.reading.v_rms = 230.1;
+        record.reading.kwh = 0.29;
+        record.reading.import_kwh = 0.3;
+        record.reading.export_kwh = 0.01;
+        record.reading.frequency = 50.01;
+        record.reading.real_power_stats = MinMaxLast {
+            min: 900.0,
+            max: 1400.0,
+            last: 1100.0,
+        };
+        record.reading.samples = 1800;
+        record.reading.timestamp = 1_673_000_000_000;
+        record.reading.uptime = 3_600_000;
+        record.reading.demand = 2.41;
+        record.reading.peak_demand = 5.87;
+        record.reading.peak_demand_time = 1_672_999_200_000;
+        record.lifetime_tariff_import_kwh[1] = 820.1;
+        record.lifetime_tariff_export_kwh[3] = 4.5;
+        record
+    }
+
+    #[test]
+    fn record_round_trip() {
+        let header = ShardHeader::current(MAC);
+        let buf = record().to_le_bytes().unwrap();
+        let decoded = Record::from_le_bytes(&header, &buf).unwrap();
+        let expected = record();
+        assert_eq!(decoded.ct_id, expected.ct_id);
+        assert_eq!(decoded.seq, expected.seq);
+        assert_eq!(decoded.boot_count, expected.boot_count);
+        assert_eq!(decoded.flags, expected.flags);
+        assert_eq!(decoded.lifetime_import_kwh, expected.lifetime_import_kwh);
+        assert_eq!(decoded.lifetime_export_kwh, expected.lifetime_export_kwh);
+        assert_eq!(
+            decoded.lifetime_tariff_import_kwh,
+            expected.lifetime_tariff_import_kwh
+        );
+        assert_eq!(
+            decoded.lifetime_tariff_export_kwh,
+            expected.lifetime_tariff_export_kwh
+        );
+        let (reading, expected) = (decoded.reading, expected.reading);
+        assert_eq!(reading.real_power, expected.real_power);
+        assert_eq!(reading.apparent_power, expected.apparent_power);
+        assert_eq!(reading.reactive_power, expected.reactive_power);
+        assert_eq!(reading.power_factor, expected.power_factor);
+        assert_eq!(reading.i_rms, expected.i_rms);
+        assert_eq!(reading.v_rms, expected.v_rms);
+        assert_eq!(reading.kwh, expected.kwh);
+        assert_eq!(reading.import_kwh, expected.import_kwh);
+        assert_eq!(reading.export_kwh, expected.export_kwh);
+        assert_eq!(reading.frequency, expected.frequency);
+        assert_eq!(reading.real_power_stats.min, 900.0);
+        assert_eq!(reading.real_power_stats.max, 1400.0);
+        assert_eq!(reading.real_power_stats.last, 1100.0);
+        assert_eq!(reading.samples, expected.samples);
+        assert_eq!(reading.timestamp, expected.timestamp);
+        assert_eq!(reading.uptime, expected.uptime);
+        assert_eq!(reading.demand, expected.demand);
+        assert_eq!(reading.peak_demand, expected.peak_demand);
+        assert_eq!(reading.peak_demand_time, expected.peak_demand_time);
+    }
+
+    #[test]
+    fn corrupted_record_fails_the_crc() {
+        let header = ShardHeader::current(MAC);
+        let buf = record().to_le_bytes().unwrap();
+        for i in [0, 2, 30, CT_READING_SIZE / 2, CT_READING_SIZE - 1] {
+            let mut corrupted = buf;
+            corrupted[i] ^= 0x10;
+            assert!(
+                Record::from_le_bytes(&header, &corrupted).is_err(),
+                "flipping byte {} went unnoticed",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn legacy_record() {
+        let buf = record().to_legacy_le_bytes().unwrap();
+        let decoded = Record::from_le_bytes(&ShardHeader::legacy(), &buf).unwrap();
+        assert_eq!(decoded.ct_id, 2);
+        assert_eq!(decoded.reading.real_power, 1150.5);
+        assert_eq!(decoded.reading.apparent_power, 1200.0);
+        assert_eq!(decoded.reading.i_rms, 5.2);
+        assert_eq!(decoded.reading.v_rms, 230.1);
+        assert_eq!(decoded.reading.kwh, 0.29);
+        assert_eq!(decoded.reading.timestamp, 1_673_000_000_000);
+        // the fields appended since then read as zero, all of the energy as import.
+        assert_eq!(decoded.seq, 0);
+        assert_eq!(decoded.boot_count, 0);
+        assert_eq!(decoded.reading.uptime, 0);
+        assert_eq!(decoded.reading.reactive_power, 0.0);
+        assert_eq!(decoded.reading.import_kwh, 0.29);
+        assert_eq!(decoded.reading.export_kwh, 0.0);
+        assert_eq!(decoded.reading.frequency, 0.0);
+        assert_eq!(decoded.reading.samples, 0);
+        assert_eq!(decoded.lifetime_import_kwh, 0.0);
+        assert_eq!(decoded.lifetime_export_kwh, 0.0);
+        assert_eq!(decoded.flags, 0);
+        assert_eq!(decoded.reading.peak_demand_time, 0);
+        assert_eq!(decoded.lifetime_tariff_import_kwh, [0.0; MAX_TARIFFS]);
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = ShardHeader::current(MAC);
+        let mut shard = header.to_le_bytes().unwrap().to_vec();
+        shard.extend_from_slice(&record().to_le_bytes().unwrap());
+        let mut reader = Cursor::new(shard);
+        assert_eq!(ShardHeader::read_from(&mut reader).unwrap(), header);
+        assert_eq!(reader.position(), SHARD_HEADER_SIZE as u64);
+    }
+
+    #[test]
+    fn legacy_shard_has_no_header() {
+        let mut reader = Cursor::new(record().to_legacy_le_bytes().unwrap().to_vec());
+        assert_eq!(
+            ShardHeader::read_from(&mut reader).unwrap(),
+            ShardHeader::legacy()
+        );
+        assert_eq!(reader.position(), 0);
+    }
+
+    #[test]
+    fn invalid_header_sizes() {
+        for (header_size, record_size) in [(SHARD_HEADER_SIZE as u16 - 1, 220), (0, 220), (20, 4)] {
+            let header = ShardHeader {
+                header_size,
+                record_size,
+                ..ShardHeader::current(MAC)
+            };
+            let mut reader = Cursor::new(header.to_le_bytes().unwrap().to_vec());
+            assert!(ShardHeader::read_from(&mut reader).is_err());
+        }
+    }
+}
@@ -0,0 +1,83 @@
+//! Wall-clock time of the device.
+//!
+//! The ESP32 has no battery backed RTC, so the time is kept by the system clock and restored
+//! from storage on boot. On the host the system clock is left alone and an offset is kept instead.
+//!
+//! The time since boot can't jump, so it is kept next to the wall-clock time.
+
+use std::time::Duration;
+
+#[cfg(feature = "esp")]
+use esp_idf_sys::{esp, esp_timer_get_time, gettimeofday, settimeofday, timeval};
+
+#[cfg(not(feature = "esp"))]
+use std::sync::atomic::{AtomicI64, Ordering};
+#[cfg(not(feature = "esp"))]
+use std::sync::OnceLock;
+#[cfg(not(feature = "esp"))]
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Difference between the time set with `set_system_time` and the host clock, in milliseconds.
+#[cfg(not(feature = "esp"))]
+static TIME_OFFSET_MILIS: AtomicI64 = AtomicI64::new(0);
+
+/// When the simulated device booted, which is the first time `uptime` is called.
+#[cfg(not(feature = "esp"))]
+static BOOT_INSTANT: OnceLock<Instant> = OnceLock::new();
+
+#[cfg(feature = "esp")]
+pub fn now() -> Duration {
+    let mut tv_now: timeval = Default::default();
+
+    unsafe {
+        gettimeofday(&mut tv_now as *mut _, core::ptr::null_mut());
+    }
+
+    Duration::from_micros(tv_now.tv_sec as u64 * 1000000_u64 + tv_now.tv_usec as u64)
+}
+
+#[cfg(feature = "esp")]
+pub fn set_system_time(time_milis: u64) -> anyhow::Result<()> {
+    let mut tv_now: timeval = timeval {
+        tv_sec: (time_milis / 1000) as i32,
+        tv_usec: 0,
+    };
+    println!("settimeofday milis: {} sec: {}", time_milis, tv_now.tv_sec);
+    esp!(unsafe { settimeofday(&mut tv_now as *mut _, core::ptr::null_mut()) })?;
+    Ok(())
+}
+
+/// Time since boot.
+#[cfg(feature = "esp")]
+pub fn uptime() -> Duration {
+    Duration::from_micros(unsafe { esp_timer_get_time() } as u64)
+}
+
+#[cfg(not(feature = "esp"))]
+fn host_time_milis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+#[cfg(not(feature = "esp"))]
+pub fn now() -> Duration {
+    let time_milis = host_time_milis() + TIME_OFFSET_MILIS.load(Ordering::Relaxed);
+    Duration::from_millis(i64::max(time_milis, 0) as u64)
+}
+
+#[cfg(not(feature = "esp"))]
+pub fn set_system_time(time_milis: u64) -> anyhow::Result<()> {
+    // Like settimeofday on the device, only whole seconds are kept.
+    let time_milis = (time_milis / 1000 * 1000) as i64;
+    TIME_OFFSET_MILIS.store(time_milis - host_time_milis(), Ordering::Relaxed);
+    println!("set simulated time milis: {}", time_milis);
+    Ok(())
+}
+
+/// Time since boot.
+#[cfg(not(feature = "esp"))]
+pub fn uptime() -> Duration {
+    BOOT_INSTANT.get_or_init(Instant::now).elapsed()
+}
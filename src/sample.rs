@@ -0,0 +1,339 @@
+use std::f32::consts::PI;
+use std::time::Duration;
+
+#[cfg(feature = "esp")]
+use std::cell::RefCell;
+#[cfg(feature = "esp")]
+use std::rc::Rc;
+#[cfg(feature = "esp")]
+use std::time::Instant;
+
+#[cfg(feature = "esp")]
+use embedded_hal_0_2_7::adc::OneShot;
+#[cfg(feature = "esp")]
+use esp_idf_hal::adc::{Atten11dB, PoweredAdc, ADC1};
+
+use crate::MAX_MV_ATTEN_11;
+
+/// A source of voltage and current samples for a single CT.
+///
+/// Samples are in millivolts as returned by the ADC with 11dB attenuation, so they are
+/// in the range `0..=MAX_MV_ATTEN_11` and centred around a DC offset.
+pub trait SampleSource {
+    /// Read a single voltage sample. `None` if the read failed.
+    fn read_voltage(&mut self) -> Option<u16>;
+
+    /// Read a current sample immediately followed by a voltage sample.
+    fn read_pair(&mut self) -> (Option<u16>, Option<u16>);
+
+    /// Monotonic time since an arbitrary fixed point. Used to time the measurement window.
+    fn clock(&self) -> Duration;
+}
+
+impl<S: SampleSource + ?Sized> SampleSource for Box<S> {
+    fn read_voltage(&mut self) -> Option<u16> {
+        (**self).read_voltage()
+    }
+
+    fn read_pair(&mut self) -> (Option<u16>, Option<u16>) {
+        (**self).read_pair()
+    }
+
+    fn clock(&self) -> Duration {
+        (**self).clock()
+    }
+}
+
+/// An ADC1 pin that was set up for analog reads with 11dB attenuation.
+#[cfg(feature = "esp")]
+pub trait AdcChannel {
+    fn read(&mut self, adc: &mut PoweredAdc<ADC1>) -> Option<u16>;
+}
+
+#[cfg(feature = "esp")]
+impl<P> AdcChannel for P
+where
+    PoweredAdc<ADC1>: OneShot<Atten11dB<ADC1>, u16, P>,
+{
+    fn read(&mut self, adc: &mut PoweredAdc<ADC1>) -> Option<u16> {
+        adc.read(self).ok()
+    }
+}
+
+/// Reads samples from a current and a voltage pin of ADC1.
+///
+/// ADC1 is shared between all the CTs, so every source holds a handle to the same `PoweredAdc`.
+/// CTs on the same phase share their voltage pin the same way.
+#[cfg(feature = "esp")]
+pub struct AdcSampleSource {
+    adc: Rc<RefCell<PoweredAdc<ADC1>>>,
+    current_pin: Box<dyn AdcChannel>,
+    voltage_pin: Rc<RefCell<Box<dyn AdcChannel>>>,
+    epoch: Instant,
+}
+
+#[cfg(feature = "esp")]
+impl AdcSampleSource {
+    pub fn new(
+        adc: Rc<RefCell<PoweredAdc<ADC1>>>,
+        current_pin: Box<dyn AdcChannel>,
+        voltage_pin: Rc<RefCell<Box<dyn AdcChannel>>>,
+    ) -> Self {
+        AdcSampleSource {
+            adc,
+            current_pin,
+            voltage_pin,
+            epoch: Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "esp")]
+impl SampleSource for AdcSampleSource {
+    fn read_voltage(&mut self) -> Option<u16> {
+        self.voltage_pin
+            .borrow_mut()
+            .read(&mut self.adc.borrow_mut())
+    }
+
+    fn read_pair(&mut self) -> (Option<u16>, Option<u16>) {
+        let mut adc = self.adc.borrow_mut();
+        let sample_i = self.current_pin.read(&mut adc);
+        let sample_v = self.voltage_pin.borrow_mut().read(&mut adc);
+        (sample_i, sample_v)
+    }
+
+    fn clock(&self) -> Duration {
+        self.epoch.elapsed()
+    }
+}
+
+/// Generates ideal sine waves instead of reading the ADC.
+///
+/// Time is simulated: every read advances the clock by `read_time`, so the results of
+/// `CT::calculate_energy` only depend on the waveform and not on how fast the host is.
+pub struct SyntheticSource {
+    /// Mains frequency in Hz.
+    pub frequency: f32,
+    /// Peak voltage signal in millivolts.
+    pub v_amplitude: f32,
+    /// Peak current signal in millivolts.
+    pub i_amplitude: f32,
+    /// How much the current lags the voltage, in radians.
+    pub phase_shift: f32,
+    /// DC offset both signals are centred on, in millivolts.
+    pub offset: f32,
+    /// Harmonics added to the voltage and the current, to simulate distorted waveforms.
+    pub v_harmonics: Vec<Harmonic>,
+    pub i_harmonics: Vec<Harmonic>,
+    /// Simulated duration of a single ADC read.
+    pub read_time: Duration,
+    elapsed: Duration,
+}
+
+/// A harmonic of a `SyntheticSource` waveform.
+#[derive(Debug, Clone, Copy)]
+pub struct Harmonic {
+    /// Multiple of the mains frequency, 3 for the third harmonic.
+    pub order: u32,
+    /// Amplitude relative to the fundamental.
+    pub amplitude: f32,
+}
+
+impl Harmonic {
+    /// Parses a list of harmonics like `3:0.2,5:0.1`, i.e. the third harmonic with 20% of the
+    /// amplitude of the fundamental and the fifth with 10%.
+    pub fn parse_list(list: &str) -> anyhow::Result<Vec<Harmonic>> {
+        let mut harmonics = Vec::new();
+        for item in list
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+        {
+            let (order, amplitude) = item
+                .split_once(':')
+                .ok_or_else(|| anyhow::anyhow!("Harmonic {} must be <order>:<amplitude>.", item))?;
+            let order = order.trim().parse()?;
+            if order < 2 {
+                anyhow::bail!("The order of harmonic {} must be at least 2.", item);
+            }
+            harmonics.push(Harmonic {
+                order,
+                amplitude: amplitude.trim().parse()?,
+            });
+        }
+        Ok(harmonics)
+    }
+}
+
+impl SyntheticSource {
+    pub fn new(frequency: f32, v_amplitude: f32, i_amplitude: f32, phase_shift: f32) -> Self {
+        SyntheticSource {
+            frequency,
+            v_amplitude,
+            i_amplitude,
+            phase_shift,
+            offset: MAX_MV_ATTEN_11 as f32 / 2.0,
+            v_harmonics: Vec::new(),
+            i_harmonics: Vec::new(),
+            read_time: Duration::from_micros(25),
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    /// Distort the voltage and the current with harmonics.
+    pub fn with_harmonics(
+        mut self,
+        v_harmonics: Vec<Harmonic>,
+        i_harmonics: Vec<Harmonic>,
+    ) -> Self {
+        self.v_harmonics = v_harmonics;
+        self.i_harmonics = i_harmonics;
+        self
+    }
+
+    fn sample(&mut self, amplitude: f32, phase: f32, harmonics: &[Harmonic]) -> Option<u16> {
+        let t = self.elapsed.as_secs_f32();
+        self.elapsed += self.read_time;
+        let angle = 2.0 * PI * self.frequency * t - phase;
+        let mut value = self.offset + amplitude * f32::sin(angle);
+        for harmonic in harmonics {
+            value += amplitude * harmonic.amplitude * f32::sin(harmonic.order as f32 * angle);
+        }
+        Some(value.round().clamp(0.0, MAX_MV_ATTEN_11 as f32) as u16)
+    }
+
+    fn sample_voltage(&mut self) -> Option<u16> {
+        let harmonics = std::mem::take(&mut self.v_harmonics);
+        let sample = self.sample(self.v_amplitude, 0.0, &harmonics);
+        self.v_harmonics = harmonics;
+        sample
+    }
+
+    fn sample_current(&mut self) -> Option<u16> {
+        let harmonics = std::mem::take(&mut self.i_harmonics);
+        let sample = self.sample(self.i_amplitude, self.phase_shift, &harmonics);
+        self.i_harmonics = harmonics;
+        sample
+    }
+}
+
+impl SampleSource for SyntheticSource {
+    fn read_voltage(&mut self) -> Option<u16> {
+        self.sample_voltage()
+    }
+
+    fn read_pair(&mut self) -> (Option<u16>, Option<u16>) {
+        let sample_i = self.sample_current();
+        let sample_v = self.sample_voltage();
+        (sample_i, sample_v)
+    }
+
+    fn clock(&self) -> Duration {
+        self.elapsed
+    }
+}
+
+/// One step of a `ScriptedSource`: the waveform is held for `duration` of simulated time.
+#[derive(Debug, Clone, Copy)]
+pub struct WaveformStep {
+    pub duration: Duration,
+    pub v_amplitude: f32,
+    pub i_amplitude: f32,
+    pub phase_shift: f32,
+}
+
+/// A `SyntheticSource` whose amplitudes and phase follow a script.
+///
+/// The last step is held forever. Time is simulated the same way as in `SyntheticSource`.
+pub struct ScriptedSource {
+    source: SyntheticSource,
+    steps: Vec<WaveformStep>,
+    step: usize,
+    step_start: Duration,
+}
+
+impl ScriptedSource {
+    pub fn new(frequency: f32, steps: Vec<WaveformStep>) -> Self {
+        let mut source = ScriptedSource {
+            source: SyntheticSource::new(frequency, 0.0, 0.0, 0.0),
+            steps,
+            step: 0,
+            step_start: Duration::ZERO,
+        };
+        source.apply_step();
+        source
+    }
+
+    /// Distort the voltage and the current with harmonics, which are the same in every step.
+    pub fn with_harmonics(
+        mut self,
+        v_harmonics: Vec<Harmonic>,
+        i_harmonics: Vec<Harmonic>,
+    ) -> Self {
+        self.source = self.source.with_harmonics(v_harmonics, i_harmonics);
+        self
+    }
+
+    /// Parses a script where every line is `<seconds> <v_amplitude> <i_amplitude> <phase_shift>`.
+    ///
+    /// Amplitudes are peak millivolts and the phase shift is in radians. Empty lines and lines
+    /// starting with `#` are ignored.
+    pub fn parse_script(script: &str) -> anyhow::Result<Vec<WaveformStep>> {
+        let mut steps = Vec::new();
+        for (line_num, line) in script.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let values = line
+                .split_whitespace()
+                .map(|value| value.parse::<f32>())
+                .collect::<Result<Vec<f32>, _>>()?;
+            if values.len() != 4 {
+                anyhow::bail!("Line {} of the script must have 4 values.", line_num + 1);
+            }
+            steps.push(WaveformStep {
+                duration: Duration::from_secs_f32(values[0]),
+                v_amplitude: values[1],
+                i_amplitude: values[2],
+                phase_shift: values[3],
+            });
+        }
+        Ok(steps)
+    }
+
+    fn apply_step(&mut self) {
+        if let Some(step) = self.steps.get(self.step) {
+            self.source.v_amplitude = step.v_amplitude;
+            self.source.i_amplitude = step.i_amplitude;
+            self.source.phase_shift = step.phase_shift;
+        }
+    }
+
+    fn advance(&mut self) {
+        while self.step + 1 < self.steps.len()
+            && self.source.clock() - self.step_start >= self.steps[self.step].duration
+        {
+            self.step_start += self.steps[self.step].duration;
+            self.step += 1;
+            self.apply_step();
+        }
+    }
+}
+
+impl SampleSource for ScriptedSource {
+    fn read_voltage(&mut self) -> Option<u16> {
+        self.advance();
+        self.source.read_voltage()
+    }
+
+    fn read_pair(&mut self) -> (Option<u16>, Option<u16>) {
+        self.advance();
+        self.source.read_pair()
+    }
+
+    fn clock(&self) -> Duration {
+        self.source.clock()
+    }
+}
@@ -0,0 +1,98 @@
+//! When the readings are stored.
+//!
+//! Once the time is known, save periods are aligned to the clock, so hourly records cover whole
+//! hours and 15 minute records quarter hours, like the interval data of a utility meter. Before
+//! that, they are timed with the time since boot. Intervals that don't cover a whole save period,
+//! like the first one after boot or one in which the time was changed, are flagged as partial.
+
+use std::time::Duration;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+use crate::record::Record;
+use crate::{MIN_KNOWN_TIME, TIME_JUMP_TOLERANCE};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Deadline {
+    /// In milliseconds since the UNIX epoch.
+    Clock(u64),
+    /// In time since boot.
+    Uptime(Duration),
+}
+
+pub(crate) struct SaveSchedule {
+    period: Duration,
+    /// When the current interval ends.
+    end: Deadline,
+    /// Whether the current interval started in the middle of a save period.
+    partial: bool,
+    /// The time, in ms since the epoch, and the time since boot when the clock was last checked.
+    last_time: u64,
+    last_uptime: Duration,
+}
+
+impl SaveSchedule {
+    /// Starts with the rest of the current save period, which is partial.
+    pub(crate) fn new(period: Duration, time: u64, uptime: Duration) -> Self {
+        SaveSchedule {
+            period,
+            end: deadline(period, time, uptime),
+            partial: true,
+            last_time: time,
+            last_uptime: uptime,
+        }
+    }
+
+    /// Whether the current interval is over. A time change or a new `period` restarts it.
+    pub(crate) fn is_due(&mut self, period: Duration, time: u64, uptime: Duration) -> bool {
+        let expected = self.last_time + uptime.saturating_sub(self.last_uptime).as_millis() as u64;
+        let jump = (time as i64 - expected as i64).unsigned_abs();
+        self.last_time = time;
+        self.last_uptime = uptime;
+        if jump > TIME_JUMP_TOLERANCE || period != self.period {
+            info!(
+                "Restarting the save period, the clock jumped by {} ms and the period is {:?}.",
+                jump, period
+            );
+            self.period = period;
+            self.end = deadline(period, time, uptime);
+            self.partial = true;
+        }
+        match self.end {
+            Deadline::Clock(end) => time >= end,
+            Deadline::Uptime(end) => uptime >= end,
+        }
+    }
+
+    /// Starts the next interval, and returns the `Record` flags of the one that ended.
+    pub(crate) fn next(&mut self, time: u64, uptime: Duration) -> u16 {
+        let mut flags = 0;
+        if self.partial {
+            flags |= Record::PARTIAL;
+        }
+        if let Deadline::Clock(_) = self.end {
+            flags |= Record::ALIGNED;
+        }
+        let next = deadline(self.period, time, uptime);
+        (self.end, self.partial) = match (self.end, next) {
+            (Deadline::Uptime(end), Deadline::Uptime(_)) => {
+                (Deadline::Uptime(end + self.period), false)
+            }
+            // the clock was set in the meantime, the next save period already started.
+            (Deadline::Uptime(_), Deadline::Clock(_)) => (next, true),
+            (Deadline::Clock(_), _) => (next, false),
+        };
+        flags
+    }
+}
+
+/// The end of the save period `time` is in, or `period` from now if the time is not known.
+fn deadline(period: Duration, time: u64, uptime: Duration) -> Deadline {
+    let period_ms = u64::max(period.as_millis() as u64, 1);
+    if time >= MIN_KNOWN_TIME {
+        Deadline::Clock((time / period_ms + 1) * period_ms)
+    } else {
+        Deadline::Uptime(uptime + period)
+    }
+}
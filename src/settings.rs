@@ -0,0 +1,356 @@
+//! Settings of the device that can be changed over http.
+//!
+//! Every setting is kept in the keystore, settings that were never changed have their default.
+//! The tariff schedule is kept as text, see `tariffs`, and there is none until one is set.
+
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+use crate::events::EventThresholds;
+use crate::keystore::SharedKeyStore;
+use crate::tariffs::TariffSchedule;
+use crate::{
+    CROSSINGS, LOOP_SLEEP, MAX_TARIFF_SCHEDULE_SIZE, MEASUREMENT_TIMEOUT, SAVE_PERIOD_TIMEOUT,
+};
+
+/// The mains frequencies a device can be set up for, in Hz.
+pub const NOMINAL_FREQUENCIES: [u8; 2] = [50, 60];
+/// The storage intervals a device can be set up for, in seconds.
+pub const SAVE_PERIODS: [u64; 4] = [60, 300, 900, 3600];
+/// The demand windows a device can be set up for, in seconds.
+pub const DEMAND_WINDOWS: [u64; 5] = [300, 600, 900, 1800, 3600];
+
+pub const DEFAULT_SETTINGS: SettingValues = SettingValues {
+    nominal_frequency: 50,
+    save_period: SAVE_PERIOD_TIMEOUT,
+    crossings: CROSSINGS,
+    measurement_timeout: MEASUREMENT_TIMEOUT * 1000,
+    loop_sleep: LOOP_SLEEP,
+    nominal_voltage: 230,
+    sag_threshold: 90,
+    swell_threshold: 110,
+    interruption_threshold: 10,
+    event_min_duration: 20,
+    demand_window: 900,
+    demand_sliding: false,
+};
+
+const NOMINAL_FREQUENCY_KEY: &str = "nominal_hz";
+const SAVE_PERIOD_KEY: &str = "save_period";
+const CROSSINGS_KEY: &str = "crossings";
+const MEASUREMENT_TIMEOUT_KEY: &str = "meas_timeout";
+const LOOP_SLEEP_KEY: &str = "loop_sleep";
+const NOMINAL_VOLTAGE_KEY: &str = "nominal_v";
+const SAG_THRESHOLD_KEY: &str = "sag_pct";
+const SWELL_THRESHOLD_KEY: &str = "swell_pct";
+const INTERRUPTION_THRESHOLD_KEY: &str = "interrupt_pct";
+const EVENT_MIN_DURATION_KEY: &str = "event_min_ms";
+const DEMAND_WINDOW_KEY: &str = "demand_window";
+const DEMAND_SLIDING_KEY: &str = "demand_sliding";
+const TARIFFS_KEY: &str = "tariffs";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SettingValues {
+    /// The frequency of the mains the device is connected to, in Hz.
+    pub nominal_frequency: u8,
+    /// How often the readings are stored, in seconds.
+    pub save_period: u64,
+    /// Voltage crossings in one measurement.
+    pub crossings: u32,
+    /// The longest a measurement may take, in milliseconds.
+    pub measurement_timeout: u64,
+    /// The pause after the CTs were measured, in milliseconds.
+    pub loop_sleep: u64,
+    /// The voltage of the mains the device is connected to, in volts.
+    pub nominal_voltage: u16,
+    /// Thresholds of the voltage events, in percent of the nominal voltage.
+    pub sag_threshold: u8,
+    pub swell_threshold: u8,
+    pub interruption_threshold: u8,
+    /// Shorter voltage events are not logged, in milliseconds.
+    pub event_min_duration: u64,
+    /// How long the demand is averaged over, in seconds.
+    pub demand_window: u64,
+    /// Whether the demand window slides by the minute rather than following the previous one.
+    pub demand_sliding: bool,
+}
+
+pub struct Settings {
+    keystore: SharedKeyStore,
+    values: SettingValues,
+    tariffs: TariffSchedule,
+}
+
+pub type SharedSettings = Arc<Mutex<Settings>>;
+
+impl SettingValues {
+    /// Makes sure the device keeps measuring and storing with these settings.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !NOMINAL_FREQUENCIES.contains(&self.nominal_frequency) {
+            anyhow::bail!("The nominal frequency must be 50 or 60 Hz.");
+        }
+        if !SAVE_PERIODS.contains(&self.save_period) {
+            anyhow::bail!("The save period must be 60, 300, 900 or 3600 seconds.");
+        }
+        if !(2..=1000).contains(&self.crossings) {
+            anyhow::bail!("The crossings must be between 2 and 1000.");
+        }
+        if !(100..=10_000).contains(&self.measurement_timeout) {
+            anyhow::bail!("The measurement timeout must be between 100 and 10000 ms.");
+        }
+        // every period has two crossings, and the mains frequency can be somewhat low.
+        let needed = self.crossings as u64 * 1000 / (2 * self.nominal_frequency as u64);
+        if self.measurement_timeout < needed + needed / 10 {
+            anyhow::bail!(
+                "The measurement timeout must be at least {} ms for {} crossings.",
+                needed + needed / 10,
+                self.crossings
+            );
+        }
+        if self.loop_sleep > 60_000 || self.loop_sleep >= self.save_period * 1000 {
+            anyhow::bail!(
+                "The loop sleep must be at most 60000 ms and shorter than the save period."
+            );
+        }
+        if !(50..=500).contains(&self.nominal_voltage) {
+            anyhow::bail!("The nominal voltage must be between 50 and 500 V.");
+        }
+        if !(self.interruption_threshold < self.sag_threshold
+            && self.sag_threshold < 100
+            && (101..=200).contains(&self.swell_threshold))
+        {
+            anyhow::bail!(
+                "The thresholds must be interruption < sag < 100 < swell <= 200 percent."
+            );
+        }
+        if !(10..=60_000).contains(&self.event_min_duration) {
+            anyhow::bail!("The minimum event duration must be between 10 and 60000 ms.");
+        }
+        if !DEMAND_WINDOWS.contains(&self.demand_window) {
+            anyhow::bail!("The demand window must be 300, 600, 900, 1800 or 3600 seconds.");
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> String {
+        format!(
+            concat!(
+                r#"{{"nominal_frequency":{},"save_period":{},"crossings":{},"#,
+                r#""measurement_timeout":{},"loop_sleep":{},"nominal_voltage":{},"#,
+                r#""sag_threshold":{},"swell_threshold":{},"interruption_threshold":{},"#,
+                r#""event_min_duration":{},"demand_window":{},"demand_sliding":{}}}"#
+            ),
+            self.nominal_frequency,
+            self.save_period,
+            self.crossings,
+            self.measurement_timeout,
+            self.loop_sleep,
+            self.nominal_voltage,
+            self.sag_threshold,
+            self.swell_threshold,
+            self.interruption_threshold,
+            self.event_min_duration,
+            self.demand_window,
+            self.demand_sliding
+        )
+    }
+}
+
+impl Settings {
+    /// Loads the settings from the keystore. If the stored settings are not valid together, the
+    /// defaults are used.
+    pub fn load(keystore: SharedKeyStore) -> anyhow::Result<SharedSettings> {
+        let mut values = DEFAULT_SETTINGS;
+        let mut tariffs = TariffSchedule::default();
+        {
+            let keystore = match keystore.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            let mut buf = [0_u8; 1];
+            if keystore.get_raw(NOMINAL_FREQUENCY_KEY, &mut buf)?.is_some() {
+                values.nominal_frequency = buf[0];
+            }
+            if let Some(save_period) = keystore.get_u64(SAVE_PERIOD_KEY)? {
+                values.save_period = save_period;
+            }
+            if let Some(crossings) = keystore.get_u64(CROSSINGS_KEY)? {
+                values.crossings = u32::try_from(crossings).unwrap_or(0);
+            }
+            if let Some(timeout) = keystore.get_u64(MEASUREMENT_TIMEOUT_KEY)? {
+                values.measurement_timeout = timeout;
+            }
+            if let Some(loop_sleep) = keystore.get_u64(LOOP_SLEEP_KEY)? {
+                values.loop_sleep = loop_sleep;
+            }
+            if let Some(voltage) = keystore.get_u64(NOMINAL_VOLTAGE_KEY)? {
+                values.nominal_voltage = u16::try_from(voltage).unwrap_or(0);
+            }
+            if let Some(sag) = keystore.get_u64(SAG_THRESHOLD_KEY)? {
+                values.sag_threshold = u8::try_from(sag).unwrap_or(0);
+            }
+            if let Some(swell) = keystore.get_u64(SWELL_THRESHOLD_KEY)? {
+                values.swell_threshold = u8::try_from(swell).unwrap_or(0);
+            }
+            if let Some(interruption) = keystore.get_u64(INTERRUPTION_THRESHOLD_KEY)? {
+                values.interruption_threshold = u8::try_from(interruption).unwrap_or(0);
+            }
+            if let Some(duration) = keystore.get_u64(EVENT_MIN_DURATION_KEY)? {
+                values.event_min_duration = duration;
+            }
+            if let Some(window) = keystore.get_u64(DEMAND_WINDOW_KEY)? {
+                values.demand_window = window;
+            }
+            if let Some(sliding) = keystore.get_u64(DEMAND_SLIDING_KEY)? {
+                values.demand_sliding = sliding != 0;
+            }
+            let mut buf = [0_u8; MAX_TARIFF_SCHEDULE_SIZE];
+            if let Some(len) = keystore.get_raw(TARIFFS_KEY, &mut buf)? {
+                let parsed = std::str::from_utf8(&buf[..len])
+                    .map_err(anyhow::Error::from)
+                    .and_then(TariffSchedule::parse);
+                match parsed {
+                    Ok(schedule) => tariffs = schedule,
+                    Err(e) => warn!("Ignored stored tariff schedule: {:?}", e),
+                }
+            }
+        }
+        if let Err(e) = values.validate() {
+            warn!("Ignored stored settings {:?}: {:?}", values, e);
+            values = DEFAULT_SETTINGS;
+        }
+        info!("Settings: {:?}", values);
+        info!("Tariff schedule: {:?}", tariffs);
+        Ok(Arc::new(Mutex::new(Settings {
+            keystore,
+            values,
+            tariffs,
+        })))
+    }
+
+    pub fn values(&self) -> SettingValues {
+        self.values
+    }
+
+    pub fn nominal_frequency(&self) -> u8 {
+        self.values.nominal_frequency
+    }
+
+    pub fn save_period(&self) -> Duration {
+        Duration::from_secs(self.values.save_period)
+    }
+
+    pub fn crossings(&self) -> u32 {
+        self.values.crossings
+    }
+
+    pub fn measurement_timeout(&self) -> Duration {
+        Duration::from_millis(self.values.measurement_timeout)
+    }
+
+    pub fn loop_sleep(&self) -> Duration {
+        Duration::from_millis(self.values.loop_sleep)
+    }
+
+    pub fn event_thresholds(&self) -> EventThresholds {
+        EventThresholds {
+            nominal_voltage: self.values.nominal_voltage as f32,
+            sag: self.values.sag_threshold as f32,
+            swell: self.values.swell_threshold as f32,
+            interruption: self.values.interruption_threshold as f32,
+            min_duration: Duration::from_millis(self.values.event_min_duration),
+        }
+    }
+
+    pub fn demand_window(&self) -> Duration {
+        Duration::from_secs(self.values.demand_window)
+    }
+
+    pub fn demand_sliding(&self) -> bool {
+        self.values.demand_sliding
+    }
+
+    pub fn tariffs(&self) -> &TariffSchedule {
+        &self.tariffs
+    }
+
+    /// Stores a tariff schedule, which is used from the next measurement on.
+    pub(crate) fn set_tariffs(&mut self, tariffs: TariffSchedule) -> anyhow::Result<()> {
+        let text = tariffs.to_text();
+        if text.len() > MAX_TARIFF_SCHEDULE_SIZE {
+            anyhow::bail!("The tariff schedule is too long.");
+        }
+        {
+            let mut keystore = match self.keystore.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            keystore.put_raw(TARIFFS_KEY, text.as_bytes())?;
+        }
+        info!("Tariff schedule set to {:?}", tariffs);
+        self.tariffs = tariffs;
+        Ok(())
+    }
+
+    /// Stores the settings that changed. They are used from the next measurement on.
+    pub(crate) fn set(&mut self, values: SettingValues) -> anyhow::Result<()> {
+        values.validate()?;
+        {
+            let mut keystore = match self.keystore.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            let old = self.values;
+            if values.nominal_frequency != old.nominal_frequency {
+                keystore.put_raw(NOMINAL_FREQUENCY_KEY, &[values.nominal_frequency])?;
+            }
+            if values.save_period != old.save_period {
+                keystore.put_u64(SAVE_PERIOD_KEY, values.save_period)?;
+            }
+            if values.crossings != old.crossings {
+                keystore.put_u64(CROSSINGS_KEY, values.crossings as u64)?;
+            }
+            if values.measurement_timeout != old.measurement_timeout {
+                keystore.put_u64(MEASUREMENT_TIMEOUT_KEY, values.measurement_timeout)?;
+            }
+            if values.loop_sleep != old.loop_sleep {
+                keystore.put_u64(LOOP_SLEEP_KEY, values.loop_sleep)?;
+            }
+            if values.nominal_voltage != old.nominal_voltage {
+                keystore.put_u64(NOMINAL_VOLTAGE_KEY, values.nominal_voltage as u64)?;
+            }
+            if values.sag_threshold != old.sag_threshold {
+                keystore.put_u64(SAG_THRESHOLD_KEY, values.sag_threshold as u64)?;
+            }
+            if values.swell_threshold != old.swell_threshold {
+                keystore.put_u64(SWELL_THRESHOLD_KEY, values.swell_threshold as u64)?;
+            }
+            if values.interruption_threshold != old.interruption_threshold {
+                keystore.put_u64(
+                    INTERRUPTION_THRESHOLD_KEY,
+                    values.interruption_threshold as u64,
+                )?;
+            }
+            if values.event_min_duration != old.event_min_duration {
+                keystore.put_u64(EVENT_MIN_DURATION_KEY, values.event_min_duration)?;
+            }
+            if values.demand_window != old.demand_window {
+                keystore.put_u64(DEMAND_WINDOW_KEY, values.demand_window)?;
+            }
+            if values.demand_sliding != old.demand_sliding {
+                keystore.put_u64(DEMAND_SLIDING_KEY, values.demand_sliding as u64)?;
+            }
+        }
+        self.values = values;
+        info!("Settings set to {:?}", values);
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> String {
+        self.values.to_json()
+    }
+}
@@ -0,0 +1,378 @@
+//! Time-of-use tariff schedules.
+//!
+//! A schedule splits the year into seasons, and the days of every season into time bands, one set
+//! of bands for weekdays and one for weekends. Every band has one of `MAX_TARIFFS` tariffs, and the
+//! energy measured during it is added to the tariff registers of the CT, see `energy`. The bands are
+//! in local time, UTC plus a fixed offset in minutes; daylight saving time can be followed with a
+//! season that starts on the day it changes.
+//!
+//! A schedule is written as text, one item per line:
+//!
+//! ```text
+//! offset 60
+//! season 01-01 weekday 00:00=2,07:00=1,23:00=2 weekend 00:00=2
+//! season 06-01 weekday 00:00=2,08:00=1,20:00=2 weekend 00:00=2
+//! ```
+//!
+//! A season lasts from its start date to the start of the next one, the last one lasts into the
+//! next year until the first one starts. A band lasts until the next one starts, and the first one
+//! starts at midnight. A schedule without seasons has no tariffs.
+
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+use crate::utils::civil_from_days;
+use crate::{MAX_TARIFFS, MAX_TARIFF_BANDS, MAX_TARIFF_SEASONS, MIN_KNOWN_TIME};
+
+const MINUTES_PER_DAY: i64 = 24 * 60;
+const DAYS_IN_MONTH: [u8; 12] = [31, 29, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TariffSchedule {
+    /// Local time minus UTC, in minutes.
+    utc_offset: i16,
+    /// Ordered by their start.
+    seasons: Vec<Season>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Season {
+    /// Month and day the season starts on.
+    start: (u8, u8),
+    weekday: Vec<Band>,
+    weekend: Vec<Band>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Band {
+    /// In minutes since midnight.
+    start: u16,
+    tariff: u8,
+}
+
+impl TariffSchedule {
+    /// Parses and checks a schedule written as text.
+    pub fn parse(text: &str) -> anyhow::Result<TariffSchedule> {
+        let mut schedule = TariffSchedule::default();
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            match line.split_whitespace().collect::<Vec<&str>>().as_slice() {
+                ["offset", offset] => {
+                    schedule.utc_offset = offset
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("Invalid offset {}.", offset))?
+                }
+                ["season", start, "weekday", weekday, "weekend", weekend] => {
+                    schedule.seasons.push(Season {
+                        start: parse_date(start)?,
+                        weekday: parse_bands(weekday)?,
+                        weekend: parse_bands(weekend)?,
+                    })
+                }
+                _ => anyhow::bail!("Invalid line: {}", line),
+            }
+        }
+        schedule.validate()?;
+        Ok(schedule)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if !(-720..=840).contains(&self.utc_offset) {
+            anyhow::bail!("The offset must be between -720 and 840 minutes.");
+        }
+        if self.seasons.len() > MAX_TARIFF_SEASONS {
+            anyhow::bail!("There can be at most {} seasons.", MAX_TARIFF_SEASONS);
+        }
+        if self
+            .seasons
+            .windows(2)
+            .any(|seasons| seasons[0].start >= seasons[1].start)
+        {
+            anyhow::bail!("The seasons must be ordered by their start.");
+        }
+        for bands in self
+            .seasons
+            .iter()
+            .flat_map(|season| [&season.weekday, &season.weekend])
+        {
+            if bands.len() > MAX_TARIFF_BANDS {
+                anyhow::bail!("There can be at most {} bands a day.", MAX_TARIFF_BANDS);
+            }
+            if bands.first().map(|band| band.start) != Some(0) {
+                anyhow::bail!("The first band of a day must start at 00:00.");
+            }
+            if bands
+                .windows(2)
+                .any(|bands| bands[0].start >= bands[1].start)
+            {
+                anyhow::bail!("The bands must be ordered by their start.");
+            }
+            if bands
+                .iter()
+                .any(|band| !(1..=MAX_TARIFFS as u8).contains(&band.tariff))
+            {
+                anyhow::bail!("The tariffs must be between 1 and {}.", MAX_TARIFFS);
+            }
+        }
+        Ok(())
+    }
+
+    /// Local time minus UTC, in minutes.
+    pub fn utc_offset(&self) -> i16 {
+        self.utc_offset
+    }
+
+    /// The tariff at `time`, in ms since the epoch. None if there are no seasons or the time is
+    /// not known.
+    pub fn tariff_at(&self, time: u64) -> Option<u8> {
+        if time < MIN_KNOWN_TIME {
+            return None;
+        }
+        let local = (time / 60_000) as i64 + self.utc_offset as i64;
+        let days = local.div_euclid(MINUTES_PER_DAY);
+        let minute = local.rem_euclid(MINUTES_PER_DAY) as u16;
+        let (_, month, day) = civil_from_days(days);
+        let date = (month as u8, day as u8);
+        let season = self
+            .seasons
+            .iter()
+            .rev()
+            .find(|season| season.start <= date)
+            .or_else(|| self.seasons.last())?;
+        // 1970-01-01 was a Thursday.
+        let bands = if (days + 3).rem_euclid(7) >= 5 {
+            &season.weekend
+        } else {
+            &season.weekday
+        };
+        bands
+            .iter()
+            .rev()
+            .find(|band| band.start <= minute)
+            .map(|band| band.tariff)
+    }
+
+    /// The schedule written as text, in the format `parse` reads.
+    pub fn to_text(&self) -> String {
+        let mut text = format!("offset {}\n", self.utc_offset);
+        for season in self.seasons.iter() {
+            text.push_str(&format!(
+                "season {:02}-{:02} weekday {} weekend {}\n",
+                season.start.0,
+                season.start.1,
+                bands_to_text(&season.weekday),
+                bands_to_text(&season.weekend)
+            ));
+        }
+        text
+    }
+
+    /// The schedule as JSON, e.g.
+    /// `{"offset":60,"seasons":[{"start":"01-01","weekday":[{"start":"00:00","tariff":2}],"weekend":[{"start":"00:00","tariff":2}]}]}`.
+    pub fn to_json(&self) -> String {
+        let bands_to_json = |bands: &[Band]| {
+            let bands = bands
+                .iter()
+                .map(|band| {
+                    format!(
+                        r#"{{"start":"{:02}:{:02}","tariff":{}}}"#,
+                        band.start / 60,
+                        band.start % 60,
+                        band.tariff
+                    )
+                })
+                .collect::<Vec<String>>();
+            format!("[{}]", bands.join(","))
+        };
+        let seasons = self
+            .seasons
+            .iter()
+            .map(|season| {
+                format!(
+                    r#"{{"start":"{:02}-{:02}","weekday":{},"weekend":{}}}"#,
+                    season.start.0,
+                    season.start.1,
+                    bands_to_json(&season.weekday),
+                    bands_to_json(&season.weekend)
+                )
+            })
+            .collect::<Vec<String>>();
+        format!(
+            r#"{{"offset":{},"seasons":[{}]}}"#,
+            self.utc_offset,
+            seasons.join(",")
+        )
+    }
+}
+
+// A date as `MM-DD`.
+fn parse_date(text: &str) -> anyhow::Result<(u8, u8)> {
+    let date = text
+        .split_once('-')
+        .and_then(|(month, day)| Some((month.parse::<u8>().ok()?, day.parse::<u8>().ok()?)));
+    match date {
+        Some((month, day))
+            if (1..=12).contains(&month)
+                && (1..=DAYS_IN_MONTH[month as usize - 1]).contains(&day) =>
+        {
+            Ok((month, day))
+        }
+        _ => anyhow::bail!("Invalid date {}.", text),
+    }
+}
+
+// Bands as `HH:MM=<tariff>,...`.
+fn parse_bands(text: &str) -> anyhow::Result<Vec<Band>> {
+    text.split(',')
+        .map(|band| {
+            let parsed = band.split_once('=').and_then(|(start, tariff)| {
+                let (hour, minute) = start.split_once(':')?;
+                let (hour, minute) = (hour.parse::<u16>().ok()?, minute.parse::<u16>().ok()?);
+                if hour > 23 || minute > 59 {
+                    return None;
+                }
+                Some(Band {
+                    start: hour * 60 + minute,
+                    tariff: tariff.parse().ok()?,
+                })
+            });
+            parsed.ok_or_else(|| anyhow::anyhow!("Invalid band {}.", band))
+        })
+        .collect()
+}
+
+fn bands_to_text(bands: &[Band]) -> String {
+    bands
+        .iter()
+        .map(|band| {
+            format!(
+                "{:02}:{:02}={}",
+                band.start / 60,
+                band.start % 60,
+                band.tariff
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::parse_iso8601;
+
+    const TEXT: &str = "offset 60\n\
+        season 01-01 weekday 00:00=2,07:00=1,23:00=2 weekend 00:00=2\n\
+        season 06-01 weekday 00:00=2,08:00=1,20:00=2 weekend 00:00=3\n";
+
+    fn schedule(text: &str) -> TariffSchedule {
+        TariffSchedule::parse(text).unwrap()
+    }
+
+    fn tariff_at(schedule: &TariffSchedule, time: &str) -> Option<u8> {
+        schedule.tariff_at(parse_iso8601(time).unwrap())
+    }
+
+    #[test]
+    fn weekday_and_weekend() {
+        let schedule = schedule("season 01-01 weekday 00:00=1 weekend 00:00=2");
+        // 2023-01-06 was a Friday.
+        assert_eq!(tariff_at(&schedule, "2023-01-06T23:59:59.999"), Some(1));
+        assert_eq!(tariff_at(&schedule, "2023-01-07T00:00"), Some(2));
+        assert_eq!(tariff_at(&schedule, "2023-01-08T23:59:59.999"), Some(2));
+        assert_eq!(tariff_at(&schedule, "2023-01-09T00:00"), Some(1));
+    }
+
+    #[test]
+    fn season_wraps_past_year_end() {
+        let schedule = schedule(
+            "season 03-01 weekday 00:00=1 weekend 00:00=1\n\
+             season 11-01 weekday 00:00=2 weekend 00:00=2",
+        );
+        assert_eq!(tariff_at(&schedule, "2023-01-02T12:00"), Some(2));
+        assert_eq!(tariff_at(&schedule, "2023-02-28T23:59"), Some(2));
+        assert_eq!(tariff_at(&schedule, "2023-03-01T00:00"), Some(1));
+        assert_eq!(tariff_at(&schedule, "2023-10-31T23:59"), Some(1));
+        assert_eq!(tariff_at(&schedule, "2023-11-01T00:00"), Some(2));
+        assert_eq!(tariff_at(&schedule, "2024-02-29T12:00"), Some(2));
+    }
+
+    #[test]
+    fn band_boundaries() {
+        let schedule = schedule(TEXT);
+        // 2023-01-09 was a Monday, 07:00 local is 06:00 UTC.
+        assert_eq!(tariff_at(&schedule, "2023-01-09T05:59:59.999"), Some(2));
+        assert_eq!(tariff_at(&schedule, "2023-01-09T06:00"), Some(1));
+        assert_eq!(tariff_at(&schedule, "2023-01-09T21:59:59.999"), Some(1));
+        assert_eq!(tariff_at(&schedule, "2023-01-09T22:00"), Some(2));
+        // The summer season has its own bands.
+        assert_eq!(tariff_at(&schedule, "2023-06-05T06:59"), Some(2));
+        assert_eq!(tariff_at(&schedule, "2023-06-05T07:00"), Some(1));
+        assert_eq!(tariff_at(&schedule, "2023-06-05T19:00"), Some(2));
+    }
+
+    #[test]
+    fn negative_offset_around_midnight() {
+        let schedule = schedule(
+            "offset -300\n\
+             season 01-01 weekday 00:00=1 weekend 00:00=2\n\
+             season 03-01 weekday 00:00=3 weekend 00:00=4",
+        );
+        // Local midnight is 05:00 UTC, 2023-01-07 was a Saturday.
+        assert_eq!(tariff_at(&schedule, "2023-01-07T04:59:59.999"), Some(1));
+        assert_eq!(tariff_at(&schedule, "2023-01-07T05:00"), Some(2));
+        assert_eq!(tariff_at(&schedule, "2023-01-09T04:59"), Some(2));
+        assert_eq!(tariff_at(&schedule, "2023-01-09T05:00"), Some(1));
+        // 2023-03-01 was a Wednesday, the season starts at local midnight.
+        assert_eq!(tariff_at(&schedule, "2023-03-01T04:59"), Some(1));
+        assert_eq!(tariff_at(&schedule, "2023-03-01T05:00"), Some(3));
+        // Early on New Year's Day UTC it is still Sunday, the last day of 2023, locally.
+        assert_eq!(tariff_at(&schedule, "2024-01-01T04:59"), Some(4));
+        assert_eq!(tariff_at(&schedule, "2024-01-01T05:00"), Some(1));
+    }
+
+    #[test]
+    fn unknown_time() {
+        assert_eq!(schedule(TEXT).tariff_at(MIN_KNOWN_TIME - 1), None);
+        assert_eq!(schedule(TEXT).tariff_at(MIN_KNOWN_TIME), Some(2));
+        assert_eq!(schedule("offset 60").tariff_at(MIN_KNOWN_TIME), None);
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let schedule = schedule(TEXT);
+        assert_eq!(schedule.utc_offset(), 60);
+        assert_eq!(schedule.to_text(), TEXT);
+        assert_eq!(
+            TariffSchedule::parse(&schedule.to_text()).unwrap(),
+            schedule
+        );
+        // Unpadded numbers and blank lines are read too, but written padded.
+        let schedule = self::schedule("\n  season 2-1 weekday 0:00=1,7:5=2 weekend 0:0=3  \n\n");
+        assert_eq!(
+            schedule.to_text(),
+            "offset 0\nseason 02-01 weekday 00:00=1,07:05=2 weekend 00:00=3\n"
+        );
+    }
+
+    #[test]
+    fn invalid_schedules() {
+        for text in [
+            "offset 841",
+            "offset -721",
+            "offset x",
+            "season 02-30 weekday 00:00=1 weekend 00:00=1",
+            "season 13-01 weekday 00:00=1 weekend 00:00=1",
+            "season 01-01 weekday 00:00=5 weekend 00:00=1",
+            "season 01-01 weekday 00:00=0 weekend 00:00=1",
+            "season 01-01 weekday 01:00=1 weekend 00:00=1",
+            "season 01-01 weekday 00:00=1,24:00=2 weekend 00:00=1",
+            "season 01-01 weekday 00:00=1,07:00=2,07:00=1 weekend 00:00=1",
+            "season 06-01 weekday 00:00=1 weekend 00:00=1\n\
+             season 01-01 weekday 00:00=1 weekend 00:00=1",
+            "season 01-01 weekday 00:00=1",
+            "tariff 1",
+        ] {
+            assert!(TariffSchedule::parse(text).is_err(), "{}", text);
+        }
+    }
+}
@@ -12,9 +12,187 @@ pub(crate) fn add_f32_to_buf(val: &f32, buf: &mut [u8], offset: &usize) -> anyho
     Ok(n)
 }
 
+pub(crate) fn add_f64_to_buf(val: &f64, buf: &mut [u8], offset: &usize) -> anyhow::Result<usize> {
+    let bytes = val.to_le_bytes();
+    let n = bytes.len();
+    buf[*offset..(n + (*offset))].copy_from_slice(&bytes);
+    Ok(n)
+}
+
 pub(crate) fn add_u64_to_buf(val: &u64, buf: &mut [u8], offset: &usize) -> anyhow::Result<usize> {
     let bytes = val.to_le_bytes();
     let n = bytes.len();
     buf[*offset..(n + (*offset))].copy_from_slice(&bytes);
     Ok(n)
 }
+
+pub(crate) fn add_u32_to_buf(val: &u32, buf: &mut [u8], offset: &usize) -> anyhow::Result<usize> {
+    let bytes = val.to_le_bytes();
+    let n = bytes.len();
+    buf[*offset..(n + (*offset))].copy_from_slice(&bytes);
+    Ok(n)
+}
+
+/// CRC-32 (IEEE 802.3), the same checksum as zlib and most `crc32` functions.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF_u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// JSON has no NaN or infinity, they are sent as null.
+pub(crate) fn json_number(value: f32) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        "null".to_string()
+    }
+}
+
+/// A JSON string literal of `text`, with quotes, backslashes and control characters escaped.
+pub(crate) fn json_string(text: &str) -> String {
+    let mut json = String::with_capacity(text.len() + 2);
+    json.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Formats milliseconds since the UNIX epoch as ISO-8601 UTC, e.g. `2023-01-10T12:00:00.000Z`.
+pub(crate) fn iso8601(time_milis: u64) -> String {
+    let secs = time_milis / 1000;
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        time_milis % 1000
+    )
+}
+
+/// Parses an ISO-8601 UTC date or date and time into milliseconds since the UNIX epoch.
+///
+/// Accepts `2023-01-10`, `2023-01-10T12:00`, `2023-01-10T12:00:00` and `2023-01-10T12:00:00.123`,
+/// optionally followed by `Z`. Years after 9999 are refused.
+pub(crate) fn parse_iso8601(text: &str) -> anyhow::Result<u64> {
+    let text = text.strip_suffix('Z').unwrap_or(text);
+    let (date, time) = text.split_once('T').unwrap_or((text, "00:00"));
+    let date = date
+        .split('-')
+        .map(|part| part.parse::<u32>())
+        .collect::<Result<Vec<u32>, _>>()?;
+    let (secs, milis) = match time.split_once('.') {
+        Some((secs, milis)) => {
+            let milis = milis
+                .chars()
+                .chain("000".chars())
+                .take(3)
+                .collect::<String>();
+            (secs, milis.parse::<u64>()?)
+        }
+        None => (time, 0),
+    };
+    let time = secs
+        .split(':')
+        .map(|part| part.parse::<u64>())
+        .collect::<Result<Vec<u64>, _>>()?;
+    if date.len() != 3
+        || date[0] > 9999
+        || !(1..=12).contains(&date[1])
+        || !(1..=31).contains(&date[2])
+        || !(2..=3).contains(&time.len())
+        || time[0] > 23
+        || time[1] > 59
+        || *time.get(2).unwrap_or(&0) > 59
+    {
+        anyhow::bail!("Invalid ISO-8601 time {}.", text);
+    }
+    let days = days_from_civil(date[0] as i64, date[1], date[2]);
+    if days < 0 {
+        anyhow::bail!("Time {} is before 1970.", text);
+    }
+    let secs = days as u64 * 86400 + time[0] * 3600 + time[1] * 60 + time.get(2).unwrap_or(&0);
+    Ok(secs * 1000 + milis)
+}
+
+// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+// see http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// The inverse of `days_from_civil`.
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn iso8601_round_trip() {
+        assert_eq!(parse_iso8601("1970-01-01").unwrap(), 0);
+        assert_eq!(parse_iso8601("2023-01-01").unwrap(), 1_672_531_200_000);
+        assert_eq!(
+            parse_iso8601("2023-01-10T12:00:05.123Z").unwrap(),
+            1_673_352_005_123
+        );
+        assert_eq!(
+            parse_iso8601("9999-12-31T23:59").unwrap(),
+            253_402_300_740_000
+        );
+        assert_eq!(iso8601(1_673_352_005_123), "2023-01-10T12:00:05.123Z");
+    }
+
+    #[test]
+    fn invalid_iso8601() {
+        for text in [
+            "",
+            "abc",
+            "2023-13-01",
+            "2023-01-10T24:00",
+            "2023-01-10T12",
+            "1969-12-31",
+            "10000-01-01",
+            "4000000000-01-01",
+        ] {
+            assert!(parse_iso8601(text).is_err(), "{} was parsed", text);
+        }
+    }
+}
@@ -0,0 +1,778 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+use crate::alarms::{AlarmRule, Quantity, SharedAlarms};
+use crate::calibration::{CalibrationJob, SharedCalibrations};
+use crate::channels::{Channel, ChannelMap, SharedChannels};
+use crate::ct::CTStorage;
+use crate::demand::SharedDemand;
+use crate::record::AlarmEvent;
+use crate::settings::SharedSettings;
+use crate::tariffs::TariffSchedule;
+use crate::utils::parse_iso8601;
+use crate::{now, set_system_time, ACCESS_TOKEN_SIZE, MAX_CTS, MAX_REQUEST_BODY_SIZE, VERSION};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post,
+}
+
+/// Everything the handlers need access to. Shared between the web server tasks.
+pub struct Context {
+    pub storage: Arc<Mutex<CTStorage>>,
+    pub calibrations: SharedCalibrations,
+    pub channels: SharedChannels,
+    pub settings: SharedSettings,
+    pub alarms: SharedAlarms,
+    pub demand: SharedDemand,
+}
+
+/// Writes a response body directly into the response in chunks, so large data never has to be
+/// in RAM at once.
+pub type BodyWriter<'a> = Box<dyn FnOnce(&mut dyn Write) -> anyhow::Result<()> + 'a>;
+
+pub enum Body<'a> {
+    Empty,
+    Bytes(Vec<u8>),
+    Stream(BodyWriter<'a>),
+}
+
+pub struct Response<'a> {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Body<'a>,
+}
+
+impl<'a> Response<'a> {
+    fn ok(body: Body<'a>) -> Self {
+        Response {
+            status: 200,
+            content_type: "application/octet-stream",
+            body,
+        }
+    }
+
+    fn text(text: impl Into<String>) -> Self {
+        Response {
+            status: 200,
+            content_type: "text/plain",
+            body: Body::Bytes(text.into().into_bytes()),
+        }
+    }
+
+    fn json(json: impl Into<String>) -> Self {
+        Response {
+            status: 200,
+            content_type: "application/json",
+            body: Body::Bytes(json.into().into_bytes()),
+        }
+    }
+
+    fn bad_request(message: impl std::fmt::Display) -> Self {
+        Response {
+            status: 400,
+            content_type: "text/plain",
+            body: Body::Bytes(message.to_string().into_bytes()),
+        }
+    }
+
+    /// The answer to a request whose body is longer than `MAX_REQUEST_BODY_SIZE`, which is not
+    /// handled.
+    pub fn payload_too_large() -> Self {
+        Response {
+            status: 413,
+            content_type: "text/plain",
+            body: Body::Bytes(
+                format!(
+                    "The body must be at most {} bytes long.",
+                    MAX_REQUEST_BODY_SIZE
+                )
+                .into_bytes(),
+            ),
+        }
+    }
+
+    fn status(status: u16) -> Self {
+        Response {
+            status,
+            content_type: "text/plain",
+            body: Body::Empty,
+        }
+    }
+}
+
+/// Routes that are served the same way by the device and the simulator.
+///
+/// `/ota` is not here since flashing only makes sense on the device.
+pub const ROUTES: &[(Method, &str)] = &[
+    (Method::Get, "/"),
+    (Method::Get, "/telemetry"),
+    (Method::Get, "/telemetry.json"),
+    (Method::Get, "/telemetry.csv"),
+    (Method::Get, "/powerloss_log"),
+    (Method::Post, "/time"),
+    (Method::Post, "/token"),
+    (Method::Get, "/token"),
+    (Method::Get, "/reset"),
+    (Method::Get, "/version"),
+    (Method::Get, "/dropped_records"),
+    (Method::Get, "/records"),
+    (Method::Post, "/ack"),
+    (Method::Get, "/calibration"),
+    (Method::Post, "/calibration"),
+    (Method::Get, "/calibration/run"),
+    (Method::Post, "/calibration/run"),
+    (Method::Get, "/channels"),
+    (Method::Post, "/channels"),
+    (Method::Get, "/energy"),
+    (Method::Get, "/demand"),
+    (Method::Get, "/settings"),
+    (Method::Post, "/settings"),
+    (Method::Get, "/tariffs"),
+    (Method::Post, "/tariffs"),
+    (Method::Get, "/events"),
+    (Method::Get, "/alarms"),
+    (Method::Get, "/alarms.json"),
+    (Method::Get, "/alarms/active"),
+    (Method::Get, "/alarms/rules"),
+    (Method::Post, "/alarms/rules"),
+];
+
+/// Handles a request to one of the `ROUTES`.
+///
+/// `query` is the part of the uri after `?` and `body` is the whole request body, which is at most
+/// `MAX_REQUEST_BODY_SIZE` bytes long; longer ones are answered with `payload_too_large` instead.
+pub fn handle<'a>(
+    context: &'a Context,
+    method: Method,
+    path: &str,
+    query: &str,
+    body: &[u8],
+) -> anyhow::Result<Response<'a>> {
+    log::info!("Handling {:?} {} request.", method, path);
+    let storage_lock = &context.storage;
+    let response = match (method, path) {
+        (Method::Get, "/") => Response {
+            status: 200,
+            content_type: "text/html",
+            body: Body::Bytes(templated_webpage("You should not be here.").into_bytes()),
+        },
+        (Method::Get, "/telemetry") => Response::ok(Body::Stream(Box::new(move |writer| {
+            let mut ct_storage = match storage_lock.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            ct_storage.send_readings_shards(writer)
+        }))),
+        (Method::Get, "/telemetry.json") => {
+            let (from_seq, limit) = match cursor(query) {
+                Ok(cursor) => cursor,
+                Err(e) => return Ok(Response::bad_request(e)),
+            };
+            Response {
+                status: 200,
+                content_type: "application/json",
+                body: Body::Stream(Box::new(move |writer| {
+                    let mut ct_storage = match storage_lock.lock() {
+                        Ok(gaurd) => gaurd,
+                        Err(poisoned) => poisoned.into_inner(),
+                    };
+                    ct_storage.send_records_json(writer, from_seq, limit)
+                })),
+            }
+        }
+        (Method::Get, "/telemetry.csv") => {
+            let from_time = match time_param(query, "from") {
+                Ok(time) => time,
+                Err(e) => return Ok(Response::bad_request(e)),
+            };
+            let to_time = match time_param(query, "to") {
+                Ok(time) => time,
+                Err(e) => return Ok(Response::bad_request(e)),
+            };
+            Response {
+                status: 200,
+                content_type: "text/csv",
+                body: Body::Stream(Box::new(move |writer| {
+                    let mut ct_storage = match storage_lock.lock() {
+                        Ok(gaurd) => gaurd,
+                        Err(poisoned) => poisoned.into_inner(),
+                    };
+                    ct_storage.send_records_csv(writer, from_time, to_time)
+                })),
+            }
+        }
+        (Method::Get, "/powerloss_log") => Response::ok(Body::Stream(Box::new(move |writer| {
+            let mut ct_storage = match storage_lock.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            ct_storage.send_boot_log(writer)
+        }))),
+        (Method::Post, "/time") => {
+            let mut buf = [0_u8; std::mem::size_of::<u64>()];
+            let size = usize::min(body.len(), buf.len());
+            buf[..size].copy_from_slice(&body[..size]);
+            println!("Read {} bytes of data", body.len());
+
+            // Convert raw bytes to time. Store it and update system time.
+            let time = u64::from_le_bytes(buf);
+            {
+                let mut ct_storage = match storage_lock.lock() {
+                    Ok(gaurd) => gaurd,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+                ct_storage.store_time(time)?;
+                println!("Response: {}", time);
+            }
+            set_system_time(time)?;
+            Response::status(200)
+        }
+        (Method::Post, "/token") => {
+            let mut token = [0_u8; ACCESS_TOKEN_SIZE];
+            let size = usize::min(body.len(), token.len());
+            token[..size].copy_from_slice(&body[..size]);
+            println!("Read {} bytes of data", body.len());
+
+            // Store the given token in storage.
+            let mut ct_storage = match storage_lock.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            ct_storage.store_token(&token)?;
+            println!("Response: {}", std::str::from_utf8(&token)?);
+            Response::status(200)
+        }
+        (Method::Get, "/token") => {
+            let mut ct_storage = match storage_lock.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            let token_buf = ct_storage.retrieve_token()?;
+            log::info!("Sent token: {}", std::str::from_utf8(&token_buf)?);
+            Response::ok(Body::Bytes(token_buf.to_vec()))
+        }
+        (Method::Get, "/reset") => {
+            let mut ct_storage = match storage_lock.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            ct_storage.reset_storage()?;
+            Response::status(200)
+        }
+        (Method::Get, "/version") => {
+            log::info!("Sent version: {}", VERSION);
+            Response::text(VERSION.to_string())
+        }
+        (Method::Get, "/dropped_records") => {
+            let ct_storage = match storage_lock.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            Response::text(ct_storage.dropped_records.to_string())
+        }
+        (Method::Get, "/records") => {
+            let (from_seq, limit) = match cursor(query) {
+                Ok(cursor) => cursor,
+                Err(e) => return Ok(Response::bad_request(e)),
+            };
+            Response::ok(Body::Stream(Box::new(move |writer| {
+                let mut ct_storage = match storage_lock.lock() {
+                    Ok(gaurd) => gaurd,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+                ct_storage.send_records(writer, from_seq, limit)
+            })))
+        }
+        (Method::Post, "/ack") => {
+            let mut buf = [0_u8; std::mem::size_of::<u64>()];
+            if body.len() != buf.len() {
+                return Ok(Response::bad_request(
+                    "The body must be the sequence number as a little-endian u64.",
+                ));
+            }
+            buf.copy_from_slice(body);
+
+            let seq = u64::from_le_bytes(buf);
+            let mut ct_storage = match storage_lock.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            let deleted = ct_storage.acknowledge(seq)?;
+            Response::text(deleted.to_string())
+        }
+        (Method::Get, "/calibration") => {
+            let calibrations = match context.calibrations.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            match param(query, "ct") {
+                Some(ct_id) => {
+                    let ct_id = match ct_id.parse() {
+                        Ok(ct_id) => ct_id,
+                        Err(_) => return Ok(Response::bad_request("Invalid ct.")),
+                    };
+                    match calibrations.get(ct_id) {
+                        Some(calibration) => Response::json(calibration.to_json(ct_id)),
+                        None => Response::status(404),
+                    }
+                }
+                None => {
+                    let all = calibrations
+                        .all()
+                        .iter()
+                        .enumerate()
+                        .map(|(i, calibration)| calibration.to_json(i as u16 + 1))
+                        .collect::<Vec<String>>();
+                    Response::json(format!("[{}]", all.join(",")))
+                }
+            }
+        }
+        (Method::Post, "/calibration") => {
+            // a form with the CT id and the values to change, e.g. `ct=1&ical=30&vcal=219.25`.
+            let form = std::str::from_utf8(body)?;
+            let ct_id = match param(form, "ct").map(|ct_id| ct_id.parse::<u16>()) {
+                Some(Ok(ct_id)) => ct_id,
+                _ => return Ok(Response::bad_request("The ct field is missing.")),
+            };
+            let mut calibrations = match context.calibrations.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            let mut calibration = match calibrations.get(ct_id) {
+                Some(calibration) => calibration,
+                None => return Ok(Response::status(404)),
+            };
+            for (name, value) in [
+                ("ical", &mut calibration.ical),
+                ("vcal", &mut calibration.vcal),
+                ("phase_cal", &mut calibration.phase_cal),
+                ("offset_i", &mut calibration.offset_i),
+                ("offset_v", &mut calibration.offset_v),
+            ] {
+                if let Some(text) = param(form, name) {
+                    match text.parse() {
+                        Ok(parsed) => *value = parsed,
+                        Err(_) => return Ok(Response::bad_request(format!("Invalid {}.", name))),
+                    }
+                }
+            }
+            if let Err(e) = calibration.validate() {
+                return Ok(Response::bad_request(e));
+            }
+            calibrations.set(ct_id, calibration)?;
+            Response::json(calibration.to_json(ct_id))
+        }
+        (Method::Get, "/calibration/run") => {
+            let calibrations = match context.calibrations.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            Response::json(calibrations.status().to_json())
+        }
+        (Method::Post, "/calibration/run") => {
+            // a form with the CT id and the readings of the reference meter,
+            // e.g. `ct=1&voltage=230.5&current=4.35&power=1002`. The power is optional.
+            let form = std::str::from_utf8(body)?;
+            let number = |name: &str| param(form, name).and_then(|text| text.parse().ok());
+            let job = match (
+                param(form, "ct").and_then(|text| text.parse().ok()),
+                number("voltage"),
+                number("current"),
+            ) {
+                (Some(ct_id), Some(voltage), Some(current)) => CalibrationJob {
+                    ct_id,
+                    voltage,
+                    current,
+                    real_power: number("power"),
+                },
+                _ => {
+                    return Ok(Response::bad_request(
+                        "The ct, voltage and current fields are needed.",
+                    ))
+                }
+            };
+            if param(form, "power").is_some() && job.real_power.is_none() {
+                return Ok(Response::bad_request("Invalid power."));
+            }
+            let mut calibrations = match context.calibrations.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            if calibrations.is_busy() {
+                return Ok(Response::status(409));
+            }
+            match calibrations.queue(job) {
+                Ok(()) => Response {
+                    status: 202,
+                    ..Response::json(calibrations.status().to_json())
+                },
+                Err(e) => Response::bad_request(e),
+            }
+        }
+        (Method::Get, "/channels") => {
+            let channels = match context.channels.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            Response::json(channels.to_json())
+        }
+        (Method::Post, "/channels") => {
+            // a form with a preset, the GPIOs of every CT, or both, and optionally the default
+            // calibration, e.g. `ct1=32,39&ct2=35,39&ical=30&vcal=219.25`. Used from the next boot.
+            let form = std::str::from_utf8(body)?;
+            let mut channels = match context.channels.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            let map = match channel_map(form, channels.next()) {
+                Ok(map) => map,
+                Err(e) => return Ok(Response::bad_request(e)),
+            };
+            if let Err(e) = channels.set(map) {
+                return Ok(Response::bad_request(e));
+            }
+            Response::json(channels.to_json())
+        }
+        (Method::Get, "/energy") => {
+            let ct_storage = match context.storage.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            Response::json(ct_storage.energy().to_json())
+        }
+        (Method::Get, "/demand") => {
+            let demand = match context.demand.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            Response::json(demand.to_json())
+        }
+        (Method::Get, "/settings") => {
+            let settings = match context.settings.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            Response::json(settings.to_json())
+        }
+        (Method::Post, "/settings") => {
+            // a form with the settings to change, e.g. `save_period=900&nominal_frequency=60`.
+            let form = std::str::from_utf8(body)?;
+            let mut settings = match context.settings.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            let mut values = settings.values();
+            let parsed = form_field(form, "nominal_frequency", &mut values.nominal_frequency)
+                .and_then(|_| form_field(form, "save_period", &mut values.save_period))
+                .and_then(|_| form_field(form, "crossings", &mut values.crossings))
+                .and_then(|_| {
+                    form_field(form, "measurement_timeout", &mut values.measurement_timeout)
+                })
+                .and_then(|_| form_field(form, "loop_sleep", &mut values.loop_sleep))
+                .and_then(|_| form_field(form, "nominal_voltage", &mut values.nominal_voltage))
+                .and_then(|_| form_field(form, "sag_threshold", &mut values.sag_threshold))
+                .and_then(|_| form_field(form, "swell_threshold", &mut values.swell_threshold))
+                .and_then(|_| {
+                    form_field(
+                        form,
+                        "interruption_threshold",
+                        &mut values.interruption_threshold,
+                    )
+                })
+                .and_then(|_| {
+                    form_field(form, "event_min_duration", &mut values.event_min_duration)
+                })
+                .and_then(|_| form_field(form, "demand_window", &mut values.demand_window))
+                .and_then(|_| form_field(form, "demand_sliding", &mut values.demand_sliding));
+            if let Err(e) = parsed {
+                return Ok(Response::bad_request(e));
+            }
+            if let Err(e) = settings.set(values) {
+                return Ok(Response::bad_request(e));
+            }
+            Response::json(settings.to_json())
+        }
+        (Method::Get, "/tariffs") => {
+            let settings = match context.settings.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            Response::json(tariffs_json(settings.tariffs()))
+        }
+        (Method::Post, "/tariffs") => {
+            // the schedule written as text, see `tariffs`. An empty body removes it.
+            let schedule = match TariffSchedule::parse(std::str::from_utf8(body)?) {
+                Ok(schedule) => schedule,
+                Err(e) => return Ok(Response::bad_request(e)),
+            };
+            let mut settings = match context.settings.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            if let Err(e) = settings.set_tariffs(schedule) {
+                return Ok(Response::bad_request(e));
+            }
+            Response::json(tariffs_json(settings.tariffs()))
+        }
+        (Method::Get, "/events") => Response {
+            status: 200,
+            content_type: "application/json",
+            body: Body::Stream(Box::new(move |writer| {
+                let mut ct_storage = match storage_lock.lock() {
+                    Ok(gaurd) => gaurd,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+                ct_storage.send_events_json(writer)
+            })),
+        },
+        (Method::Get, "/alarms") | (Method::Get, "/alarms.json") => {
+            let thingsboard = path == "/alarms.json";
+            Response {
+                status: 200,
+                content_type: "application/json",
+                body: Body::Stream(Box::new(move |writer| {
+                    let mut ct_storage = match storage_lock.lock() {
+                        Ok(gaurd) => gaurd,
+                        Err(poisoned) => poisoned.into_inner(),
+                    };
+                    ct_storage.send_alarms_json(writer, thingsboard)
+                })),
+            }
+        }
+        (Method::Get, "/alarms/active") => {
+            let alarms = match context.alarms.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            Response::json(alarms.to_thingsboard_attributes())
+        }
+        (Method::Get, "/alarms/rules") => {
+            let alarms = match context.alarms.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            Response::json(alarms.to_json())
+        }
+        (Method::Post, "/alarms/rules") => {
+            // a form with a rule, e.g. `ct=1&quantity=i_rms&max=32&delay=300`, which replaces the
+            // rule with the `id` if it is given. `remove=2` removes rule 2 instead.
+            let form = std::str::from_utf8(body)?;
+            let mut alarms = match context.alarms.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            if let Some(id) = param(form, "remove") {
+                let id = match id.parse() {
+                    Ok(id) => id,
+                    Err(_) => return Ok(Response::bad_request("Invalid remove.")),
+                };
+                let cleared = match alarms.remove(id) {
+                    Ok(cleared) => cleared,
+                    Err(e) => return Ok(Response::bad_request(e)),
+                };
+                let json = alarms.to_json();
+                drop(alarms);
+                log_cleared_alarm(storage_lock, cleared);
+                return Ok(Response::json(json));
+            }
+            let quantity = match param(form, "quantity").and_then(|q| Quantity::from_name(&q)) {
+                Some(quantity) => quantity,
+                None => {
+                    return Ok(Response::bad_request(
+                        "The quantity must be real_power, apparent_power, reactive_power, power_factor, i_rms, v_rms or frequency.",
+                    ))
+                }
+            };
+            let mut rule = AlarmRule {
+                ct_id: 0,
+                quantity,
+                min: None,
+                max: None,
+                delay: 0,
+            };
+            let mut id = None;
+            let parsed = form_field(form, "ct", &mut rule.ct_id)
+                .and_then(|_| form_field(form, "delay", &mut rule.delay))
+                .and_then(|_| optional_form_field(form, "min", &mut rule.min))
+                .and_then(|_| optional_form_field(form, "max", &mut rule.max))
+                .and_then(|_| optional_form_field(form, "id", &mut id));
+            if let Err(e) = parsed {
+                return Ok(Response::bad_request(e));
+            }
+            let cleared = match alarms.set(id, rule) {
+                Ok((_, cleared)) => cleared,
+                Err(e) => return Ok(Response::bad_request(e)),
+            };
+            let json = alarms.to_json();
+            drop(alarms);
+            log_cleared_alarm(storage_lock, cleared);
+            Response::json(json)
+        }
+        _ => Response::status(404),
+    };
+    Ok(response)
+}
+
+/// Logs the event of an active alarm that cleared because its rule was replaced or removed.
+fn log_cleared_alarm(storage_lock: &Mutex<CTStorage>, cleared: Option<AlarmEvent>) {
+    if let Some(event) = cleared {
+        let mut ct_storage = match storage_lock.lock() {
+            Ok(gaurd) => gaurd,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Err(e) = ct_storage.log_alarms(&[event]) {
+            error!("Failed to log alarms: {:?}", e);
+        }
+    }
+}
+
+/// Parses the form field `name` into `value`, if it is there.
+fn form_field<T: std::str::FromStr>(form: &str, name: &str, value: &mut T) -> Result<(), String> {
+    if let Some(text) = param(form, name) {
+        *value = text.parse().map_err(|_| format!("Invalid {}.", name))?;
+    }
+    Ok(())
+}
+
+/// Like `form_field`, for fields that are `None` when they are left out.
+fn optional_form_field<T: std::str::FromStr>(
+    form: &str,
+    name: &str,
+    value: &mut Option<T>,
+) -> Result<(), String> {
+    if let Some(text) = param(form, name) {
+        *value = Some(text.parse().map_err(|_| format!("Invalid {}.", name))?);
+    }
+    Ok(())
+}
+
+/// The channel map of a `/channels` form, fields that are left out are taken from the preset or
+/// else from `base`. CTs are numbered from 1 without gaps.
+fn channel_map(form: &str, base: &ChannelMap) -> Result<ChannelMap, String> {
+    let mut map = match param(form, "preset") {
+        Some(name) => {
+            ChannelMap::preset(&name).ok_or_else(|| format!("There is no preset {}.", name))?
+        }
+        None => base.clone(),
+    };
+    let mut channels = Vec::new();
+    while let Some(text) = param(form, &format!("ct{}", channels.len() + 1)) {
+        if channels.len() == MAX_CTS {
+            return Err(format!("There can be at most {} CTs.", MAX_CTS));
+        }
+        let channel = text
+            .split_once(',')
+            .and_then(|(current, voltage)| {
+                Some(Channel {
+                    current_gpio: current.trim().parse().ok()?,
+                    voltage_gpio: voltage.trim().parse().ok()?,
+                })
+            })
+            .ok_or_else(|| format!("Invalid ct{}.", channels.len() + 1))?;
+        channels.push(channel);
+    }
+    if !channels.is_empty() {
+        map.channels = channels;
+    }
+    form_field(form, "ical", &mut map.ical)?;
+    form_field(form, "vcal", &mut map.vcal)?;
+    Ok(map)
+}
+
+/// The tariff schedule with the tariff in effect now, e.g.
+/// `{"schedule":{"offset":60,"seasons":[...]},"tariff":1}`.
+fn tariffs_json(schedule: &TariffSchedule) -> String {
+    format!(
+        r#"{{"schedule":{},"tariff":{}}}"#,
+        schedule.to_json(),
+        schedule
+            .tariff_at(now().as_millis() as u64)
+            .map(|tariff| tariff.to_string())
+            .unwrap_or_else(|| "null".to_string())
+    )
+}
+
+/// The `from` sequence number and `limit` query parameters of the record downloads.
+fn cursor(query: &str) -> Result<(u64, usize), String> {
+    let mut from_seq = 0;
+    let mut limit = usize::MAX;
+    form_field(query, "from", &mut from_seq)?;
+    form_field(query, "limit", &mut limit)?;
+    Ok((from_seq, limit))
+}
+
+/// A time query parameter, either in milliseconds since the UNIX epoch or in ISO-8601.
+fn time_param(query: &str, name: &str) -> Result<Option<u64>, String> {
+    let time = match param(query, name) {
+        Some(time) => time,
+        None => return Ok(None),
+    };
+    let parsed = if !time.is_empty() && time.bytes().all(|b| b.is_ascii_digit()) {
+        time.parse().ok()
+    } else {
+        parse_iso8601(&time).ok()
+    };
+    match parsed {
+        Some(time) => Ok(Some(time)),
+        None => Err(format!(
+            "Invalid {}, it must be in milliseconds since the epoch or in ISO-8601 UTC.",
+            name
+        )),
+    }
+}
+
+/// The decoded value of the first `name=value` pair with this name in a query string.
+fn param(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| percent_decode(value))
+}
+
+// Undo the url encoding of a query value, e.g. `12%3A00` is `12:00`.
+fn percent_decode(value: &str) -> String {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => match std::str::from_utf8(rest.get(..2).unwrap_or_default())
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                Some(decoded) => {
+                    bytes.push(decoded);
+                    rest = &rest[2..];
+                }
+                None => bytes.push(byte),
+            },
+            _ => bytes.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn templated_webpage(content: impl AsRef<str>) -> String {
+    format!(
+        r#"
+<!DOCTYPE html>
+<html>
+    <head>
+        <meta charset="utf-8">
+        <title>esp-rs web server</title>
+    </head>
+    <body>
+        {}
+    </body>
+</html>
+"#,
+        content.as_ref()
+    )
+}